@@ -0,0 +1,3 @@
+ALTER TABLE chat_users ADD COLUMN password_hash text;
+
+CREATE UNIQUE INDEX chat_users_name_key ON chat_users (name);
@@ -0,0 +1,9 @@
+CREATE TABLE sessions (
+    id uuid PRIMARY KEY,
+    token_hash bytea NOT NULL UNIQUE,
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    last_seen timestamp NOT NULL,
+    expires timestamp NOT NULL
+);
+
+CREATE INDEX sessions_user_idx ON sessions ("user");
@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    ADD COLUMN user_agent text,
+    ADD COLUMN ip text;
@@ -0,0 +1,46 @@
+ALTER TABLE servers ADD COLUMN owner uuid REFERENCES chat_users (id) ON DELETE SET NULL;
+
+-- Existing servers have no recorded creator, the member who sent the first message is the
+-- earliest one known. Servers without messages fall back to any member.
+UPDATE servers SET owner = COALESCE(
+    (
+        SELECT m.author FROM messages AS m
+        JOIN channels AS c ON c.id = m.channel
+        JOIN users_member_of_servers AS um ON um."user" = m.author AND um.server = c.server
+        WHERE c.server = servers.id
+        ORDER BY m.id
+        LIMIT 1
+    ),
+    (
+        SELECT "user" FROM users_member_of_servers
+        WHERE server = servers.id
+        ORDER BY "user"
+        LIMIT 1
+    )
+);
+
+CREATE TABLE roles (
+    id uuid PRIMARY KEY,
+    server uuid NOT NULL REFERENCES servers (id) ON DELETE CASCADE,
+    name text NOT NULL,
+    permissions bigint NOT NULL,
+    UNIQUE (id, server)
+);
+
+CREATE INDEX roles_server_idx ON roles (server);
+
+-- The roles new servers are created with, Admin has every permission and Moderator can manage
+-- messages and kick members
+INSERT INTO roles (id, server, name, permissions)
+SELECT gen_random_uuid(), id, 'Admin', 63 FROM servers
+UNION ALL
+SELECT gen_random_uuid(), id, 'Moderator', 40 FROM servers;
+
+CREATE TABLE members_have_roles (
+    "user" uuid NOT NULL,
+    server uuid NOT NULL,
+    role uuid NOT NULL,
+    PRIMARY KEY ("user", role),
+    FOREIGN KEY ("user", server) REFERENCES users_member_of_servers ("user", server) ON DELETE CASCADE,
+    FOREIGN KEY (role, server) REFERENCES roles (id, server) ON DELETE CASCADE
+);
@@ -0,0 +1,17 @@
+-- An overwrite without a role or user applies to everyone in the server
+CREATE TABLE channel_overwrites (
+    id uuid PRIMARY KEY,
+    channel uuid NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    role uuid REFERENCES roles (id) ON DELETE CASCADE,
+    "user" uuid REFERENCES chat_users (id) ON DELETE CASCADE,
+    allow bigint NOT NULL DEFAULT 0,
+    deny bigint NOT NULL DEFAULT 0,
+    CHECK (role IS NULL OR "user" IS NULL)
+);
+
+CREATE UNIQUE INDEX channel_overwrites_everyone_key ON channel_overwrites (channel)
+    WHERE role IS NULL AND "user" IS NULL;
+CREATE UNIQUE INDEX channel_overwrites_role_key ON channel_overwrites (channel, role)
+    WHERE role IS NOT NULL;
+CREATE UNIQUE INDEX channel_overwrites_user_key ON channel_overwrites (channel, "user")
+    WHERE "user" IS NOT NULL;
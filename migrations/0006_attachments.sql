@@ -0,0 +1,9 @@
+CREATE TABLE attachments (
+    id uuid PRIMARY KEY,
+    message uuid NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    name text NOT NULL,
+    content_type text NOT NULL,
+    size bigint NOT NULL
+);
+
+CREATE INDEX attachments_message_idx ON attachments (message);
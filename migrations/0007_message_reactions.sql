@@ -0,0 +1,31 @@
+CREATE TABLE message_reactions (
+    message uuid NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    emoji text NOT NULL,
+    created timestamp NOT NULL,
+    PRIMARY KEY (message, "user", emoji)
+);
+
+-- Payload is the message id followed by the channel id, like the message notifications
+CREATE FUNCTION notify_reaction() RETURNS trigger AS $$
+DECLARE
+  message_id uuid;
+  channel_id uuid;
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    message_id := OLD.message;
+  ELSE
+    message_id := NEW.message;
+  END IF;
+  -- Reactions removed along with their message have nothing left to update
+  SELECT channel INTO channel_id FROM messages WHERE id = message_id;
+  IF channel_id IS NOT NULL THEN
+    PERFORM pg_notify('reaction', message_id::text || channel_id::text);
+  END IF;
+  RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER message_reactions_notify
+    AFTER INSERT OR DELETE ON message_reactions
+    FOR EACH ROW EXECUTE FUNCTION notify_reaction();
@@ -0,0 +1,6 @@
+ALTER TABLE messages
+    ADD COLUMN reply_to uuid REFERENCES messages (id) ON DELETE SET NULL,
+    -- Messages in a thread are not shown in the channel itself, only in the thread of the parent
+    ADD COLUMN thread uuid REFERENCES messages (id) ON DELETE CASCADE;
+
+CREATE INDEX messages_thread_idx ON messages (thread);
@@ -0,0 +1,10 @@
+-- The versions of a message before each edit, the current version is kept in messages
+CREATE TABLE message_revisions (
+    id uuid PRIMARY KEY,
+    message uuid NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    content text NOT NULL,
+    -- When this version was written, either when the message was sent or when it was last edited
+    written timestamp NOT NULL
+);
+
+CREATE INDEX message_revisions_message_idx ON message_revisions (message);
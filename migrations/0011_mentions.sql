@@ -0,0 +1,17 @@
+-- A mention without a role or user is of @everyone
+CREATE TABLE message_mentions (
+    message uuid NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    role uuid REFERENCES roles (id) ON DELETE CASCADE,
+    "user" uuid REFERENCES chat_users (id) ON DELETE CASCADE,
+    CHECK (role IS NULL OR "user" IS NULL)
+);
+
+CREATE INDEX message_mentions_message_idx ON message_mentions (message);
+
+-- The inbox of a user, one entry for each message they were mentioned in
+CREATE TABLE notifications (
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    message uuid NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    read boolean NOT NULL DEFAULT false,
+    PRIMARY KEY ("user", message)
+);
@@ -0,0 +1,18 @@
+-- The newest message each member has seen in a channel, newer messages are unread
+CREATE TABLE channel_reads (
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    channel uuid NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    last_read uuid NOT NULL,
+    PRIMARY KEY ("user", channel)
+);
+
+-- Existing members start with everything read, instead of the whole history being unread
+INSERT INTO channel_reads ("user", channel, last_read)
+SELECT m."user", c.id, newest.id
+FROM users_member_of_servers AS m
+JOIN channels AS c ON c.server = m.server
+CROSS JOIN LATERAL (
+    SELECT id FROM messages WHERE channel = c.id ORDER BY id DESC LIMIT 1
+) AS newest;
+
+CREATE INDEX messages_channel_id_idx ON messages (channel, id);
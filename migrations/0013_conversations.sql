@@ -0,0 +1,10 @@
+-- Direct conversations are channels without a server, so they share the messages of channels
+ALTER TABLE channels ALTER COLUMN server DROP NOT NULL;
+
+CREATE TABLE conversation_members (
+    channel uuid NOT NULL REFERENCES channels (id) ON DELETE CASCADE,
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    PRIMARY KEY (channel, "user")
+);
+
+CREATE INDEX conversation_members_user_idx ON conversation_members ("user");
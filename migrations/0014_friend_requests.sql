@@ -0,0 +1,10 @@
+-- Friendships are only added to users_friends once the recipient accepts the request
+CREATE TABLE friend_requests (
+    sender uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    recipient uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    created timestamp NOT NULL,
+    PRIMARY KEY (sender, recipient),
+    CHECK (sender <> recipient)
+);
+
+CREATE INDEX friend_requests_recipient_idx ON friend_requests (recipient);
@@ -0,0 +1,8 @@
+CREATE TABLE user_blocks (
+    blocker uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    blocked uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    PRIMARY KEY (blocker, blocked),
+    CHECK (blocker <> blocked)
+);
+
+CREATE INDEX user_blocks_blocked_idx ON user_blocks (blocked);
@@ -0,0 +1,7 @@
+-- Display names are shown instead of the login name when set. Avatars are kept in storage at a
+-- small and a large size, referenced by their storage ids.
+ALTER TABLE chat_users
+    ADD COLUMN display_name text,
+    ADD COLUMN bio text NOT NULL DEFAULT '',
+    ADD COLUMN avatar_small uuid,
+    ADD COLUMN avatar_large uuid;
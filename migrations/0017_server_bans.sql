@@ -0,0 +1,8 @@
+-- Banned users are removed from the server and can not be added back until unbanned
+CREATE TABLE server_bans (
+    server uuid NOT NULL REFERENCES servers (id) ON DELETE CASCADE,
+    "user" uuid NOT NULL REFERENCES chat_users (id) ON DELETE CASCADE,
+    created timestamp NOT NULL,
+    PRIMARY KEY (server, "user")
+);
+
@@ -0,0 +1,5 @@
+-- Whether others see the user as online while connected, and a short text shown with their status
+ALTER TABLE chat_users
+    ADD COLUMN status_mode text NOT NULL DEFAULT 'online'
+        CHECK (status_mode IN ('online', 'dnd', 'invisible')),
+    ADD COLUMN status_text text NOT NULL DEFAULT '';
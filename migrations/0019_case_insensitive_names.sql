@@ -0,0 +1,14 @@
+-- Mentions match names regardless of case, so two users can not only differ in it
+
+-- Names that only differ in case are kept by the oldest account, the others get a suffix from
+-- their id and keep showing the old name as their display name
+UPDATE chat_users AS u
+SET name = left(u.name, 23) || '-' || right(replace(u.id::text, '-', ''), 8),
+    display_name = COALESCE(u.display_name, u.name)
+WHERE EXISTS (
+    SELECT * FROM chat_users AS other
+    WHERE lower(other.name) = lower(u.name) AND other.id < u.id
+);
+
+DROP INDEX chat_users_name_key;
+CREATE UNIQUE INDEX chat_users_name_key ON chat_users (lower(name));
@@ -0,0 +1,21 @@
+-- What the reader has not read in the channel: the messages by others after the last one they
+-- read, and how many of those mention them. Replies in threads are not shown in the channel, so
+-- they do not count.
+CREATE FUNCTION channel_unread(reader uuid, channel_id uuid)
+RETURNS TABLE (messages bigint, mentions bigint)
+LANGUAGE sql STABLE
+AS $$
+    SELECT count(*),
+        count(*) FILTER (WHERE EXISTS (
+            SELECT * FROM message_mentions AS mm
+            WHERE mm.message = m.id AND (
+                mm."user" = reader
+                OR (mm.role IS NULL AND mm."user" IS NULL)
+                OR mm.role IN (SELECT role FROM members_have_roles WHERE "user" = reader)
+            )
+        ))
+    FROM messages AS m
+    LEFT JOIN channel_reads AS r ON r.channel = m.channel AND r."user" = reader
+    WHERE m.channel = channel_id AND m.thread IS NULL AND m.author <> reader
+        AND m.id > COALESCE(r.last_read, '00000000-0000-0000-0000-000000000000')
+$$;
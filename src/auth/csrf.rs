@@ -0,0 +1,69 @@
+use axum::{
+    extract::Request,
+    http::HeaderName,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::CookieJar;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+use super::SESSION_COOKIE;
+
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Routes that authenticate with credentials instead of the session cookie
+const EXEMPT_PATHS: [&str; 2] = ["/login", "/register"];
+
+tokio::task_local! {
+    static CSRF_TOKEN: Option<String>;
+}
+
+/// The csrf token of the session the current request is handled for
+pub fn current_token() -> Option<String> {
+    CSRF_TOKEN.try_with(Clone::clone).ok().flatten()
+}
+
+/// Rejects state changing requests that do not carry the csrf token of the session.
+///
+/// The token is derived from the session token, so it is unique per session without having to be
+/// stored. It is made available to `base_tempalte` for the duration of the request, which sends
+/// it back with every htmx request through `hx-headers`.
+pub async fn csrf_protection(cookies: CookieJar, request: Request, next: Next) -> Response {
+    let token = cookies
+        .get(SESSION_COOKIE)
+        .map(|session| derive_token(session.value_trimmed()));
+
+    if !request.method().is_safe() && !EXEMPT_PATHS.contains(&request.uri().path()) {
+        if let Some(token) = &token {
+            let provided = request
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok());
+            if !provided.is_some_and(|provided| constant_time_eq(provided, token)) {
+                return Error::CsrfTokenMismatch.into_response();
+            }
+        }
+    }
+
+    CSRF_TOKEN.scope(token, next.run(request)).await
+}
+
+fn derive_token(session_token: &str) -> String {
+    Sha256::new()
+        .chain_update(b"csrf:")
+        .chain_update(session_token.as_bytes())
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -0,0 +1,338 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::Uri,
+    response::{IntoResponse, Redirect, Response},
+    Form,
+};
+use axum_extra::{extract::CookieJar, headers::UserAgent, TypedHeader};
+use axum_htmx::HxRedirect;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::{
+    base_tempalte,
+    error::{Error, Result},
+    header, AppState,
+};
+
+use super::{
+    sanitize_redirect, session_cookie, session_cookie_removal, sessions, validate_username,
+    SESSION_COOKIE,
+};
+
+const PASSWORD_LEN: std::ops::RangeInclusive<usize> = 8..=128;
+
+#[derive(Deserialize)]
+pub struct RedirectQuery {
+    redirect: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct LoginForm {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    password: String,
+    redirect: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct RegisterForm {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    confirm_password: String,
+    redirect: Option<String>,
+}
+
+#[derive(Default)]
+struct FormErrors {
+    name: Option<&'static str>,
+    password: Option<&'static str>,
+    confirm_password: Option<&'static str>,
+    form: Option<&'static str>,
+}
+
+impl FormErrors {
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.password.is_none()
+            && self.confirm_password.is_none()
+            && self.form.is_none()
+    }
+}
+
+pub async fn get_login(Query(RedirectQuery { redirect }): Query<RedirectQuery>) -> Markup {
+    render_login_page(
+        &LoginForm {
+            redirect,
+            ..Default::default()
+        },
+        &FormErrors::default(),
+    )
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    cookies: CookieJar,
+    Form(form): Form<LoginForm>,
+) -> Result<Response> {
+    let mut errors = FormErrors::default();
+    if form.name.trim().is_empty() {
+        errors.name = Some("Enter your username");
+    }
+    if form.password.is_empty() {
+        errors.password = Some("Enter your password");
+    }
+    if !errors.is_empty() {
+        return Ok(render_login_page(&form, &errors).into_response());
+    }
+
+    let user = query!(
+        r#"SELECT id, password_hash FROM chat_users WHERE lower(name) = lower($1)"#,
+        form.name.trim(),
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    let user_id = match user {
+        Some(user) => match user.password_hash {
+            Some(hash) => verify_password(form.password.clone(), hash)
+                .await?
+                .then_some(user.id),
+            None => None,
+        },
+        None => None,
+    };
+    let Some(user_id) = user_id else {
+        errors.form = Some("Wrong username or password");
+        return Ok(render_login_page(&form, &errors).into_response());
+    };
+
+    let token = sessions::create_session(
+        &state.db,
+        user_id,
+        user_agent
+            .as_ref()
+            .map(|TypedHeader(user_agent)| user_agent.as_str()),
+        addr.ip(),
+    )
+    .await?;
+
+    Ok((
+        cookies.add(session_cookie(token)),
+        Redirect::to(sanitize_redirect(form.redirect.as_deref())),
+    )
+        .into_response())
+}
+
+pub async fn get_register(Query(RedirectQuery { redirect }): Query<RedirectQuery>) -> Markup {
+    render_register_page(
+        &RegisterForm {
+            redirect,
+            ..Default::default()
+        },
+        &FormErrors::default(),
+    )
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    cookies: CookieJar,
+    Form(form): Form<RegisterForm>,
+) -> Result<Response> {
+    let name = form.name.trim();
+
+    let mut errors = FormErrors {
+        name: validate_username(name),
+        ..Default::default()
+    };
+    if !PASSWORD_LEN.contains(&form.password.chars().count()) {
+        errors.password = Some("Password must be between 8 and 128 characters");
+    }
+    if form.password != form.confirm_password {
+        errors.confirm_password = Some("Passwords do not match");
+    }
+    if !errors.is_empty() {
+        return Ok(render_register_page(&form, &errors).into_response());
+    }
+
+    let password_hash = hash_password(form.password.clone()).await?;
+
+    let new_id = Uuid::now_v7();
+    let rows_affected = query!(
+        r#"INSERT INTO chat_users (id, name, password_hash) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING"#,
+        new_id,
+        name,
+        password_hash,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        errors.name = Some("Username is already taken");
+        return Ok(render_register_page(&form, &errors).into_response());
+    }
+
+    let token = sessions::create_session(
+        &state.db,
+        new_id,
+        user_agent
+            .as_ref()
+            .map(|TypedHeader(user_agent)| user_agent.as_str()),
+        addr.ip(),
+    )
+    .await?;
+
+    Ok((
+        cookies.add(session_cookie(token)),
+        Redirect::to(sanitize_redirect(form.redirect.as_deref())),
+    )
+        .into_response())
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    cookies: CookieJar,
+) -> Result<impl IntoResponse> {
+    if let Some(token) = cookies.get(SESSION_COOKIE) {
+        if let Some(session_id) = sessions::revoke_session(&state.db, token.value_trimmed()).await?
+        {
+            state.message_live.close_session_streams(session_id).await;
+        }
+    }
+    // A full page load is needed so the page picks up the csrf token of the next session
+    Ok((
+        cookies.add(session_cookie_removal()),
+        HxRedirect(Uri::from_static("/login")),
+        html!(),
+    ))
+}
+
+async fn hash_password(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|_| Error::PasswordHashingFailed)?
+    .map_err(|_| Error::PasswordHashingFailed)
+}
+
+async fn verify_password(password: String, password_hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&password_hash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err),
+        }
+    })
+    .await
+    .map_err(|_| Error::PasswordHashingFailed)?
+    .map_err(|_| Error::PasswordHashingFailed)
+}
+
+fn redirect_query(redirect: Option<&str>) -> String {
+    redirect
+        .and_then(|redirect| serde_urlencoded::to_string([("redirect", redirect)]).ok())
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default()
+}
+
+fn render_field(
+    label: &str,
+    name: &str,
+    input_type: &str,
+    value: Option<&str>,
+    error: Option<&str>,
+) -> Markup {
+    html!(
+        label class="form-control w-full" {
+            .label { .label-text { (label) } }
+            input.input.input-bordered.w-full.input-error[error.is_some()]
+                type=(input_type)
+                name=(name)
+                value=[value]
+                required;
+            @if let Some(error) = error {
+                .label { span.label-text-alt.text-error { (error) } }
+            }
+        }
+    )
+}
+
+fn render_auth_page(title: &str, content: Markup) -> Markup {
+    base_tempalte(html!(
+        (header())
+        main class="grid place-items-center px-4 py-16" {
+            div class="card w-full max-w-sm bg-base-200" {
+                .card-body {
+                    h1.card-title { (title) }
+                    (content)
+                }
+            }
+        }
+    ))
+}
+
+fn render_login_page(form: &LoginForm, errors: &FormErrors) -> Markup {
+    render_auth_page(
+        "Login",
+        html!(
+            form.flex.flex-col.gap-2 method="post" action="/login" hx-boost="false" {
+                @if let Some(redirect) = &form.redirect {
+                    input type="hidden" name="redirect" value=(redirect);
+                }
+                (render_field("Username", "name", "text", Some(&form.name), errors.name))
+                (render_field("Password", "password", "password", None, errors.password))
+                @if let Some(error) = errors.form {
+                    .text-error.text-sm { (error) }
+                }
+                .card-actions.items-center.justify-between.mt-4 {
+                    a.link href={"/register"(redirect_query(form.redirect.as_deref()))} { "Create an account" }
+                    button type="submit" class="btn btn-primary" { "Login" }
+                }
+            }
+        ),
+    )
+}
+
+fn render_register_page(form: &RegisterForm, errors: &FormErrors) -> Markup {
+    render_auth_page(
+        "Register",
+        html!(
+            form.flex.flex-col.gap-2 method="post" action="/register" hx-boost="false" {
+                @if let Some(redirect) = &form.redirect {
+                    input type="hidden" name="redirect" value=(redirect);
+                }
+                (render_field("Username", "name", "text", Some(&form.name), errors.name))
+                (render_field("Password", "password", "password", None, errors.password))
+                (render_field("Confirm password", "confirm_password", "password", None, errors.confirm_password))
+                @if let Some(error) = errors.form {
+                    .text-error.text-sm { (error) }
+                }
+                .card-actions.items-center.justify-between.mt-4 {
+                    a.link href={"/login"(redirect_query(form.redirect.as_deref()))} { "Already have an account?" }
+                    button type="submit" class="btn btn-primary" { "Register" }
+                }
+            }
+        ),
+    )
+}
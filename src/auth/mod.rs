@@ -0,0 +1,195 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, OriginalUri},
+    http::{request::Parts, uri::PathAndQuery, Uri},
+    response::Redirect,
+    routing, Router,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use axum_htmx::{HxBoosted, HxCurrentUrl, HxRequest};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::AppState;
+
+pub mod csrf;
+mod login;
+mod sessions;
+
+const SESSION_COOKIE: &str = "session";
+const USERNAME_LEN: std::ops::RangeInclusive<usize> = 3..=32;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/login", routing::get(login::get_login).post(login::login))
+        .route(
+            "/register",
+            routing::get(login::get_register).post(login::register),
+        )
+        .route("/logout", routing::post(login::logout))
+}
+
+/// Checks a trimmed username, returns why it can not be used
+pub fn validate_username(name: &str) -> Option<&'static str> {
+    if !USERNAME_LEN.contains(&name.chars().count()) {
+        Some("Username must be between 3 and 32 characters")
+    } else if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        Some("Username may only contain letters, numbers, '_', '-' and '.'")
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    pub id: Uuid,
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Auth {
+    type Rejection = Redirect;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(cookies) = CookieJar::from_request_parts(parts, state).await;
+        let Some(token) = cookies.get(SESSION_COOKIE) else {
+            return Err(redirect_to_login(parts, state).await);
+        };
+        match sessions::resolve_session(&state.db, token.value_trimmed()).await {
+            Ok(Some(session)) => Ok(Auth {
+                id: session.user_id,
+                session_id: session.id,
+            }),
+            Ok(None) => Err(redirect_to_login(parts, state).await),
+            Err(err) => {
+                error!(?err, "Failed to resolve session");
+                Err(redirect_to_login(parts, state).await)
+            }
+        }
+    }
+}
+
+/// Redirects to the login page, remembering which page to return to after logging in.
+///
+/// Non-boosted htmx requests (modals, partials) are sent back to the page they were made from
+/// instead of the partial itself.
+async fn redirect_to_login<S: Send + Sync>(parts: &mut Parts, state: &S) -> Redirect {
+    let Ok(HxRequest(is_htmx)) = HxRequest::from_request_parts(parts, state).await;
+    let Ok(HxBoosted(is_boosted)) = HxBoosted::from_request_parts(parts, state).await;
+    let Ok(HxCurrentUrl(current_url)) = HxCurrentUrl::from_request_parts(parts, state).await;
+
+    let uri = match current_url {
+        Some(current_url) if is_htmx && !is_boosted => current_url,
+        _ => parts
+            .extensions
+            .get::<OriginalUri>()
+            .map(|OriginalUri(uri)| uri.clone())
+            .unwrap_or_else(|| parts.uri.clone()),
+    };
+
+    login_redirect(&uri)
+}
+
+fn login_redirect(uri: &Uri) -> Redirect {
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    match serde_urlencoded::to_string([("redirect", path)]) {
+        Ok(query) => Redirect::temporary(&format!("/login?{query}")),
+        Err(_) => Redirect::temporary("/login"),
+    }
+}
+
+/// Only allows redirecting to paths on this site, falling back to the home page.
+///
+/// Browsers drop tabs and newlines from urls, so those could turn the path into another host, and
+/// they are not allowed in the location header either.
+fn sanitize_redirect(redirect: Option<&str>) -> &str {
+    match redirect {
+        Some(redirect)
+            if redirect.starts_with('/')
+                && !redirect
+                    .chars()
+                    .any(|c| c.is_control() || c.is_whitespace())
+                && redirect.parse::<PathAndQuery>().is_ok()
+                && !redirect.starts_with("//")
+                && !redirect.starts_with("/\\")
+                && !redirect.starts_with("/login")
+                && !redirect.starts_with("/register")
+                && !redirect.starts_with("/logout") =>
+        {
+            redirect
+        }
+        _ => "/",
+    }
+}
+
+fn session_cookie<'c>(token: String) -> Cookie<'c> {
+    Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            sessions::SESSION_DURATION.num_seconds(),
+        ))
+        .build()
+}
+
+fn session_cookie_removal<'c>() -> Cookie<'c> {
+    Cookie::build(SESSION_COOKIE)
+        .removal()
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_stay_on_the_site() {
+        assert_eq!(sanitize_redirect(Some("/servers/1?a=b")), "/servers/1?a=b");
+        assert_eq!(sanitize_redirect(None), "/");
+        assert_eq!(sanitize_redirect(Some("")), "/");
+        assert_eq!(sanitize_redirect(Some("https://evil.example")), "/");
+        assert_eq!(sanitize_redirect(Some("//evil.example")), "/");
+        assert_eq!(sanitize_redirect(Some("/\\evil.example")), "/");
+        assert_eq!(sanitize_redirect(Some("/\t/evil.example")), "/");
+        assert_eq!(sanitize_redirect(Some("/x\n")), "/");
+        assert_eq!(sanitize_redirect(Some("/a b")), "/");
+    }
+
+    #[test]
+    fn redirects_skip_the_auth_pages() {
+        assert_eq!(sanitize_redirect(Some("/login?redirect=/")), "/");
+        assert_eq!(sanitize_redirect(Some("/register")), "/");
+        assert_eq!(sanitize_redirect(Some("/logout")), "/");
+    }
+
+    #[test]
+    fn username_length_counts_characters() {
+        assert_eq!(validate_username("abc"), None);
+        assert_eq!(validate_username(&"a".repeat(32)), None);
+        assert_eq!(validate_username("žšč"), None);
+        assert!(validate_username("ab").is_some());
+        assert!(validate_username(&"a".repeat(33)).is_some());
+    }
+
+    #[test]
+    fn username_characters() {
+        assert_eq!(validate_username("j.doe-2_x"), None);
+        assert!(validate_username("jane doe").is_some());
+        assert!(validate_username("@jane").is_some());
+        assert!(validate_username("<b>jane</b>").is_some());
+    }
+}
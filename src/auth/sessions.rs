@@ -0,0 +1,113 @@
+use std::net::IpAddr;
+
+use chrono::{TimeDelta, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use sqlx::{query, PgPool};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+
+/// How long a session stays valid without being used
+pub const SESSION_DURATION: TimeDelta = TimeDelta::days(30);
+/// `last_seen` is only written when it is older than this, to avoid a write on every request
+const LAST_SEEN_RESOLUTION: TimeDelta = TimeDelta::minutes(1);
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+}
+
+/// Creates a new session for the user and returns the opaque token to give to the client.
+///
+/// Only a hash of the token is stored, so a leaked sessions table cannot be used to log in.
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: Uuid,
+    user_agent: Option<&str>,
+    ip: IpAddr,
+) -> Result<String> {
+    let token = generate_token();
+    let now = Utc::now().naive_utc();
+
+    let mut transaction = pool.begin().await?;
+    query!(
+        r#"DELETE FROM sessions WHERE "user" = $1 AND expires <= $2"#,
+        user_id,
+        now,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let rows_affected = query!(
+        r#"INSERT INTO sessions (id, token_hash, "user", last_seen, expires, user_agent, ip)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+        Uuid::now_v7(),
+        hash_token(&token),
+        user_id,
+        now,
+        now + SESSION_DURATION,
+        user_agent,
+        ip.to_string(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+    transaction.commit().await?;
+
+    Ok(token)
+}
+
+/// Looks up the session belonging to the token, extending its expiry if it is still valid.
+pub async fn resolve_session(pool: &PgPool, token: &str) -> Result<Option<Session>> {
+    let now = Utc::now().naive_utc();
+    let Some(session) = query!(
+        r#"SELECT id, "user", last_seen FROM sessions WHERE token_hash = $1 AND expires > $2"#,
+        hash_token(token),
+        now,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    if now - session.last_seen > LAST_SEEN_RESOLUTION {
+        query!(
+            r#"UPDATE sessions SET last_seen = $1, expires = $2 WHERE id = $3"#,
+            now,
+            now + SESSION_DURATION,
+            session.id,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(Some(Session {
+        id: session.id,
+        user_id: session.user,
+    }))
+}
+
+/// Deletes the session belonging to the token, returning its id if it existed.
+pub async fn revoke_session(pool: &PgPool, token: &str) -> Result<Option<Uuid>> {
+    Ok(query!(
+        r#"DELETE FROM sessions WHERE token_hash = $1 RETURNING id"#,
+        hash_token(token),
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|session| session.id))
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
@@ -1,72 +1,129 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     response::IntoResponse,
+    Extension,
 };
 use maud::html;
+use serde::Deserialize;
 use tokio::try_join;
+use uuid::Uuid;
 
 use crate::{
     auth::Auth,
     base_tempalte,
+    conversations::fetch_render_conversation_list,
     error::Result,
     header,
     servers::{
         channels::{
-            fetch_render_channel_list, messages::fetch_render_message_list, MaybeChannelId,
+            fetch_render_channel_list,
+            messages::{fetch_render_message_list, render_message_form, ChannelIds},
+            unread::mark_read,
+            MaybeChannelId,
         },
-        fetch_render_server_list, MaybeServerId,
+        fetch_render_server_list,
+        member_list::fetch_render_member_panel,
+        permissions::{ChannelPermissions, Permissions},
+        search::render_search_form,
+        MaybeServerId,
     },
     AppState,
 };
 
+#[derive(Deserialize)]
+pub struct JumpTo {
+    /// Shows the channel around this message instead of the latest messages
+    around: Option<Uuid>,
+}
+
 pub async fn get_chat_page(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     Path(MaybeChannelId { channel_id }): Path<MaybeChannelId>,
     Path(MaybeServerId { server_id }): Path<MaybeServerId>,
+    Query(JumpTo { around }): Query<JumpTo>,
+    permissions: Option<Extension<Permissions>>,
+    channel_permissions: Option<Extension<ChannelPermissions>>,
 ) -> Result<impl IntoResponse> {
-    let (server_list, channel_list, messages_list) = try_join!(
+    let permissions = permissions.map_or(Permissions::empty(), |Extension(p)| p);
+    let channel_permissions =
+        channel_permissions.map_or(Permissions::empty(), |Extension(ChannelPermissions(p))| p);
+    let can_send = channel_permissions.contains(Permissions::SEND_MESSAGES);
+    // Read before the lists are rendered, so the opened channel is not counted as unread
+    let last_read = match channel_id {
+        Some(channel_id) => mark_read(&state.db, user_id, channel_id).await?,
+        None => None,
+    };
+    let (server_list, channel_list, messages_list, member_panel) = try_join!(
         fetch_render_server_list(&state.db, user_id, server_id),
         async {
-            Ok(if let Some(server_id) = server_id {
-                Some(fetch_render_channel_list(&state.db, server_id, channel_id).await?)
+            if let Some(server_id) = server_id {
+                fetch_render_channel_list(&state.db, server_id, channel_id, user_id, permissions)
+                    .await
+            } else {
+                // Direct conversations take the place of the channels without a server
+                fetch_render_conversation_list(&state.db, user_id, channel_id).await
+            }
+        },
+        async {
+            Ok(if let Some(channel_id) = channel_id {
+                let ids = ChannelIds {
+                    channel_id,
+                    server_id,
+                };
+                Some((
+                    fetch_render_message_list(
+                        &state.db,
+                        ids,
+                        user_id,
+                        channel_permissions,
+                        around,
+                        last_read,
+                    )
+                    .await?,
+                    ids,
+                ))
             } else {
                 None
             })
         },
         async {
-            Ok(
-                if let (Some(server_id), Some(channel_id)) = (server_id, channel_id) {
-                    Some((
-                        fetch_render_message_list(&state.db, server_id, channel_id, user_id)
-                            .await?,
-                        (server_id, channel_id),
-                    ))
-                } else {
-                    None
-                },
-            )
+            Ok(match server_id {
+                Some(server_id) => Some(fetch_render_member_panel(&state, server_id).await?),
+                None => None,
+            })
         }
     )?;
 
     Ok(base_tempalte(html!(
-        main class="grid max-h-screen min-h-screen px-4 py-2" style="grid-template-columns: auto auto 1fr; grid-template-rows: auto minmax(0,1fr)" {
+        main class="grid max-h-screen min-h-screen px-4 py-2" style="grid-template-columns: auto auto 1fr auto auto; grid-template-rows: auto minmax(0,1fr)" {
             .col-span-full { (header()) }
+            // Keeps the unread counts of the server and channel lists up to date
+            .hidden hx-ext="sse" sse-connect="/servers/activity" sse-swap="activity" hx-swap="none" {}
+            // Keeps the user from being shown as idle while they use the page
+            .hidden hx-post="/users/presence/active" hx-trigger="keydown from:body throttle:60s, mousemove from:body throttle:60s" hx-swap="none" {}
             (server_list)
-            (channel_list.unwrap_or(html!(ul #channels-list {})))
-            #chat-wrapper.grid style="grid-template-rows: 1fr auto" {
-                @if let Some((messages_list, (server_id, channel_id))) = messages_list {
+            (channel_list)
+            #chat-wrapper.grid style="grid-template-rows: auto 1fr auto" {
+                @if let Some(server_id) = server_id {
+                    (render_search_form(server_id))
+                } @else {
+                    div {}
+                }
+                @if let Some((messages_list, ids)) = messages_list {
                     (messages_list)
-                    form #message-form.flex.items-end.gap-2
-                        hx-post={"/servers/"(server_id)"/channels/"(channel_id)"/messages"}
-                        hx-swap="none"
-                        "hx-on::after-request"="if (event.detail.successful) this.reset()"
-                    {
-                        input.input.input-bordered.grow name="content" placeholder="Type here...";
-                        button.btn.btn-primary { "Send" }
+                    @if can_send {
+                        (render_message_form(ids, None, &state.attachment_limits))
+                    } @else {
+                        p.text-center.italic.opacity-50.py-2 { "You do not have permission to send messages in this channel" }
                     }
                 }
             }
+            // Threads and search results are opened in here, at the full height of the page
+            #thread-wrapper.grid style="grid-template-rows: minmax(0,1fr)" {}
+            @if let Some(member_panel) = member_panel {
+                (member_panel)
+            }
         }
     )))
 }
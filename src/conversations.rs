@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, Uri},
+    middleware::{from_fn_with_state, Next},
+    response::IntoResponse,
+    routing, Form, Router,
+};
+use axum_htmx::{HxRedirect, HxResponseTrigger};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_scalar, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    chat::get_chat_page,
+    error::{Error, Result},
+    servers::{
+        channels::{
+            messages,
+            unread::{fetch_conversation_unread, mark_read, render_channel_unread},
+            ChannelId, MaybeChannelId,
+        },
+        permissions::{ChannelPermissions, Permissions},
+    },
+    AppState,
+};
+
+/// Including the user that starts it
+pub const MAX_MEMBERS: usize = 10;
+
+/// Direct conversations are channels without a server, shared by the friends that are in it
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .nest("/:channel_id/messages", messages::router(state.clone()))
+        .route("/:channel_id/read", routing::post(read_conversation))
+        .route("/:channel_id", routing::get(get_chat_page))
+        .layer(from_fn_with_state(state.clone(), is_user_in_conversation))
+        .route(
+            "/",
+            routing::get(get_conversations).post(create_conversation),
+        )
+        .route("/new", routing::get(open_new_conversation))
+}
+
+/// Every member of a conversation can read and send messages, but not manage those of others.
+/// Members blocked by someone in the conversation can only read it.
+///
+/// Makes these available to handlers as [`ChannelPermissions`].
+async fn is_user_in_conversation(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    mut request: Request,
+    next: Next,
+) -> Result<impl IntoResponse> {
+    let member = query!(
+        r#"SELECT EXISTS(
+        SELECT * FROM conversation_members AS cm
+        JOIN user_blocks AS b ON b.blocker = cm."user" AND b.blocked = $2
+        WHERE cm.channel = $1
+    ) as "is_blocked!"
+    FROM conversation_members
+    WHERE channel = $1 AND "user" = $2"#,
+        channel_id,
+        user_id,
+    )
+    .fetch_optional(&state.db)
+    .await?;
+    let Some(member) = member else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let permissions = if member.is_blocked {
+        Permissions::VIEW_CHANNEL
+    } else {
+        Permissions::DEFAULT
+    };
+    request
+        .extensions_mut()
+        .insert(ChannelPermissions(permissions));
+    Ok(next.run(request).await)
+}
+
+/// Friends are sent as one checkbox per friend, named after their id
+#[derive(Deserialize)]
+struct NewConversation {
+    #[serde(flatten)]
+    members: HashMap<String, String>,
+}
+
+/// Opens the conversation with the friends, starting it if there is none with exactly them
+async fn create_conversation(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Form(NewConversation { members }): Form<NewConversation>,
+) -> Result<impl IntoResponse> {
+    let mut members = members
+        .keys()
+        .map(|id| Uuid::try_parse(id).map_err(|_| Error::InvalidFormField { field: "members" }))
+        .collect::<Result<Vec<_>>>()?;
+    members.retain(|id| id != &user_id);
+    members.sort();
+    members.dedup();
+    if members.is_empty() || members.len() >= MAX_MEMBERS {
+        return Err(Error::InvalidFormField { field: "members" });
+    }
+
+    let friends = query_scalar!(
+        r#"SELECT friend FROM users_friends WHERE "user" = $1 AND friend = ANY($2)"#,
+        user_id,
+        &members,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    if friends.len() != members.len() {
+        return Err(Error::NotFriends);
+    }
+
+    members.push(user_id);
+    let existing = query_scalar!(
+        r#"SELECT channel FROM conversation_members
+    GROUP BY channel
+    HAVING array_agg("user" ORDER BY "user") = (SELECT array_agg(id ORDER BY id) FROM unnest($1::uuid[]) AS id)
+    LIMIT 1"#,
+        &members,
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    let channel_id = match existing {
+        Some(channel_id) => channel_id,
+        None => {
+            let new_id = Uuid::now_v7();
+            let mut transaction = state.db.begin().await?;
+            // Conversations are named after their members when shown
+            let rows_affected = query!(
+                r#"INSERT INTO channels (id, name, server) VALUES ($1, '', NULL)"#,
+                new_id,
+            )
+            .execute(&mut *transaction)
+            .await?;
+            if rows_affected.rows_affected() != 1 {
+                return Err(Error::DatabaseActionFailed);
+            }
+            let rows_affected = query!(
+                r#"INSERT INTO conversation_members (channel, "user")
+            SELECT $1, "user" FROM unnest($2::uuid[]) AS "user""#,
+                new_id,
+                &members,
+            )
+            .execute(&mut *transaction)
+            .await?;
+            if rows_affected.rows_affected() != members.len() as u64 {
+                return Err(Error::DatabaseActionFailed);
+            }
+            transaction.commit().await?;
+            new_id
+        }
+    };
+
+    Ok((
+        HxRedirect(
+            Uri::try_from(format!("/conversations/{channel_id}"))
+                .expect("conversation path to be a valid uri"),
+        ),
+        html!(),
+    ))
+}
+
+/// Picks the friends to start a group conversation with
+async fn open_new_conversation(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> Result<impl IntoResponse> {
+    let friends = query!(
+        r#"SELECT u.id, COALESCE(u.display_name, u.name) as "name!"
+    FROM users_friends AS f
+    JOIN chat_users AS u ON u.id = f.friend
+    WHERE f."user" = $1
+    ORDER BY 2"#,
+        user_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html!(
+            h3.text-lg.font-bold { "New conversation" }
+            form hx-post="/conversations" {
+                p.text-sm.opacity-70 { "Pick up to " (MAX_MEMBERS - 1) " friends" }
+                ul.my-2.flex.flex-col.gap-1 {
+                    @for friend in &friends {
+                        li {
+                            label.label.cursor-pointer.justify-start.gap-2 {
+                                input type="checkbox" class="checkbox checkbox-sm" name=(friend.id);
+                                span.label-text { (friend.name) }
+                            }
+                        }
+                    }
+                    @if friends.is_empty() {
+                        li.italic.opacity-50 { "Add friends to start a conversation with them" }
+                    }
+                }
+                .modal-action {
+                    button type="submit" class="btn btn-primary" { "Start" }
+                }
+            }
+        )),
+    ))
+}
+
+/// Sent while the user is looking at the conversation and new messages come in
+async fn read_conversation(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+) -> Result<impl IntoResponse> {
+    mark_read(&state.db, user_id, channel_id).await?;
+
+    Ok(render_channel_unread(channel_id, Default::default(), true))
+}
+
+async fn get_conversations(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Query(MaybeChannelId { channel_id }): Query<MaybeChannelId>,
+) -> Result<impl IntoResponse> {
+    fetch_render_conversation_list(&state.db, user_id, channel_id).await
+}
+/// The conversations of the user, those with the latest messages first
+pub async fn fetch_render_conversation_list(
+    pool: &PgPool,
+    user_id: Uuid,
+    active_channel: Option<Uuid>,
+) -> Result<Markup> {
+    let conversations = query!(
+        r#"SELECT me.channel, array_agg(COALESCE(u.display_name, u.name) ORDER BY COALESCE(u.display_name, u.name)) as "names!"
+    FROM conversation_members AS me
+    JOIN conversation_members AS cm ON cm.channel = me.channel AND cm."user" <> me."user"
+    JOIN chat_users AS u ON u.id = cm."user"
+    WHERE me."user" = $1
+    GROUP BY me.channel
+    ORDER BY (SELECT id FROM messages WHERE channel = me.channel ORDER BY id DESC LIMIT 1) DESC NULLS LAST,
+        me.channel DESC"#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let unread = fetch_conversation_unread(pool, user_id).await?;
+
+    Ok(html!(
+        ul #channels-list
+            class="menu rounded-box bg-base-200"
+            hx-get={"/conversations?channel_id="(active_channel.unwrap_or_default())}
+            hx-trigger="get-conversation-list from:body"
+            hx-swap="outerHTML"
+        {
+            li.menu-title {
+                button class="btn btn-ghost btn-sm" hx-get="/conversations/new" hx-target="#modalInner" { "New" }
+            }
+            @for conversation in conversations {
+                @let unread = unread.get(&conversation.channel).copied().unwrap_or_default();
+                li #{"channel-"(conversation.channel)} {
+                    div.active[active_channel.is_some_and(|id| id == conversation.channel)].flex {
+                        a.grow href={"/conversations/"(conversation.channel)} {
+                            (conversation.names.join(", "))
+                        }
+                        (render_channel_unread(conversation.channel, unread, false))
+                    }
+                }
+            }
+        }
+    ))
+}
@@ -6,7 +6,7 @@ use maud::html;
 use tracing::debug;
 use uuid::Uuid;
 
-use crate::base_tempalte;
+use crate::{base_tempalte, servers::permissions::Permissions};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -17,6 +17,25 @@ pub enum Error {
     SSERegistationDidNotRecvChannel,
     SSEChannelRegistrationChannelFailed,
 
+    // Auth
+    PasswordHashingFailed,
+    CsrfTokenMismatch,
+    MissingPermissions { needed: Permissions },
+
+    // Attachments
+    Multipart(axum::extract::multipart::MultipartError),
+    AttachmentTooLarge { max_size: usize },
+    AttachmentTypeNotAllowed { content_type: String },
+    TooManyAttachments,
+    Storage(std::io::Error),
+    ImageProcessingFailed,
+
+    MessageTooLong { max_len: usize },
+    UnknownReaction { emoji: String },
+    NotFriends,
+    UserBanned,
+    InvalidFormField { field: &'static str },
+
     // Database
     DatabaseActionFailed,
     DB(sqlx::Error),
@@ -26,20 +45,78 @@ impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::NoTimestampFromUuid { id } => write!(f, "Uuid {id} has no timestamp"),
+            Error::MissingPermissions { needed } => write!(f, "Missing permissions {needed:?}"),
+            Error::Multipart(err) => write!(f, "Multipart: {err}"),
+            Error::AttachmentTooLarge { max_size } => {
+                write!(f, "Attachment is larger than {max_size} bytes")
+            }
+            Error::AttachmentTypeNotAllowed { content_type } => {
+                write!(f, "Attachment type {content_type} is not allowed")
+            }
+            Error::Storage(err) => write!(f, "Storage: {err}"),
+            Error::MessageTooLong { max_len } => {
+                write!(f, "Message is longer than {max_len} characters")
+            }
+            Error::UnknownReaction { emoji } => write!(f, "Unknown reaction {emoji}"),
+            Error::InvalidFormField { field } => write!(f, "Invalid form field {field}"),
+            Error::DB(err) => write!(f, "Database: {err}"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let id = Uuid::now_v7().to_string();
-        debug!(error = ?self, id = &id, "An error occured");
+        debug!(error = %self, id = &id, "An error occured");
+        let (status, message) = match self {
+            Error::CsrfTokenMismatch => (
+                StatusCode::FORBIDDEN,
+                "The request could not be verified, reload the page and try again",
+            ),
+            Error::MissingPermissions { .. } => (
+                StatusCode::FORBIDDEN,
+                "You do not have permission to do that",
+            ),
+            Error::Multipart(_) => (StatusCode::BAD_REQUEST, "The upload could not be read"),
+            Error::AttachmentTooLarge { .. } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The attachment is larger than allowed",
+            ),
+            Error::AttachmentTypeNotAllowed { .. } => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "That type of file can not be attached",
+            ),
+            Error::TooManyAttachments => (StatusCode::BAD_REQUEST, "Too many attachments"),
+            Error::MessageTooLong { .. } => (StatusCode::BAD_REQUEST, "The message is too long"),
+            Error::NotFriends => (
+                StatusCode::FORBIDDEN,
+                "You can only start conversations with your friends",
+            ),
+            Error::UserBanned => (
+                StatusCode::FORBIDDEN,
+                "That user is banned from this server",
+            ),
+            Error::UnknownReaction { .. } => {
+                (StatusCode::BAD_REQUEST, "That reaction is not available")
+            }
+            Error::InvalidFormField { .. } => (
+                StatusCode::BAD_REQUEST,
+                "The form was not filled in correctly",
+            ),
+            Error::DB(sqlx::Error::RowNotFound) => {
+                (StatusCode::NOT_FOUND, "That could not be found")
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "An error occured"),
+        };
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            status,
             base_tempalte(html!(
               main class="grid min-h-screen place-items-center" {
                 div {
-                  h1 class="text-center text-2xl" { "An error occured" }
+                  h1 class="text-center text-2xl" { (message) }
                   p class="text-center" { "Bellow is an error id" }
                   p class="text-center" { (id) }
                 }
@@ -55,3 +132,9 @@ impl From<sqlx::Error> for Error {
         Error::DB(value)
     }
 }
+
+impl From<axum::extract::multipart::MultipartError> for Error {
+    fn from(value: axum::extract::multipart::MultipartError) -> Self {
+        Error::Multipart(value)
+    }
+}
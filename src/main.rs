@@ -1,12 +1,16 @@
-use axum::{response::Redirect, routing, Router};
+use std::sync::Arc;
+
+use axum::{routing, Router};
 use maud::{html, PreEscaped};
 use sqlx::postgres::{PgListener, PgPool};
 use tracing::{info, info_span};
 
 mod auth;
 mod chat;
+mod conversations;
 mod error;
 mod servers;
+mod storage;
 mod users;
 mod utils;
 
@@ -34,7 +38,9 @@ fn base_tempalte(content: maud::Markup) -> maud::Markup {
                 (RELATIVE_TIME_WEB_COMPONENT)
                 link rel="stylesheet" href="/styles.css";
             }
-            body class="min-h-screen" hx-boost="true" hx-on-open-main-modal="mainModal.showModal()" {
+            body class="min-h-screen" hx-boost="true" hx-on-open-main-modal="mainModal.showModal()"
+                hx-headers=[auth::csrf::current_token().map(|token| format!(r#"{{"{}":"{}"}}"#, auth::csrf::CSRF_HEADER, token))]
+            {
                 (content)
                 dialog #mainModal class="modal"
                     hx-on-close-modal="this.close()"
@@ -65,15 +71,23 @@ fn header() -> maud::Markup {
                         details class="z-20" {
                             summary { "Auth" }
                             ul {
-                                li { a href="/auth/test" { "Test" } }
-                                li { a href="/auth/yeeter" { "Yeeter" } }
-                                li { a href="/logout" { "Logout" } }
+                                li { a href="/login" { "Login" } }
+                                li { a href="/register" { "Register" } }
+                                li { button hx-post="/logout" { "Logout" } }
                             }
                         }
                     }
                 }
             }
-            div class="flex-none" {
+            div class="flex-none gap-2" {
+                button class="btn" hx-get="/users/notifications" hx-target="#modalInner" hx-swap="outerHTML" {
+                    "Inbox"
+                    span hx-get="/users/notifications/count" hx-trigger="load, every 30s, update-inbox-count from:body" hx-target="this" hx-swap="innerHTML" {}
+                }
+                button class="btn" hx-get="/users/friends" hx-target="#modalInner" hx-swap="outerHTML" {
+                    "Friends"
+                    span #friend-request-count hx-get="/users/friend-requests/count" hx-trigger="load, update-friend-requests from:body" hx-target="this" hx-swap="innerHTML" {}
+                }
                 button class="btn"  hx-get="/users/profile" hx-target="#modalInner" hx-swap="outerHTML" { "Profile" }
             }
         }
@@ -98,6 +112,9 @@ fn base_modal(content: maud::Markup) -> maud::Markup {
 struct AppState {
     db: PgPool,
     message_live: messages::live::MessageRegistry,
+    presence: users::presence::PresenceRegistry,
+    storage: Arc<dyn storage::Storage>,
+    attachment_limits: messages::attachments::AttachmentLimits,
 }
 
 #[tokio::main]
@@ -106,7 +123,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let db = PgPool::connect_lazy(&std::env::var("DATABASE_URL")?)?;
     let message_live = messages::live::create_listener(&db).await?;
-    let state = AppState { db, message_live };
+    let storage = storage::LocalStorage::new(
+        std::env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "attachments".to_owned()),
+    )
+    .await?;
+    let presence = users::presence::spawn_presence_task(db.clone(), message_live.clone());
+    let state = AppState {
+        db,
+        message_live,
+        presence,
+        storage: Arc::new(storage),
+        attachment_limits: messages::attachments::AttachmentLimits::from_env()?,
+    };
 
     let mut listener = PgListener::connect_with(&state.db).await?;
     tokio::spawn(async move {
@@ -125,70 +153,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let router = Router::new()
         .route("/api/health", routing::any(|| async { "alive" }))
-        // FIXME: Create propper auth login handlers
-        .route(
-            "/login",
-            axum::routing::get(|| async {
-                base_tempalte(maud::html!(
-                    (header())
-                    h1 { "Login" }
-                ))
-            }),
-        )
-        .route(
-            "/logout",
-            axum::routing::get(|cookies: axum_extra::extract::CookieJar| async {
-                (
-                    cookies.add(
-                        axum_extra::extract::cookie::Cookie::build("auth_id")
-                            .removal()
-                            .path("/")
-                            .http_only(true)
-                            .secure(true),
-                    ),
-                    Redirect::temporary("/"),
-                )
-            }),
-        )
-        .route(
-            "/auth/yeeter",
-            axum::routing::get(|cookies: axum_extra::extract::CookieJar| async {
-                (
-                    cookies.add(
-                        axum_extra::extract::cookie::Cookie::build((
-                            "auth_id",
-                            "01912d47-1aa9-7c51-8537-3c751e5af344",
-                        ))
-                        .path("/")
-                        .http_only(true)
-                        .secure(true),
-                    ),
-                    Redirect::temporary("/"),
-                )
-            }),
-        )
-        // FIXME: Create propper auth login handlers
-        .route(
-            "/auth/test",
-            axum::routing::get(|cookies: axum_extra::extract::CookieJar| async {
-                (
-                    cookies.add(
-                        axum_extra::extract::cookie::Cookie::build((
-                            "auth_id",
-                            "019132bf-fac6-7ccf-a673-302ec86fefd7",
-                        ))
-                        .path("/")
-                        .http_only(true)
-                        .secure(true),
-                    ),
-                    Redirect::temporary("/"),
-                )
-            }),
-        )
+        .merge(auth::router())
         .nest("/servers", servers::router(state.clone()))
+        .nest("/conversations", conversations::router(state.clone()))
         .nest("/users", users::router())
         .route("/", routing::get(chat::get_chat_page))
         .fallback_service(tower_http::services::ServeDir::new("assets"))
+        .layer(axum::middleware::from_fn(auth::csrf::csrf_protection))
         .layer(
             tower_http::trace::TraceLayer::new_for_http().make_span_with(
                 |request: &axum::http::Request<_>| {
@@ -216,7 +187,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         listener.local_addr()?.port()
     );
 
-    axum::serve(listener, router).await?;
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
     info!("Server exited");
 
     Ok(())
@@ -0,0 +1,348 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{multipart::Field, Path, State},
+    http::header,
+    response::IntoResponse,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_as, query_scalar, PgPool};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    error::{Error, Result},
+    storage::Storage,
+    AppState,
+};
+
+use super::{ChannelId, ChannelIds, MessageId};
+
+/// How many files can be attached to a single message
+pub const MAX_ATTACHMENTS: usize = 10;
+
+#[derive(Deserialize)]
+pub(super) struct AttachmentId {
+    attachment_id: Uuid,
+}
+
+/// Limits on uploaded attachments.
+///
+/// Configured with `ATTACHMENTS_MAX_SIZE`, the size of a single file in bytes, and
+/// `ATTACHMENTS_CONTENT_TYPES`, a comma separated list of the content types that can be attached.
+#[derive(Debug, Clone)]
+pub struct AttachmentLimits {
+    pub max_size: usize,
+    pub content_types: Arc<[String]>,
+}
+
+impl AttachmentLimits {
+    const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+    const DEFAULT_CONTENT_TYPES: &'static str =
+        "image/png,image/jpeg,image/gif,image/webp,text/plain,application/pdf,application/zip";
+
+    pub fn from_env() -> std::result::Result<Self, std::num::ParseIntError> {
+        let max_size = match std::env::var("ATTACHMENTS_MAX_SIZE") {
+            Ok(max_size) => max_size.parse()?,
+            Err(_) => Self::DEFAULT_MAX_SIZE,
+        };
+        let content_types = std::env::var("ATTACHMENTS_CONTENT_TYPES")
+            .unwrap_or_else(|_| Self::DEFAULT_CONTENT_TYPES.to_owned())
+            .split(',')
+            .map(|content_type| content_type.trim().to_lowercase())
+            .filter(|content_type| !content_type.is_empty())
+            .collect();
+        Ok(Self {
+            max_size,
+            content_types,
+        })
+    }
+
+    /// The largest request body a message with attachments can have, with some room for the text
+    pub fn body_limit(&self) -> usize {
+        self.max_size * MAX_ATTACHMENTS + 64 * 1024
+    }
+
+    /// Reads a file from the message form, `None` if no file was selected
+    pub async fn read_upload(&self, mut field: Field<'_>) -> Result<Option<Upload>> {
+        // Browsers send an empty part without a file name when no file was selected
+        let Some(name) = field.file_name().filter(|name| !name.is_empty()) else {
+            return Ok(None);
+        };
+        let name = sanitize_file_name(name);
+        let content_type = field
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_lowercase();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            if data.len() + chunk.len() > self.max_size {
+                return Err(Error::AttachmentTooLarge {
+                    max_size: self.max_size,
+                });
+            }
+            data.extend_from_slice(&chunk);
+        }
+        if !self.content_types.contains(&content_type) {
+            return Err(Error::AttachmentTypeNotAllowed { content_type });
+        }
+
+        Ok(Some(Upload {
+            id: Uuid::now_v7(),
+            name,
+            content_type,
+            data: Bytes::from(data),
+        }))
+    }
+}
+
+/// Keeps only the name of the file, without any directories or characters that could break the
+/// `Content-Disposition` header
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect();
+    if name.trim().is_empty() {
+        "file".to_owned()
+    } else {
+        name
+    }
+}
+
+/// A file uploaded with a message that has not been stored yet
+pub struct Upload {
+    pub id: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub data: Bytes,
+}
+
+pub struct Attachment {
+    pub id: Uuid,
+    pub message: Uuid,
+    pub name: String,
+    pub content_type: String,
+    pub size: i64,
+}
+
+impl Attachment {
+    /// Images that are safe to show inline, svg is excluded since it can contain scripts
+    fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/") && self.content_type != "image/svg+xml"
+    }
+}
+
+/// The attachments of the messages, keyed by message
+pub async fn fetch_attachments(
+    pool: &PgPool,
+    message_ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, Vec<Attachment>>> {
+    let rows = query_as!(
+        Attachment,
+        r#"SELECT id, message, name, content_type, size
+    FROM attachments
+    WHERE message = ANY($1)
+    ORDER BY id"#,
+        message_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut attachments = BTreeMap::<Uuid, Vec<Attachment>>::new();
+    for attachment in rows {
+        attachments
+            .entry(attachment.message)
+            .or_default()
+            .push(attachment);
+    }
+    Ok(attachments)
+}
+
+pub(super) async fn get_attachment(
+    State(state): State<AppState>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Path(AttachmentId { attachment_id }): Path<AttachmentId>,
+) -> Result<impl IntoResponse> {
+    let attachment = query_as!(
+        Attachment,
+        r#"SELECT a.id, a.message, a.name, a.content_type, a.size
+    FROM attachments AS a
+    JOIN messages AS m ON m.id = a.message
+    WHERE a.id = $1 AND a.message = $2 AND m.channel = $3"#,
+        attachment_id,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    let data = state
+        .storage
+        .get(attachment.id)
+        .await
+        .map_err(Error::Storage)?;
+
+    let disposition = if attachment.is_image() {
+        "inline"
+    } else {
+        "attachment"
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, attachment.content_type.clone()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "{disposition}; filename*=UTF-8''{}",
+                    percent_encode(&attachment.name)
+                ),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            // Attachments never change, they can only be deleted
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_owned(),
+            ),
+        ],
+        data,
+    ))
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+pub fn render_attachments(attachments: &[Attachment], ids: &ChannelIds) -> Markup {
+    html!(
+        @if !attachments.is_empty() {
+            .flex.flex-col.gap-1.mt-1 {
+                @for attachment in attachments {
+                    @let url = format!(
+                        "{}/messages/{}/attachments/{}",
+                        ids.url(), attachment.message, attachment.id
+                    );
+                    @if attachment.is_image() {
+                        a href=(url) target="_blank" hx-boost="false" {
+                            img."max-h-[16rem]".rounded src=(url) alt=(attachment.name) loading="lazy";
+                        }
+                    } @else {
+                        a.link href=(url) download=(attachment.name) hx-boost="false" {
+                            (attachment.name) " (" (format_size(attachment.size)) ")"
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+fn format_size(size: i64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// What is being deleted, so the files of its attachments can be removed as well
+pub enum Scope {
+    Server(Uuid),
+    Channel(Uuid),
+    /// The message along with the replies in its thread
+    Message(Uuid),
+}
+
+/// The attachments that will be deleted along with the scope, fetch these before deleting it
+pub async fn fetch_attachment_ids(pool: &PgPool, scope: Scope) -> Result<Vec<Uuid>> {
+    Ok(match scope {
+        Scope::Server(server_id) => {
+            query_scalar!(
+                r#"SELECT a.id
+            FROM attachments AS a
+            JOIN messages AS m ON m.id = a.message
+            JOIN channels AS c ON c.id = m.channel
+            WHERE c.server = $1"#,
+                server_id,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Scope::Channel(channel_id) => {
+            query_scalar!(
+                r#"SELECT a.id
+            FROM attachments AS a
+            JOIN messages AS m ON m.id = a.message
+            WHERE m.channel = $1"#,
+                channel_id,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        Scope::Message(message_id) => {
+            query_scalar!(
+                r#"SELECT id FROM attachments
+            WHERE message IN (SELECT id FROM messages WHERE id = $1 OR thread = $1)"#,
+                message_id,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+    })
+}
+
+/// Removes stored files after their attachments have been deleted, failures are only logged since
+/// the rows are already gone
+pub async fn remove_files(storage: &dyn Storage, attachment_ids: &[Uuid]) {
+    for id in attachment_ids {
+        if let Err(err) = storage.delete(*id).await {
+            error!(?err, attachment_id = %id, "Failed to remove attachment file");
+        }
+    }
+}
+
+/// Stores the uploads and records them as attachments of the message
+pub async fn store_uploads(
+    tx: &mut sqlx::PgConnection,
+    storage: &dyn Storage,
+    message_id: Uuid,
+    uploads: Vec<Upload>,
+) -> Result<()> {
+    for upload in uploads {
+        query!(
+            r#"INSERT INTO attachments (id, message, name, content_type, size) VALUES ($1, $2, $3, $4, $5)"#,
+            upload.id,
+            message_id,
+            upload.name,
+            upload.content_type,
+            upload.data.len() as i64,
+        )
+        .execute(&mut *tx)
+        .await?;
+        storage
+            .put(upload.id, upload.data)
+            .await
+            .map_err(Error::Storage)?;
+    }
+    Ok(())
+}
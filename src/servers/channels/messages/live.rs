@@ -1,27 +1,125 @@
-use std::{collections::BTreeMap, convert::Infallible};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    convert::Infallible,
+    time::Duration,
+};
 
 use axum::response::sse::Event;
-use maud::html;
+use maud::{html, Markup};
 use sqlx::{postgres::PgListener, PgPool};
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 use tracing::{debug_span, error, trace, Instrument};
 use uuid::Uuid;
 
-use super::{render_message, Message};
+use crate::{
+    servers::{channels::unread::render_activity, permissions::Permissions},
+    users::blocks::fetch_blocked_by,
+};
+
+use crate::error::Result;
+
+use super::{
+    fetch_extras, reactions::fetch_reactions, reactions::render_reactions, render_message,
+    render_typing, threads::render_thread_count, BlockedAuthors, ChannelIds, Message,
+};
 
 type UserEvent = std::result::Result<Event, Infallible>;
-type UserRegMsg = (Uuid, oneshot::Sender<mpsc::UnboundedReceiver<UserEvent>>);
+type UserRegMsg = (
+    Subscriber,
+    oneshot::Sender<mpsc::UnboundedReceiver<UserEvent>>,
+);
 type ChannelEventMsg = (Uuid, Kind);
+type UserSenders = BTreeMap<Uuid, (Subscriber, mpsc::UnboundedSender<UserEvent>)>;
+type ActivityRegMsg = (
+    ActivitySubscriber,
+    oneshot::Sender<mpsc::UnboundedReceiver<UserEvent>>,
+);
+type ActivitySenders = BTreeMap<Uuid, (ActivitySubscriber, mpsc::UnboundedSender<UserEvent>)>;
+type UserActivityMsg = (Uuid, Markup);
+/// The user typing and the thread they are typing in
+type TypingMsg = (Uuid, Option<Uuid>);
 
-#[derive(Debug, Clone)]
-pub struct ChannelIds {
-    pub channel_id: Uuid,
-    pub server_id: Uuid,
+/// Which event streams of the channels to end
+#[derive(Debug, Clone, Copy)]
+enum CloseStreams {
+    Session(Uuid),
+    User(Uuid),
+}
+
+/// How long after the last signal a user is still shown as typing, clients signal more often
+/// than this while the user keeps typing
+const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+/// The user and login session an event stream was opened by
+#[derive(Debug, Clone, Copy)]
+pub struct Subscriber {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
+    /// The user's permissions in the channel when the stream was opened
+    pub permissions: Permissions,
+    /// Set for streams of a thread, which only get the messages in it
+    pub thread: Option<Uuid>,
+}
+
+/// A stream of the activity in every channel the user can see, it keeps the unread counts of the
+/// server and channel lists up to date
+#[derive(Debug, Clone, Copy)]
+pub struct ActivitySubscriber {
+    pub user_id: Uuid,
+    pub session_id: Uuid,
 }
 
 #[derive(Debug, Clone)]
 pub struct MessageRegistry {
     pub register: mpsc::Sender<(ChannelIds, UserRegMsg)>,
+    pub register_activity: mpsc::Sender<ActivityRegMsg>,
+    close_session: mpsc::Sender<Uuid>,
+    /// The user and the server whose channels they can no longer see
+    close_user: mpsc::Sender<(Uuid, Uuid)>,
+    user_activity: mpsc::Sender<UserActivityMsg>,
+    typing: mpsc::Sender<(Uuid, TypingMsg)>,
+}
+
+impl MessageRegistry {
+    /// Ends all event streams opened by the session, used when the session is revoked
+    pub async fn close_session_streams(&self, session_id: Uuid) {
+        if let Err(err) = self.close_session.send(session_id).await {
+            error!(?err, %session_id, "Failed to close session streams");
+        }
+    }
+
+    /// Ends the user's event streams of the server's channels, used when they are removed from it
+    pub async fn close_user_streams(&self, user_id: Uuid, server_id: Uuid) {
+        if let Err(err) = self.close_user.send((user_id, server_id)).await {
+            error!(?err, %user_id, %server_id, "Failed to close user streams");
+        }
+    }
+
+    /// Swaps the markup out of band on every page the user has open, for things that happen to
+    /// them outside of a channel
+    pub async fn send_to_user(&self, user_id: Uuid, markup: Markup) {
+        if let Err(err) = self.user_activity.send((user_id, markup)).await {
+            error!(?err, %user_id, "Failed to send activity to user");
+        }
+    }
+
+    /// Shows the user as typing to the others looking at the channel, or the thread in it
+    pub async fn typing(&self, channel_id: Uuid, user_id: Uuid, thread: Option<Uuid>) {
+        if let Err(err) = self.typing.send((channel_id, (user_id, thread))).await {
+            error!(?err, %channel_id, %user_id, "Failed to send typing to channel");
+        }
+    }
+}
+
+struct ChannelTask {
+    server_id: Option<Uuid>,
+    register: mpsc::Sender<UserRegMsg>,
+    events: mpsc::Sender<ChannelEventMsg>,
+    close: mpsc::Sender<CloseStreams>,
+    typing: mpsc::Sender<TypingMsg>,
 }
 
 #[derive(Debug)]
@@ -29,20 +127,40 @@ enum Kind {
     Insert,
     Update,
     Delete,
+    Reaction,
 }
 
 pub async fn create_listener(pool: &PgPool) -> sqlx::Result<MessageRegistry> {
     let mut listener = PgListener::connect_with(pool).await?;
     listener
-        .listen_all(["insert_message", "update_message", "delete_message"])
+        .listen_all([
+            "insert_message",
+            "update_message",
+            "delete_message",
+            "reaction",
+        ])
         .await?;
 
     let (register_tx, mut register_rx) = mpsc::channel::<(ChannelIds, UserRegMsg)>(4);
+    let (close_session_tx, mut close_session_rx) = mpsc::channel::<Uuid>(4);
+    let (close_user_tx, mut close_user_rx) = mpsc::channel::<(Uuid, Uuid)>(4);
+    let (typing_tx, mut typing_rx) = mpsc::channel::<(Uuid, TypingMsg)>(16);
+
+    let (register_activity_tx, register_activity_rx) = mpsc::channel(4);
+    let (activity_tx, activity_rx) = mpsc::channel(64);
+    let (close_activity_tx, close_activity_rx) = mpsc::channel(1);
+    let (user_activity_tx, user_activity_rx) = mpsc::channel(4);
+    spawn_activity_task(
+        register_activity_rx,
+        activity_rx,
+        user_activity_rx,
+        close_activity_rx,
+        pool.clone(),
+    );
 
     let pool = pool.clone();
     tokio::spawn(async move {
-        let mut channel_tasks =
-            BTreeMap::<Uuid, (mpsc::Sender<UserRegMsg>, mpsc::Sender<ChannelEventMsg>)>::new();
+        let mut channel_tasks = BTreeMap::<Uuid, ChannelTask>::new();
         loop {
             tokio::select! {
                 notif = listener.recv() => {
@@ -51,21 +169,56 @@ pub async fn create_listener(pool: &PgPool) -> sqlx::Result<MessageRegistry> {
                             let payload = notif.payload();
                             let channel = notif.channel();
                             let span = debug_span!("Message notification", %channel, %payload);
-                            handle_notification(channel, payload, &channel_tasks).instrument(span).await;
+                            handle_notification(channel, payload, &channel_tasks, &activity_tx).instrument(span).await;
                         }
                         Err(err) => error!(?err, "Error occured in db listener"),
                     }
                 }
                 Some((ids, user_reg_msg)) = register_rx.recv() => {
-                    if let Some((user_tx, _)) = channel_tasks.get(&ids.channel_id) {
-                        user_tx.send(user_reg_msg).await.expect("Registration to work");
+                    if let Some(task) = channel_tasks.get(&ids.channel_id) {
+                        task.register.send(user_reg_msg).await.expect("Registration to work");
                     } else {
-                        let channel_id = ids.channel_id.clone();
+                        let channel_id = ids.channel_id;
                         let (user_tx, user_rx) = mpsc::channel(1);
                         let (event_tx, event_rx) = mpsc::channel(1);
-                        spawn_channel_task(ids, user_rx, event_rx, pool.clone());
+                        let (close_tx, close_rx) = mpsc::channel(1);
+                        let (channel_typing_tx, channel_typing_rx) = mpsc::channel(4);
+                        spawn_channel_task(ids, user_rx, event_rx, close_rx, channel_typing_rx, pool.clone());
                         user_tx.send(user_reg_msg).await.expect("Registration to work");
-                        channel_tasks.insert(channel_id, (user_tx, event_tx));
+                        channel_tasks.insert(channel_id, ChannelTask {
+                            server_id: ids.server_id,
+                            register: user_tx,
+                            events: event_tx,
+                            close: close_tx,
+                            typing: channel_typing_tx,
+                        });
+                    }
+                }
+                Some((channel_id, typing)) = typing_rx.recv() => {
+                    // Without a task nobody is looking at the channel to see it
+                    if let Some(task) = channel_tasks.get(&channel_id) {
+                        if let Err(err) = task.typing.send(typing).await {
+                            error!(?err, "An error occured when sending typing to channel task");
+                        }
+                    }
+                }
+                Some(session_id) = close_session_rx.recv() => {
+                    trace!(%session_id, "Closing streams of session");
+                    for task in channel_tasks.values() {
+                        if let Err(err) = task.close.send(CloseStreams::Session(session_id)).await {
+                            error!(?err, "An error occured when closing session streams in channel task");
+                        }
+                    }
+                    if let Err(err) = close_activity_tx.send(session_id).await {
+                        error!(?err, "An error occured when closing session streams in activity task");
+                    }
+                }
+                Some((user_id, server_id)) = close_user_rx.recv() => {
+                    trace!(%user_id, %server_id, "Closing streams of user in server");
+                    for task in channel_tasks.values().filter(|task| task.server_id == Some(server_id)) {
+                        if let Err(err) = task.close.send(CloseStreams::User(user_id)).await {
+                            error!(?err, "An error occured when closing user streams in channel task");
+                        }
                     }
                 }
             };
@@ -74,13 +227,19 @@ pub async fn create_listener(pool: &PgPool) -> sqlx::Result<MessageRegistry> {
 
     Ok(MessageRegistry {
         register: register_tx,
+        register_activity: register_activity_tx,
+        close_session: close_session_tx,
+        close_user: close_user_tx,
+        user_activity: user_activity_tx,
+        typing: typing_tx,
     })
 }
 
 async fn handle_notification(
     channel: &str,
     payload: &str,
-    channel_tasks: &BTreeMap<Uuid, (mpsc::Sender<UserRegMsg>, mpsc::Sender<ChannelEventMsg>)>,
+    channel_tasks: &BTreeMap<Uuid, ChannelTask>,
+    activity_tx: &mpsc::Sender<Uuid>,
 ) {
     const UUID_LEN: usize = 36;
 
@@ -94,6 +253,7 @@ async fn handle_notification(
         "insert_message" => Kind::Insert,
         "update_message" => Kind::Update,
         "delete_message" => Kind::Delete,
+        "reaction" => Kind::Reaction,
         channel => {
             error!(%channel, "Unexpected channel recived");
             return;
@@ -107,7 +267,20 @@ async fn handle_notification(
         error!(message_id = %&payload[..UUID_LEN], channel_id = %&payload[UUID_LEN..], "An id failed to parse");
         return;
     };
-    let Some((_, event_tx)) = channel_tasks.get(&channel_id) else {
+    // New messages change the unread counts, even in channels nobody is looking at. The counts
+    // are only sent if the activity task keeps up, so it never holds up the messages themselves.
+    if matches!(kind, Kind::Insert) {
+        if let Err(err) = activity_tx.try_send(channel_id) {
+            error!(
+                ?err,
+                "An error occured when sending channel_id to activity task"
+            );
+        }
+    }
+    let Some(ChannelTask {
+        events: event_tx, ..
+    }) = channel_tasks.get(&channel_id)
+    else {
         trace!(%channel_id, "No task exists for the channel");
         return;
     };
@@ -124,86 +297,327 @@ fn spawn_channel_task(
     ids: ChannelIds,
     mut register_rx: mpsc::Receiver<UserRegMsg>,
     mut event_rx: mpsc::Receiver<ChannelEventMsg>,
+    mut close_rx: mpsc::Receiver<CloseStreams>,
+    mut typing_rx: mpsc::Receiver<TypingMsg>,
     pool: PgPool,
 ) {
     tokio::spawn(async move {
-        let mut user_senders = BTreeMap::<Uuid, mpsc::UnboundedSender<UserEvent>>::new();
+        // Keyed by a per-stream id so several tabs of the same user each get their own stream
+        let mut user_senders = UserSenders::new();
+        let mut typing = Typing::default();
         loop {
+            // Only wakes up to expire typing while someone is typing
+            let next_expiry = typing.next_expiry();
             tokio::select! {
                 Some((message_id, kind)) = event_rx.recv() => {
                     let span = debug_span!("Channel Event Task", %message_id, ?kind);
-                    if let Err(err) = handle_message_event(&ids, message_id, kind, &mut user_senders, &pool).instrument(span).await {
+                    if let Err(err) = handle_message_event(&ids, message_id, kind, &mut user_senders, &mut typing, &pool).instrument(span).await {
                         error!(?err, "An error occured while sending events to users")
                     };
                }
-                Some((user_id, sender)) = register_rx.recv() => {
+                Some((user_id, thread)) = typing_rx.recv() => {
+                    if typing.start(user_id, thread, &pool).await {
+                        send_typing(&ids, thread, &typing, &mut user_senders);
+                    }
+                }
+                _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(Instant::now)), if next_expiry.is_some() => {
+                    for thread in typing.expire() {
+                        send_typing(&ids, thread, &typing, &mut user_senders);
+                    }
+                }
+                Some((subscriber, sender)) = register_rx.recv() => {
                     let (tx, rx) = mpsc::unbounded_channel();
-                    user_senders.insert(user_id, tx);
+                    user_senders.insert(Uuid::now_v7(), (subscriber, tx));
                     sender.send(rx).expect("Sending sse channel to work");
                 }
+                Some(close) = close_rx.recv() => {
+                    // Dropping the sender ends the stream for the client
+                    user_senders.retain(|_, (subscriber, _)| match close {
+                        CloseStreams::Session(session_id) => subscriber.session_id != session_id,
+                        CloseStreams::User(user_id) => subscriber.user_id != user_id,
+                    });
+                }
             };
         }
     });
 }
 
 async fn handle_message_event(
-    ChannelIds {
-        channel_id,
-        server_id,
-    }: &ChannelIds,
+    ids: &ChannelIds,
     message_id: Uuid,
     kind: Kind,
-    users: &mut BTreeMap<Uuid, mpsc::UnboundedSender<UserEvent>>,
+    users: &mut UserSenders,
+    typing: &mut Typing,
     pool: &PgPool,
-) -> Result<(), sqlx::Error> {
+) -> Result<()> {
     let mut stale_sender = Vec::new();
     match kind {
         Kind::Insert | Kind::Update => {
             let msg = sqlx::query_as!(
                 Message,
-                r#"SELECT m.id, m.content, m.updated, m.author, u.name as author_name 
+                r#"SELECT m.id, m.content, m.updated, m.author, COALESCE(u.display_name, u.name) as "author_name!",
+              u.avatar_small as author_avatar,
+              m.reply_to, r.author as "reply_author?", r.content as "reply_content?", COALESCE(ru.display_name, ru.name) as "reply_author_name?",
+              m.thread, (SELECT count(*) FROM messages AS t WHERE t.thread = m.id) as "thread_count!"
             FROM messages AS m
             JOIN chat_users AS u ON u.id = m.author
+            LEFT JOIN messages AS r ON r.id = m.reply_to
+            LEFT JOIN chat_users AS ru ON ru.id = r.author
             WHERE m.id = $1
             LIMIT 1"#,
                 message_id,
             )
             .fetch_one(pool)
             .await?;
+            // Sending the message ends the typing, so the indicator does not linger below it
+            if matches!(kind, Kind::Insert) && typing.stop(msg.author, msg.thread) {
+                send_typing(ids, msg.thread, typing, users);
+            }
+            let extras = fetch_extras(pool, &[msg.id])
+                .await?
+                .remove(&msg.id)
+                .unwrap_or_default();
+            // Looked up once for every stream, instead of the blocks of each subscriber
+            let blocked_by = fetch_blocked_by(pool, msg.author).await?;
+            let reply_blocked_by = match msg.reply_author {
+                Some(reply_author) => fetch_blocked_by(pool, reply_author).await?,
+                None => Vec::new(),
+            };
+            // Those looking at the channel see the reply count of the thread go up
+            let thread_count = match (&kind, msg.thread) {
+                (Kind::Insert, Some(parent)) => Some((
+                    parent,
+                    sqlx::query_scalar!(
+                        r#"SELECT count(*) as "count!" FROM messages WHERE thread = $1"#,
+                        parent
+                    )
+                    .fetch_one(pool)
+                    .await?,
+                )),
+                _ => None,
+            };
 
-            for (user_id, tx) in users.iter() {
-                if let Ok(rendered_msg) = render_message(
-                    &msg,
-                    user_id,
-                    channel_id,
-                    server_id,
-                    matches!(kind, Kind::Update),
-                ) {
-                    if tx
-                        .send(Ok(Event::default().event("message").data(rendered_msg.0)))
-                        .is_err()
-                    {
-                        stale_sender.push(user_id.to_owned());
-                    };
-                }
+            for (stream_id, (subscriber, tx)) in users.iter() {
+                let rendered = if subscriber.thread == msg.thread {
+                    render_message(
+                        &msg,
+                        &extras,
+                        &subscriber.user_id,
+                        ids,
+                        subscriber.permissions,
+                        BlockedAuthors {
+                            author: blocked_by.contains(&subscriber.user_id),
+                            reply_author: reply_blocked_by.contains(&subscriber.user_id),
+                        },
+                        matches!(kind, Kind::Update),
+                    )
+                    .ok()
+                } else if let (None, Some((parent, count))) = (subscriber.thread, thread_count) {
+                    Some(render_thread_count(&parent, count, true))
+                } else {
+                    None
+                };
+                let Some(rendered) = rendered else {
+                    continue;
+                };
+                if tx
+                    .send(Ok(Event::default().event("message").data(rendered.0)))
+                    .is_err()
+                {
+                    stale_sender.push(stream_id.to_owned());
+                };
+            }
+        }
+        Kind::Reaction => {
+            let reactions = fetch_reactions(pool, &[message_id])
+                .await?
+                .remove(&message_id)
+                .unwrap_or_default();
+
+            for (stream_id, (subscriber, tx)) in users.iter() {
+                let rendered =
+                    render_reactions(&message_id, &reactions, &subscriber.user_id, ids, true);
+                if tx
+                    .send(Ok(Event::default().event("message").data(rendered.0)))
+                    .is_err()
+                {
+                    stale_sender.push(stream_id.to_owned());
+                };
             }
         }
         Kind::Delete => {
-            for (id, tx) in users.iter() {
+            for (stream_id, (_, tx)) in users.iter() {
                 if tx
                     .send(Ok(Event::default()
                         .event("message")
                         .data(html!(#{"msg-"(message_id)} hx-swap-oob="delete" {}).0)))
                     .is_err()
                 {
-                    stale_sender.push(id.to_owned());
+                    stale_sender.push(stream_id.to_owned());
                 };
             }
         }
     }
     for id in &stale_sender {
-        trace!(user_id = %id, "Removing stale sender");
+        trace!(stream_id = %id, "Removing stale sender");
         users.remove(id);
     }
     Ok(())
 }
+
+/// Who is typing in the channel and its threads. Names are looked up once each time a user starts
+/// typing, so the signals sent while they keep typing do not each hit the database.
+#[derive(Default)]
+struct Typing {
+    /// Until when the user is shown as typing, keyed by the thread and the user
+    until: BTreeMap<(Option<Uuid>, Uuid), Instant>,
+    names: BTreeMap<Uuid, String>,
+}
+
+impl Typing {
+    /// Returns if the user was not typing before, so the indicator changed
+    async fn start(&mut self, user_id: Uuid, thread: Option<Uuid>, pool: &PgPool) -> bool {
+        if let Entry::Vacant(entry) = self.names.entry(user_id) {
+            let name = sqlx::query_scalar!(
+                r#"SELECT COALESCE(display_name, name) as "name!" FROM chat_users WHERE id = $1"#,
+                user_id
+            )
+            .fetch_one(pool)
+            .await;
+            match name {
+                Ok(name) => entry.insert(name),
+                Err(err) => {
+                    error!(?err, %user_id, "Failed to fetch name of typing user");
+                    return false;
+                }
+            };
+        }
+        self.until
+            .insert((thread, user_id), Instant::now() + TYPING_EXPIRY)
+            .is_none()
+    }
+
+    /// Returns if the user was typing, so the indicator changed
+    fn stop(&mut self, user_id: Uuid, thread: Option<Uuid>) -> bool {
+        let stopped = self.until.remove(&(thread, user_id)).is_some();
+        self.forget_names();
+        stopped
+    }
+
+    /// Removes those that stopped sending signals, returns the threads whose indicator changed
+    fn expire(&mut self) -> BTreeSet<Option<Uuid>> {
+        let now = Instant::now();
+        let mut changed = BTreeSet::new();
+        self.until.retain(|(thread, _), until| {
+            let typing = *until > now;
+            if !typing {
+                changed.insert(*thread);
+            }
+            typing
+        });
+        self.forget_names();
+        changed
+    }
+
+    /// When the first of those typing expires
+    fn next_expiry(&self) -> Option<Instant> {
+        self.until.values().min().copied()
+    }
+
+    /// Drops the names of those no longer typing, so a changed name is looked up again
+    fn forget_names(&mut self) {
+        let until = &self.until;
+        self.names
+            .retain(|user_id, _| until.keys().any(|(_, typing)| typing == user_id));
+    }
+
+    /// The names of those typing in the thread, or outside of threads, except for the viewer
+    fn names(&self, thread: Option<Uuid>, viewer: Uuid) -> Vec<&str> {
+        self.until
+            .keys()
+            .filter(|(typing_thread, user_id)| *typing_thread == thread && *user_id != viewer)
+            .filter_map(|(_, user_id)| self.names.get(user_id).map(String::as_str))
+            .collect()
+    }
+}
+
+/// Updates the typing indicator for those looking at the thread, or at the channel outside of
+/// threads
+fn send_typing(ids: &ChannelIds, thread: Option<Uuid>, typing: &Typing, users: &mut UserSenders) {
+    users.retain(|_, (subscriber, tx)| {
+        if subscriber.thread != thread {
+            return true;
+        }
+        let rendered = render_typing(
+            thread.unwrap_or(ids.channel_id),
+            &typing.names(thread, subscriber.user_id),
+            true,
+        );
+        tx.send(Ok(Event::default().event("typing").data(rendered.0)))
+            .is_ok()
+    });
+}
+
+fn spawn_activity_task(
+    mut register_rx: mpsc::Receiver<ActivityRegMsg>,
+    mut event_rx: mpsc::Receiver<Uuid>,
+    mut user_event_rx: mpsc::Receiver<UserActivityMsg>,
+    mut close_session_rx: mpsc::Receiver<Uuid>,
+    pool: PgPool,
+) {
+    tokio::spawn(async move {
+        let mut activity_senders = ActivitySenders::new();
+        loop {
+            tokio::select! {
+                Some(channel_id) = event_rx.recv() => {
+                    let span = debug_span!("Activity Event Task", %channel_id);
+                    if let Err(err) = handle_activity_event(channel_id, &mut activity_senders, &pool).instrument(span).await {
+                        error!(?err, "An error occured while sending activity to users")
+                    };
+                }
+                Some((user_id, markup)) = user_event_rx.recv() => {
+                    // Streams the client already closed are removed along the way
+                    activity_senders.retain(|_, (subscriber, tx)| {
+                        subscriber.user_id != user_id
+                            || tx.send(Ok(Event::default().event("activity").data(&markup.0))).is_ok()
+                    });
+                }
+                Some((subscriber, sender)) = register_rx.recv() => {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    // The client may have disconnected while registering
+                    if sender.send(rx).is_ok() {
+                        activity_senders.insert(Uuid::now_v7(), (subscriber, tx));
+                    }
+                }
+                Some(session_id) = close_session_rx.recv() => {
+                    activity_senders.retain(|_, (subscriber, _)| subscriber.session_id != session_id);
+                }
+            };
+        }
+    });
+}
+
+async fn handle_activity_event(
+    channel_id: Uuid,
+    users: &mut ActivitySenders,
+    pool: &PgPool,
+) -> Result<()> {
+    // Counted once per user, even if they have several tabs open
+    let user_ids = users
+        .values()
+        .map(|(subscriber, _)| subscriber.user_id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let activity = render_activity(pool, channel_id, &user_ids).await?;
+    // Streams the client already closed are removed along the way
+    users.retain(
+        |_, (subscriber, tx)| match activity.get(&subscriber.user_id) {
+            Some(markup) => tx
+                .send(Ok(Event::default().event("activity").data(&markup.0)))
+                .is_ok(),
+            None => true,
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! The markdown subset supported in messages.
+//!
+//! Content is parsed into a small tree which is rendered with maud, so everything a user writes is
+//! escaped and there is no way to pass raw html through. Supported are `**bold**`, `*italics*` or
+//! `_italics_`, `~~strikethrough~~`, `` `inline code` ``, fenced code blocks, `> block quotes`,
+//! `||spoilers||`, bare `http(s)://` links and `@mentions` of the members and roles the message
+//! mentions.
+//!
+//! Fenced code blocks with a language tag are syntax highlighted, the `hl-` classes used for that
+//! are styled in `styles.pcss`.
+
+use std::sync::LazyLock;
+
+use maud::{html, Markup, PreEscaped, Render};
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+use tracing::error;
+
+use super::mentions::mention_name;
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// Quotes nested deeper than this are shown as text, so the recursion stays bounded
+const MAX_QUOTE_DEPTH: usize = 5;
+
+enum Block<'a> {
+    Paragraph(Vec<&'a str>),
+    Quote(Vec<&'a str>),
+    Code {
+        language: Option<&'a str>,
+        code: String,
+    },
+}
+
+enum Inline {
+    Text(String),
+    Code(String),
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+    Spoiler(Vec<Inline>),
+    Link(String),
+    Mention(String),
+}
+
+/// Renders message content as markdown, highlighting the mentions of the names
+pub fn render(content: &str, mentions: &[String]) -> Markup {
+    render_nested(content, mentions, 0)
+}
+
+fn render_nested(content: &str, mentions: &[String], depth: usize) -> Markup {
+    html!(
+        @for block in parse_blocks(content) {
+            (render_block(block, mentions, depth))
+        }
+    )
+}
+
+fn parse_blocks(content: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        if let Some(language) = line.trim_start().strip_prefix("```") {
+            // An unclosed fence runs until the end of the message
+            let code = lines
+                .by_ref()
+                .take_while(|line| line.trim() != "```")
+                .collect::<Vec<_>>()
+                .join("\n");
+            let language = language.trim();
+            blocks.push(Block::Code {
+                language: (!language.is_empty()).then_some(language),
+                code,
+            });
+        } else if let Some(quoted) = line.strip_prefix('>') {
+            let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+            match blocks.last_mut() {
+                Some(Block::Quote(quote)) => quote.push(quoted),
+                _ => blocks.push(Block::Quote(vec![quoted])),
+            }
+        } else if line.trim().is_empty() {
+            // Separates paragraphs
+            blocks.push(Block::Paragraph(Vec::new()));
+        } else {
+            match blocks.last_mut() {
+                Some(Block::Paragraph(paragraph)) => paragraph.push(line),
+                _ => blocks.push(Block::Paragraph(vec![line])),
+            }
+        }
+    }
+    blocks.retain(|block| !matches!(block, Block::Paragraph(lines) if lines.is_empty()));
+    blocks
+}
+
+type Span = fn(Vec<Inline>) -> Inline;
+
+/// Delimiters of the spans that can contain other markup, longest first so `**` is not mistaken
+/// for two `*`
+const DELIMITERS: [(&str, Span); 5] = [
+    ("**", Inline::Strong),
+    ("~~", Inline::Strikethrough),
+    ("||", Inline::Spoiler),
+    ("*", Inline::Emphasis),
+    ("_", Inline::Emphasis),
+];
+
+fn parse_inline(mut rest: &str, mentions: &[String]) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    let mut text = String::new();
+    let flush = |text: &mut String, inlines: &mut Vec<Inline>| {
+        if !text.is_empty() {
+            inlines.push(Inline::Text(std::mem::take(text)));
+        }
+    };
+
+    'outer: while let Some(c) = rest.chars().next() {
+        let after_word = text.chars().last().is_some_and(char::is_alphanumeric);
+
+        if let Some(escaped) = rest
+            .strip_prefix('\\')
+            .and_then(|r| r.chars().next())
+            .filter(char::is_ascii_punctuation)
+        {
+            text.push(escaped);
+            rest = &rest[1 + escaped.len_utf8()..];
+            continue;
+        }
+
+        if let Some((code, after)) = rest
+            .strip_prefix('`')
+            .and_then(|r| r.split_once('`'))
+            .filter(|(code, _)| !code.is_empty())
+        {
+            flush(&mut text, &mut inlines);
+            inlines.push(Inline::Code(code.to_owned()));
+            rest = after;
+            continue;
+        }
+
+        for (delimiter, inline) in DELIMITERS {
+            // `_` is common inside of words, like in snake_case, so it only counts around words
+            if delimiter == "_" && after_word {
+                continue;
+            }
+            let Some((inner, after)) = rest
+                .strip_prefix(delimiter)
+                .and_then(|r| r.split_once(delimiter))
+                .filter(|(inner, _)| {
+                    !inner.is_empty()
+                        && !inner.starts_with(char::is_whitespace)
+                        && !inner.ends_with(char::is_whitespace)
+                })
+            else {
+                continue;
+            };
+            if delimiter == "_" && after.starts_with(char::is_alphanumeric) {
+                continue;
+            }
+            flush(&mut text, &mut inlines);
+            inlines.push(inline(parse_inline(inner, mentions)));
+            rest = after;
+            continue 'outer;
+        }
+
+        if !after_word && (rest.starts_with("https://") || rest.starts_with("http://")) {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let url = rest[..end].trim_end_matches(['.', ',', ':', ';', '!', '?', ')', '\'', '"']);
+            if !url.ends_with("//") {
+                flush(&mut text, &mut inlines);
+                inlines.push(Inline::Link(url.to_owned()));
+                rest = &rest[url.len()..];
+                continue;
+            }
+        }
+
+        if let Some(name) = rest
+            .strip_prefix('@')
+            .filter(|_| !after_word)
+            .and_then(mention_name)
+            .filter(|name| {
+                let name = name.to_lowercase();
+                mentions.iter().any(|m| m.to_lowercase() == name)
+            })
+        {
+            flush(&mut text, &mut inlines);
+            inlines.push(Inline::Mention(name.to_owned()));
+            rest = &rest[1 + name.len()..];
+            continue;
+        }
+
+        text.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    flush(&mut text, &mut inlines);
+    inlines
+}
+
+fn render_block(block: Block, mentions: &[String], depth: usize) -> Markup {
+    match block {
+        Block::Paragraph(lines) => html!(
+            p {
+                @for (i, line) in lines.iter().enumerate() {
+                    @if i > 0 { br; }
+                    @for inline in parse_inline(line, mentions) { (inline) }
+                }
+            }
+        ),
+        Block::Quote(lines) => html!(
+            blockquote.border-l-4.border-current.pl-2.opacity-80 {
+                @if depth < MAX_QUOTE_DEPTH {
+                    (render_nested(&lines.join("\n"), mentions, depth + 1))
+                } @else {
+                    (render_block(Block::Paragraph(lines), mentions, depth))
+                }
+            }
+        ),
+        Block::Code { language, code } => html!(
+            div class="group/code relative" {
+                button class="btn btn-xs absolute right-1 top-1 opacity-0 group-hover/code:opacity-100"
+                    onclick="navigator.clipboard.writeText(this.nextElementSibling.innerText)"
+                    title="Copy code"
+                    { "Copy" }
+                pre.rounded.bg-base-300.text-base-content.p-2.my-1.overflow-x-auto {
+                    code data-language=[language] {
+                        @if let Some(highlighted) = language.and_then(|language| highlight(language, &code)) {
+                            (highlighted)
+                        } @else {
+                            (code)
+                        }
+                    }
+                }
+            }
+        ),
+    }
+}
+
+/// Highlights the code if the language is known, the html is generated by syntect which escapes
+/// the code itself
+fn highlight(language: &str, code: &str) -> Option<Markup> {
+    let syntax = SYNTAXES.find_syntax_by_token(language)?;
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(
+        syntax,
+        &SYNTAXES,
+        ClassStyle::SpacedPrefixed { prefix: "hl-" },
+    );
+    for line in LinesWithEndings::from(code) {
+        if let Err(err) = generator.parse_html_for_line_which_includes_newline(line) {
+            error!(?err, %language, "Failed to highlight code block");
+            return None;
+        }
+    }
+    Some(PreEscaped(generator.finalize()))
+}
+
+impl Render for Inline {
+    fn render(&self) -> Markup {
+        let children = |inlines: &[Inline]| html!(@for inline in inlines { (inline) });
+        match self {
+            Inline::Text(text) => html!((text)),
+            Inline::Code(code) => html!(code.rounded.bg-base-300.text-base-content.px-1 { (code) }),
+            Inline::Strong(inner) => html!(strong { (children(inner)) }),
+            Inline::Emphasis(inner) => html!(em { (children(inner)) }),
+            Inline::Strikethrough(inner) => html!(s { (children(inner)) }),
+            Inline::Spoiler(inner) => html!(
+                span.spoiler "hx-on:click"="this.classList.add('revealed')" { (children(inner)) }
+            ),
+            Inline::Link(url) => html!(
+                a.link href=(url) target="_blank" rel="noopener noreferrer nofollow" { (url) }
+            ),
+            Inline::Mention(name) => html!(
+                span.rounded.bg-accent.px-1.font-bold.text-accent-content { "@" (name) }
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_str(content: &str) -> String {
+        render(content, &[]).into_string()
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            render_str("<script>alert(1)</script>"),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn renders_spans() {
+        assert_eq!(
+            render_str("**bold** *it* _it_ ~~gone~~ ||secret||"),
+            "<p><strong>bold</strong> <em>it</em> <em>it</em> <s>gone</s> \
+            <span class=\"spoiler\" hx-on:click=\"this.classList.add('revealed')\">secret</span></p>"
+        );
+        assert_eq!(
+            render_str("**a *b* c**"),
+            "<p><strong>a <em>b</em> c</strong></p>"
+        );
+    }
+
+    #[test]
+    fn leaves_unmatched_delimiters() {
+        assert_eq!(render_str("snake_case_name"), "<p>snake_case_name</p>");
+        assert_eq!(render_str("2 * 3 * 4"), "<p>2 * 3 * 4</p>");
+        assert_eq!(render_str("**open"), "<p>**open</p>");
+        assert_eq!(render_str(r"\*not\*"), "<p>*not*</p>");
+    }
+
+    #[test]
+    fn inline_code_is_not_parsed() {
+        assert_eq!(
+            render_str("`**a** <b>`"),
+            "<p><code class=\"rounded bg-base-300 text-base-content px-1\">**a** &lt;b&gt;</code></p>"
+        );
+    }
+
+    #[test]
+    fn links_stop_before_punctuation() {
+        assert_eq!(
+            render_str("see https://example.com/a?b=1."),
+            "<p>see <a class=\"link\" href=\"https://example.com/a?b=1\" target=\"_blank\" \
+            rel=\"noopener noreferrer nofollow\">https://example.com/a?b=1</a>.</p>"
+        );
+        assert_eq!(
+            render_str("xhttps://example.com"),
+            "<p>xhttps://example.com</p>"
+        );
+    }
+
+    #[test]
+    fn only_highlights_mentioned_names() {
+        let mentions = ["Alice".to_owned()];
+        let rendered = render("@alice @bob mail@alice", &mentions).into_string();
+        assert_eq!(
+            rendered,
+            "<p><span class=\"rounded bg-accent px-1 font-bold text-accent-content\">@alice</span> \
+            @bob mail@alice</p>"
+        );
+    }
+
+    #[test]
+    fn splits_paragraphs_and_lines() {
+        assert_eq!(render_str("a\nb\n\nc"), "<p>a<br>b</p><p>c</p>");
+    }
+
+    #[test]
+    fn code_blocks_are_not_parsed() {
+        let rendered = render_str("```\n**a**\n<b>\n```\nafter");
+        assert!(rendered.contains("<code>**a**\n&lt;b&gt;</code>"));
+        assert!(rendered.ends_with("<p>after</p>"));
+    }
+
+    #[test]
+    fn unclosed_code_block_runs_to_the_end() {
+        let rendered = render_str("```rust\nlet a = 1;\n\nlet b = 2;");
+        assert!(rendered.contains("data-language=\"rust\""));
+        assert!(rendered.contains("hl-"));
+        assert!(!rendered.contains("<p>"));
+    }
+
+    #[test]
+    fn quotes_nest() {
+        assert_eq!(
+            render_str("> a\n> > b"),
+            "<blockquote class=\"border-l-4 border-current pl-2 opacity-80\"><p>a</p>\
+            <blockquote class=\"border-l-4 border-current pl-2 opacity-80\"><p>b</p>\
+            </blockquote></blockquote>"
+        );
+    }
+
+    #[test]
+    fn quote_nesting_is_bounded() {
+        let content = ">".repeat(100) + " deep";
+        let rendered = render_str(&content);
+        assert_eq!(rendered.matches("<blockquote").count(), MAX_QUOTE_DEPTH + 1);
+        assert!(rendered.contains(
+            &format!("{} deep", ">".repeat(100 - MAX_QUOTE_DEPTH - 1)).replace('>', "&gt;")
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::{auth::Auth, error::Result, servers::permissions::fetch_channel_viewers, AppState};
+
+use super::ChannelIds;
+
+/// Mentions every member that can see the channel
+pub const EVERYONE: &str = "everyone";
+
+/// The name of a mention at the start of the text, which is right after the `@`
+pub fn mention_name(text: &str) -> Option<&str> {
+    let end = text
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        .unwrap_or(text.len());
+    // A mention at the end of a sentence
+    let name = text[..end].trim_end_matches('.');
+    (!name.is_empty()).then_some(name)
+}
+
+/// The names mentioned in the text, as written. Like in the markdown an `@` right after a word, as
+/// in an email address, is not a mention.
+fn mentioned_names(text: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut after_word = false;
+    for (i, c) in text.char_indices() {
+        if c == '@' && !after_word {
+            if let Some(name) = mention_name(&text[i + 1..]) {
+                names.push(name.to_owned());
+            }
+        }
+        after_word = c.is_alphanumeric();
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Who is mentioned in a message
+#[derive(Default)]
+pub struct Mentions {
+    pub everyone: bool,
+    /// The members mentioned by name or through one of their roles
+    pub users: Vec<Uuid>,
+    /// The names of the mentioned members and roles, to highlight in the content
+    pub names: Vec<String>,
+}
+
+impl Mentions {
+    pub fn includes(&self, user_id: &Uuid) -> bool {
+        self.everyone || self.users.contains(user_id)
+    }
+}
+
+/// The mentions of the messages, keyed by message
+pub async fn fetch_mentions(
+    pool: &PgPool,
+    message_ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, Mentions>> {
+    let rows = query!(
+        r#"SELECT mm.message,
+        bool_or(mm.role IS NULL AND mm."user" IS NULL) as "everyone!",
+        array_remove(array_agg(DISTINCT COALESCE(mm."user", mr."user")), NULL) as "users!",
+        array_remove(array_agg(DISTINCT COALESCE(u.name, r.name)), NULL) as "names!"
+    FROM message_mentions AS mm
+    LEFT JOIN chat_users AS u ON u.id = mm."user"
+    LEFT JOIN roles AS r ON r.id = mm.role
+    LEFT JOIN members_have_roles AS mr ON mr.role = mm.role
+    WHERE mm.message = ANY($1)
+    GROUP BY mm.message"#,
+        message_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let mut names = row.names;
+            if row.everyone {
+                names.push(EVERYONE.to_owned());
+            }
+            let mentions = Mentions {
+                everyone: row.everyone,
+                users: row.users,
+                names,
+            };
+            (row.message, mentions)
+        })
+        .collect())
+}
+
+/// Replaces the mentions of the message with the members, roles and `@everyone` named in its
+/// content. Conversations have no roles, only their members can be mentioned.
+pub async fn store_mentions(
+    tx: &mut PgConnection,
+    ids: ChannelIds,
+    message_id: Uuid,
+    content: &str,
+) -> Result<()> {
+    query!(
+        r#"DELETE FROM message_mentions WHERE message = $1"#,
+        message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    let names = mentioned_names(content);
+    if names.is_empty() {
+        return Ok(());
+    }
+    let Some(server_id) = ids.server_id else {
+        query!(
+            r#"INSERT INTO message_mentions (message, role, "user")
+        SELECT $1::uuid, NULL::uuid, u.id
+            FROM conversation_members AS cm
+            JOIN chat_users AS u ON u.id = cm."user"
+            WHERE cm.channel = $2 AND lower(u.name) IN (SELECT lower(name) FROM unnest($3::text[]) AS name)
+        UNION ALL
+        SELECT $1, NULL, NULL WHERE $4::text IN (SELECT lower(name) FROM unnest($3::text[]) AS name)"#,
+            message_id,
+            ids.channel_id,
+            &names,
+            EVERYONE,
+        )
+        .execute(&mut *tx)
+        .await?;
+        return Ok(());
+    };
+    query!(
+        r#"INSERT INTO message_mentions (message, role, "user")
+    SELECT $1::uuid, NULL::uuid, u.id
+        FROM users_member_of_servers AS m
+        JOIN chat_users AS u ON u.id = m."user"
+        WHERE m.server = $2 AND lower(u.name) IN (SELECT lower(name) FROM unnest($3::text[]) AS name)
+    UNION ALL
+    SELECT $1, r.id, NULL FROM roles AS r
+        WHERE r.server = $2 AND lower(r.name) IN (SELECT lower(name) FROM unnest($3::text[]) AS name)
+    UNION ALL
+    SELECT $1, NULL, NULL WHERE $4::text IN (SELECT lower(name) FROM unnest($3::text[]) AS name)"#,
+        message_id,
+        server_id,
+        &names,
+        EVERYONE,
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Adds the message to the inbox of everyone it mentions that can see the channel, except its
+/// author and those who blocked them. Those already notified about it, before it was edited, are
+/// not notified again.
+pub async fn notify_mentioned(
+    pool: &PgPool,
+    ChannelIds {
+        channel_id,
+        server_id,
+    }: ChannelIds,
+    message_id: Uuid,
+    author: Uuid,
+) -> Result<()> {
+    let Some(server_id) = server_id else {
+        // Every member of a conversation can see all of it
+        query!(
+            r#"INSERT INTO notifications ("user", message)
+        SELECT cm."user", $1 FROM conversation_members AS cm
+        WHERE cm.channel = $2 AND cm."user" <> $3
+        AND NOT EXISTS (SELECT * FROM user_blocks WHERE blocker = cm."user" AND blocked = $3)
+        AND EXISTS (
+            SELECT * FROM message_mentions AS mm
+            WHERE mm.message = $1 AND (mm."user" = cm."user" OR (mm.role IS NULL AND mm."user" IS NULL))
+        )
+        ON CONFLICT DO NOTHING"#,
+            message_id,
+            channel_id,
+            author,
+        )
+        .execute(pool)
+        .await?;
+        return Ok(());
+    };
+    let mentioned = query_scalar!(
+        r#"SELECT m."user"
+    FROM users_member_of_servers AS m
+    WHERE m.server = $2 AND m."user" <> $3
+    AND NOT EXISTS (SELECT * FROM user_blocks WHERE blocker = m."user" AND blocked = $3)
+    AND EXISTS (
+        SELECT * FROM message_mentions AS mm
+        WHERE mm.message = $1 AND (
+            mm."user" = m."user"
+            OR (mm.role IS NULL AND mm."user" IS NULL)
+            OR mm.role IN (SELECT role FROM members_have_roles WHERE "user" = m."user" AND server = $2)
+        )
+    )"#,
+        message_id,
+        server_id,
+        author,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let notified = fetch_channel_viewers(pool, server_id, channel_id, &mentioned).await?;
+
+    query!(
+        r#"INSERT INTO notifications ("user", message)
+    SELECT "user", $2 FROM unnest($1::uuid[]) AS "user"
+    ON CONFLICT DO NOTHING"#,
+        &notified,
+        message_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub(super) struct MentionQuery {
+    /// The content of the message form up to the cursor
+    before: String,
+}
+/// Suggests members and roles while a mention is being typed
+pub(super) async fn get_mention_suggestions(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(ChannelIds {
+        channel_id,
+        server_id,
+    }): Path<ChannelIds>,
+    Query(MentionQuery { before }): Query<MentionQuery>,
+) -> Result<impl IntoResponse> {
+    // Only the last word counts, and only if it is a mention
+    let word = before
+        .rsplit(char::is_whitespace)
+        .next()
+        .unwrap_or_default();
+    let Some(prefix) = word
+        .strip_prefix('@')
+        .filter(|prefix| prefix.is_empty() || mention_name(prefix) == Some(prefix))
+    else {
+        return Ok(html!());
+    };
+    let prefix = prefix.to_lowercase();
+
+    // The channel is only used for conversations, which have their own members. Users the user
+    // blocked are left out.
+    let suggestions = query_as!(
+        Suggestion,
+        r#"SELECT name as "name!", is_role as "is_role!" FROM (
+        SELECT u.id, u.name, false as is_role
+        FROM users_member_of_servers AS m
+        JOIN chat_users AS u ON u.id = m."user"
+        WHERE m.server = $1 AND starts_with(lower(u.name), $3)
+        UNION ALL
+        SELECT NULL, r.name, true FROM roles AS r WHERE r.server = $1 AND starts_with(lower(r.name), $3)
+        UNION ALL
+        SELECT u.id, u.name, false
+        FROM conversation_members AS cm
+        JOIN chat_users AS u ON u.id = cm."user"
+        WHERE $1::uuid IS NULL AND cm.channel = $2 AND starts_with(lower(u.name), $3)
+    ) AS suggestions
+    WHERE id IS NULL OR id NOT IN (SELECT blocked FROM user_blocks WHERE blocker = $4)
+    ORDER BY is_role, name
+    LIMIT 8"#,
+        server_id,
+        channel_id,
+        prefix,
+        user_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(html!(
+        @if EVERYONE.starts_with(&prefix) {
+            (render_suggestion(EVERYONE, "Notifies everyone in the channel"))
+        }
+        @for suggestion in suggestions {
+            (render_suggestion(&suggestion.name, if suggestion.is_role { "Role" } else { "Member" }))
+        }
+    ))
+}
+
+struct Suggestion {
+    name: String,
+    is_role: bool,
+}
+
+/// Replaces the mention being typed with the suggestion
+fn render_suggestion(name: &str, description: &str) -> Markup {
+    html!(
+        li {
+            button type="button" data-name=(name)
+                "hx-on:click"="
+                    const textarea = this.closest('form').querySelector('textarea');
+                    const cursor = textarea.selectionStart;
+                    const before = textarea.value.slice(0, cursor).replace(/@\\S*$/, () => '@' + this.dataset.name + ' ');
+                    textarea.value = before + textarea.value.slice(cursor);
+                    textarea.focus();
+                    textarea.setSelectionRange(before.length, before.length);
+                    this.closest('.mention-suggestions').replaceChildren();
+                "
+            {
+                span.font-bold { "@" (name) }
+                span.text-xs.opacity-50 { (description) }
+            }
+        }
+    )
+}
+
+/// Filled with suggestions by the textarea of the message form
+pub fn render_suggestions_list() -> Markup {
+    html!(
+        ul class="mention-suggestions menu absolute bottom-full left-0 z-10 rounded-box bg-base-200 shadow empty:hidden" {}
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mention_name_stops_at_other_characters() {
+        assert_eq!(mention_name("alice, hi"), Some("alice"));
+        assert_eq!(mention_name("j.doe-2_x!"), Some("j.doe-2_x"));
+        assert_eq!(mention_name("bob."), Some("bob"));
+        assert_eq!(mention_name("élodie"), Some("élodie"));
+        assert_eq!(mention_name(" alice"), None);
+        assert_eq!(mention_name("..."), None);
+        assert_eq!(mention_name(""), None);
+    }
+
+    #[test]
+    fn mentioned_names_are_deduplicated() {
+        assert_eq!(
+            mentioned_names("@bob and @Alice, @bob again. @everyone"),
+            ["Alice", "bob", "everyone"]
+        );
+    }
+
+    #[test]
+    fn email_addresses_are_not_mentions() {
+        assert!(mentioned_names("mail me@alice.dev or @ alice").is_empty());
+        assert_eq!(mentioned_names("(@carol)"), ["carol"]);
+    }
+}
@@ -1,46 +1,152 @@
 use axum::{
-    extract::{Path, Query, State},
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
     response::{
         sse::{Event, KeepAlive, Sse},
         IntoResponse,
     },
-    routing, Form, Router,
+    routing, Extension, Form, Router,
 };
 use chrono::NaiveDateTime;
 use maud::{html, Markup};
 use serde::Deserialize;
 use sqlx::{query, query_as, PgPool};
-use std::convert::Infallible;
+use std::{collections::BTreeMap, convert::Infallible};
+use tokio::try_join;
+use tracing::error;
 use uuid::Uuid;
 
+pub mod attachments;
 pub mod live;
+mod markdown;
+mod mentions;
+mod reactions;
+mod revisions;
+mod threads;
 
 use crate::{
     auth::Auth,
     error::{Error, Result},
-    servers::ServerId,
+    servers::permissions::{ChannelPermissions, Permissions},
+    users::{avatars::render_avatar, blocks::fetch_blocked, cards::card_url},
     utils::MyUuidExt,
     AppState,
 };
 
 use super::ChannelId;
 
+use attachments::{fetch_attachments, render_attachments, Attachment, AttachmentLimits};
+use mentions::{fetch_mentions, Mentions};
+use reactions::{fetch_reactions, render_reaction_picker, render_reactions, Reaction};
+
 #[derive(Deserialize)]
 struct MessageId {
     message_id: Uuid,
 }
 
+/// The channel the messages are in, direct conversations are channels without a server
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ChannelIds {
+    pub channel_id: Uuid,
+    pub server_id: Option<Uuid>,
+}
+
+impl ChannelIds {
+    /// The routes of the channel's messages are below this
+    pub fn url(&self) -> String {
+        match self.server_id {
+            Some(server_id) => format!("/servers/{server_id}/channels/{}", self.channel_id),
+            None => format!("/conversations/{}", self.channel_id),
+        }
+    }
+}
+
 struct Message {
     id: Uuid,
     content: String,
     updated: NaiveDateTime,
     author: Uuid,
     author_name: String,
+    author_avatar: Option<Uuid>,
+    /// The message this one replies to, quoted above its content
+    reply_to: Option<Uuid>,
+    reply_author: Option<Uuid>,
+    reply_content: Option<String>,
+    reply_author_name: Option<String>,
+    /// The message whose thread this message is part of
+    thread: Option<Uuid>,
+    thread_count: i64,
+}
+
+/// Whether the viewer blocked the author of the message, or of the message it replies to. Their
+/// content is collapsed.
+#[derive(Clone, Copy, Default)]
+struct BlockedAuthors {
+    author: bool,
+    reply_author: bool,
+}
+
+impl BlockedAuthors {
+    fn of(msg: &Message, blocked: &[Uuid]) -> Self {
+        BlockedAuthors {
+            author: blocked.contains(&msg.author),
+            reply_author: msg
+                .reply_author
+                .is_some_and(|reply_author| blocked.contains(&reply_author)),
+        }
+    }
+}
+
+/// What is shown along with a message that is stored outside of the messages table
+#[derive(Default)]
+struct MessageExtras {
+    attachments: Vec<Attachment>,
+    reactions: Vec<Reaction>,
+    mentions: Mentions,
 }
 
-pub fn router() -> Router<AppState> {
+/// The extras of the messages, with an entry for every message even if it has none
+async fn fetch_extras(
+    pool: &PgPool,
+    message_ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, MessageExtras>> {
+    let (mut attachments, mut reactions, mut mentions) = try_join!(
+        fetch_attachments(pool, message_ids),
+        fetch_reactions(pool, message_ids),
+        fetch_mentions(pool, message_ids),
+    )?;
+    Ok(message_ids
+        .iter()
+        .map(|id| {
+            let extras = MessageExtras {
+                attachments: attachments.remove(id).unwrap_or_default(),
+                reactions: reactions.remove(id).unwrap_or_default(),
+                mentions: mentions.remove(id).unwrap_or_default(),
+            };
+            (*id, extras)
+        })
+        .collect())
+}
+
+/// Length in characters of the longest message content that can be sent
+const MAX_CONTENT_LEN: usize = 4000;
+
+/// Fails with [`Error::MessageTooLong`] if the content is longer than allowed
+fn check_content_len(content: &str) -> Result<()> {
+    if content.chars().count() > MAX_CONTENT_LEN {
+        return Err(Error::MessageTooLong {
+            max_len: MAX_CONTENT_LEN,
+        });
+    }
+    Ok(())
+}
+
+pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
-        .route("/", routing::post(send_message))
+        .route(
+            "/",
+            routing::post(send_message)
+                .layer(DefaultBodyLimit::max(state.attachment_limits.body_limit())),
+        )
         .route(
             "/:message_id",
             routing::get(get_message)
@@ -48,34 +154,71 @@ pub fn router() -> Router<AppState> {
                 .delete(delete_message),
         )
         .route("/:message_id/editable", routing::get(edit_message))
+        .route("/:message_id/reply", routing::get(get_reply_bar))
+        .route(
+            "/:message_id/revisions",
+            routing::get(revisions::get_revisions),
+        )
+        .nest("/:message_id/thread", threads::router())
+        .route(
+            "/:message_id/reactions",
+            routing::post(reactions::toggle_reaction),
+        )
+        .route(
+            "/:message_id/attachments/:attachment_id",
+            routing::get(attachments::get_attachment),
+        )
         .route("/more", routing::get(get_more_messages))
+        .route("/mentions", routing::get(mentions::get_mention_suggestions))
         .route("/events", routing::get(message_event_stream))
+        .route("/typing", routing::post(send_typing))
 }
 
+/// Access to the channel is checked by the `can_user_view_channel` middleware before subscribing
 async fn message_event_stream(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
-    Path(ChannelId { channel_id }): Path<ChannelId>,
-    Path(ServerId { server_id }): Path<ServerId>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(ids): Path<ChannelIds>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = std::result::Result<Event, Infallible>>>> {
+    subscribe(
+        &state,
+        ids,
+        live::Subscriber {
+            user_id,
+            session_id,
+            permissions,
+            thread: None,
+        },
+    )
+    .await
+}
+
+/// Registers the subscriber with the task of the channel and streams the events it sends
+async fn subscribe(
+    state: &AppState,
+    ids: ChannelIds,
+    subscriber: live::Subscriber,
 ) -> Result<Sse<impl tokio_stream::Stream<Item = std::result::Result<Event, Infallible>>>> {
     let (tx, rx) = tokio::sync::oneshot::channel();
 
     state
         .message_live
         .register
-        .send((
-            live::ChannelIds {
-                channel_id,
-                server_id,
-            },
-            (user_id, tx),
-        ))
+        .send((ids, (subscriber, tx)))
         .await
         .map_err(|_| Error::SSEChannelRegistrationChannelFailed)?;
 
-    let stream = tokio_stream::wrappers::UnboundedReceiverStream::new(
-        rx.await
-            .map_err(|_| Error::SSERegistationDidNotRecvChannel)?,
+    // The user counts as online for as long as the stream is open
+    let stream = state.presence.track(
+        subscriber.user_id,
+        tokio_stream::wrappers::UnboundedReceiverStream::new(
+            rx.await
+                .map_err(|_| Error::SSERegistationDidNotRecvChannel)?,
+        ),
     );
 
     Ok(Sse::new(stream).keep_alive(
@@ -86,220 +229,718 @@ async fn message_event_stream(
 }
 
 #[derive(Deserialize)]
-struct SentMessage {
-    content: String,
+struct Typing {
+    thread: Option<Uuid>,
 }
-async fn send_message(
+/// Sent by the message form while the user types, throttled on the client. Nothing is stored, the
+/// channel's task shows it to the others until it expires.
+async fn send_typing(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
     Path(ChannelId { channel_id }): Path<ChannelId>,
-    Form(sent_msg): Form<SentMessage>,
+    Form(Typing { thread }): Form<Typing>,
 ) -> Result<impl IntoResponse> {
-    // FIXME: Check if user has access to channel
+    permissions.require(Permissions::SEND_MESSAGES)?;
+    state.message_live.typing(channel_id, user_id, thread).await;
+
+    Ok(html!())
+}
+
+/// The message form is sent as multipart, with a `content` field and any number of `attachments`.
+///
+/// A `reply_to` field quotes another message, a `thread` field posts the message in the thread of
+/// a message instead of the channel itself.
+async fn send_message(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(ids @ ChannelIds { channel_id, .. }): Path<ChannelIds>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::SEND_MESSAGES)?;
+
+    let mut content = String::new();
+    let mut reply_to = None;
+    let mut thread = None;
+    let mut uploads = Vec::new();
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("content") => content = field.text().await?,
+            Some("reply_to") => reply_to = parse_optional_id("reply_to", &field.text().await?)?,
+            Some("thread") => thread = parse_optional_id("thread", &field.text().await?)?,
+            Some("attachments") => {
+                if let Some(upload) = state.attachment_limits.read_upload(field).await? {
+                    uploads.push(upload);
+                }
+            }
+            _ => {}
+        }
+        if uploads.len() > attachments::MAX_ATTACHMENTS {
+            return Err(Error::TooManyAttachments);
+        }
+    }
+    check_content_len(&content)?;
+
+    // Threads can not be nested and replies have to be in the same thread as their parent
+    if let Some(thread) = thread {
+        query!(
+            r#"SELECT id FROM messages WHERE id = $1 AND channel = $2 AND thread IS NULL"#,
+            thread,
+            channel_id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+    }
+    if let Some(reply_to) = reply_to {
+        query!(
+            r#"SELECT id FROM messages WHERE id = $1 AND channel = $2 AND thread IS NOT DISTINCT FROM $3"#,
+            reply_to,
+            channel_id,
+            thread,
+        )
+        .fetch_one(&state.db)
+        .await?;
+    }
+
     let new_id = Uuid::now_v7();
     let timestamp = new_id.get_datetime().expect("v7 uuid to return datetime");
-    let rows_affected = query!(
-        r#"INSERT INTO messages (id, updated, content, channel, author) VALUES ($1, $2, $3, $4, $5)"#,
-        new_id,
-        timestamp.naive_utc(),
-        sent_msg.content,
-        channel_id,
-        user_id
-    )
-    .execute(&state.db)
-    .await?;
+    let upload_ids = uploads.iter().map(|upload| upload.id).collect::<Vec<_>>();
+    // The message is only announced to listeners on commit, so it is sent out with its attachments
+    let result = async {
+        let mut tx = state.db.begin().await?;
+        let rows_affected = query!(
+            r#"INSERT INTO messages (id, updated, content, channel, author, reply_to, thread)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+            new_id,
+            timestamp.naive_utc(),
+            content,
+            channel_id,
+            user_id,
+            reply_to,
+            thread,
+        )
+        .execute(&mut *tx)
+        .await?;
 
-    if rows_affected.rows_affected() != 1 {
-        return Err(Error::DatabaseActionFailed);
+        if rows_affected.rows_affected() != 1 {
+            return Err(Error::DatabaseActionFailed);
+        }
+
+        mentions::store_mentions(&mut tx, ids, new_id, &content).await?;
+        attachments::store_uploads(&mut tx, state.storage.as_ref(), new_id, uploads).await?;
+        tx.commit().await?;
+        Ok(())
     }
+    .await;
+    if result.is_err() {
+        attachments::remove_files(state.storage.as_ref(), &upload_ids).await;
+    }
+    result?;
+    notify_mentioned(&state, ids, new_id, user_id).await;
 
     Ok(html!())
 }
 
-async fn get_message(
+/// The message is already sent when this runs, so failing to notify is only logged
+async fn notify_mentioned(state: &AppState, ids: ChannelIds, message_id: Uuid, author: Uuid) {
+    if let Err(err) = mentions::notify_mentioned(&state.db, ids, message_id, author).await {
+        error!(?err, %message_id, "Failed to notify the mentioned members");
+    }
+}
+
+/// Empty form fields are treated as not set
+fn parse_optional_id(field: &'static str, value: &str) -> Result<Option<Uuid>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    Uuid::try_parse(value)
+        .map(Some)
+        .map_err(|_| Error::InvalidFormField { field })
+}
+
+/// Shown above the message form while replying, it carries the `reply_to` field
+async fn get_reply_bar(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
     Path(MessageId { message_id }): Path<MessageId>,
     Path(ChannelId { channel_id }): Path<ChannelId>,
-    Path(ServerId { server_id }): Path<ServerId>,
+) -> Result<impl IntoResponse> {
+    let msg = query!(
+        r#"SELECT COALESCE(u.display_name, u.name) as "author_name!"
+      FROM messages AS m
+      JOIN chat_users AS u ON u.id = m.author
+      WHERE m.id = $1 AND m.channel = $2 AND m.thread IS NULL"#,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(html!(
+        #reply-bar.flex.basis-full.items-center.gap-2.text-sm {
+            input type="hidden" name="reply_to" value=(message_id);
+            span.opacity-70 { "Replying to " span.font-bold { (msg.author_name) } }
+            button.btn.btn-ghost.btn-xs type="button" "hx-on:click"="this.parentElement.replaceChildren()" { "✕" }
+        }
+    ))
+}
+
+async fn get_message(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Path(ids): Path<ChannelIds>,
 ) -> Result<impl IntoResponse> {
     // FIXME: Allow for getting any message user has access to, not just those they authored
     let msg = query_as!(
         Message,
-        r#"SELECT m.id, m.content, m.updated, m.author, u.name as author_name 
+        r#"SELECT m.id, m.content, m.updated, m.author, COALESCE(u.display_name, u.name) as "author_name!",
+        u.avatar_small as author_avatar,
+        m.reply_to, r.author as "reply_author?", r.content as "reply_content?", COALESCE(ru.display_name, ru.name) as "reply_author_name?",
+        m.thread, (SELECT count(*) FROM messages AS t WHERE t.thread = m.id) as "thread_count!"
       FROM messages AS m
       JOIN chat_users AS u ON u.id = m.author
+      LEFT JOIN messages AS r ON r.id = m.reply_to
+      LEFT JOIN chat_users AS ru ON ru.id = r.author
       WHERE m.id = $1 AND m.author = $2"#,
         message_id,
         user_id
     )
     .fetch_one(&state.db)
     .await?;
-    return Ok(render_message(
+    let extras = fetch_extras(&state.db, &[msg.id]).await?;
+    let blocked = fetch_blocked(&state.db, user_id).await?;
+    render_message(
         &msg,
+        &extras[&msg.id],
         &user_id,
-        &channel_id,
-        &server_id,
+        &ids,
+        permissions,
+        BlockedAuthors::of(&msg, &blocked),
         false,
-    )?);
+    )
 }
 
 #[derive(Deserialize)]
 struct UpdatedMessage {
     content: String,
 }
+/// Authors can edit their messages for as long as they can send messages in the channel
 async fn edit_message(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
     Path(MessageId { message_id }): Path<MessageId>,
-    Path(ChannelId { channel_id }): Path<ChannelId>,
-    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ids @ ChannelIds { channel_id, .. }): Path<ChannelIds>,
     updated_msg: Option<Form<UpdatedMessage>>,
 ) -> Result<impl IntoResponse> {
-    // FIXME: Check if allowed to edit
+    permissions.require(Permissions::SEND_MESSAGES)?;
     let Some(Form(updated_msg)) = updated_msg else {
         let msg = query_as!(
             Message,
-            r#"SELECT m.id, m.content, m.updated, m.author, u.name as author_name 
+            r#"SELECT m.id, m.content, m.updated, m.author, COALESCE(u.display_name, u.name) as "author_name!",
+            u.avatar_small as author_avatar,
+            m.reply_to, r.author as "reply_author?", r.content as "reply_content?", COALESCE(ru.display_name, ru.name) as "reply_author_name?",
+            m.thread, (SELECT count(*) FROM messages AS t WHERE t.thread = m.id) as "thread_count!"
           FROM messages AS m
           JOIN chat_users AS u ON u.id = m.author
-          WHERE m.id = $1 AND m.author = $2"#,
+          LEFT JOIN messages AS r ON r.id = m.reply_to
+          LEFT JOIN chat_users AS ru ON ru.id = r.author
+          WHERE m.id = $1 AND m.author = $2 AND m.channel = $3"#,
             message_id,
-            user_id
+            user_id,
+            channel_id,
         )
         .fetch_one(&state.db)
         .await?;
-        return Ok(render_message_for_edit(&msg, &server_id, &channel_id)?);
+        return render_message_for_edit(&msg, &ids);
     };
+    check_content_len(&updated_msg.content)?;
 
+    let mut tx = state.db.begin().await?;
+    // The replaced version is kept in the edit history, saving without changes does not count
+    query!(
+        r#"INSERT INTO message_revisions (id, message, content, written)
+        SELECT $1, id, content, updated FROM messages
+        WHERE id = $2 AND author = $3 AND channel = $4 AND content <> $5"#,
+        Uuid::now_v7(),
+        message_id,
+        user_id,
+        channel_id,
+        updated_msg.content,
+    )
+    .execute(&mut *tx)
+    .await?;
     let rows_affected = query!(
-        r#"UPDATE messages SET updated = NOW(), content = $1 WHERE id = $2 AND author = $3"#,
+        r#"UPDATE messages
+        SET updated = CASE WHEN content = $1 THEN updated ELSE NOW() END, content = $1
+        WHERE id = $2 AND author = $3 AND channel = $4"#,
         updated_msg.content,
         message_id,
-        user_id
+        user_id,
+        channel_id,
     )
-    .execute(&state.db)
+    .execute(&mut *tx)
     .await?;
 
     if rows_affected.rows_affected() != 1 {
         return Err(Error::DatabaseActionFailed);
     }
+    mentions::store_mentions(&mut tx, ids, message_id, &updated_msg.content).await?;
+    tx.commit().await?;
+    notify_mentioned(&state, ids, message_id, user_id).await;
 
     Ok(html!())
 }
 
 async fn delete_message(
     State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
     Path(MessageId { message_id }): Path<MessageId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
 ) -> Result<impl IntoResponse> {
-    // FIXME: Check if allowed to delete
-    let rows_affected = query!(r#"DELETE FROM messages WHERE id = $1"#, message_id)
-        .execute(&state.db)
-        .await?;
+    let msg = query!(
+        r#"SELECT author FROM messages WHERE id = $1 AND channel = $2"#,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    if msg.author != user_id {
+        permissions.require(Permissions::MANAGE_MESSAGES)?;
+    }
+
+    let attachment_ids =
+        attachments::fetch_attachment_ids(&state.db, attachments::Scope::Message(message_id))
+            .await?;
+    let rows_affected = query!(
+        r#"DELETE FROM messages WHERE id = $1 AND channel = $2"#,
+        message_id,
+        channel_id,
+    )
+    .execute(&state.db)
+    .await?;
 
     if rows_affected.rows_affected() != 1 {
         return Err(Error::DatabaseActionFailed);
     }
+    attachments::remove_files(state.storage.as_ref(), &attachment_ids).await;
 
     Ok(html!())
 }
 
-#[derive(Deserialize)]
-struct MoreOpts {
-    before: Uuid,
+/// Which messages of the channel or thread to load
+#[derive(Clone, Copy)]
+enum Page {
+    Latest,
+    Before(Uuid),
+    After(Uuid),
+    /// The message with the messages sent right before and after it
+    Around(Uuid),
 }
-async fn get_more_messages(
-    State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
-    Query(MoreOpts { before }): Query<MoreOpts>,
-    Path(ChannelId { channel_id }): Path<ChannelId>,
-    Path(ServerId { server_id }): Path<ServerId>,
-) -> Result<impl IntoResponse> {
-    let messages = query_as!(
+
+const PAGE_SIZE: i64 = 25;
+
+/// A page of messages, newest first
+struct MessagePage {
+    messages: Vec<Message>,
+    has_older: bool,
+    has_newer: bool,
+    /// The newest message the user had read, the messages after it are marked as new
+    last_read: Option<Uuid>,
+    /// The users the user blocked, their messages are collapsed
+    blocked: Vec<Uuid>,
+}
+
+/// Loads the messages of the channel, or those in the thread of a message, closest to the page
+async fn fetch_page(
+    pool: &PgPool,
+    channel_id: Uuid,
+    thread: Option<Uuid>,
+    page: Page,
+) -> Result<MessagePage> {
+    Ok(match page {
+        Page::Latest => {
+            let messages = fetch_messages(pool, channel_id, thread, None, None, PAGE_SIZE).await?;
+            MessagePage {
+                has_older: messages.len() as i64 >= PAGE_SIZE,
+                has_newer: false,
+                messages,
+                last_read: None,
+                blocked: Vec::new(),
+            }
+        }
+        Page::Before(before) => {
+            let messages =
+                fetch_messages(pool, channel_id, thread, Some(before), None, PAGE_SIZE).await?;
+            MessagePage {
+                has_older: messages.len() as i64 >= PAGE_SIZE,
+                // Newer messages are already shown
+                has_newer: false,
+                messages,
+                last_read: None,
+                blocked: Vec::new(),
+            }
+        }
+        Page::After(after) => {
+            let messages =
+                fetch_messages(pool, channel_id, thread, None, Some(after), PAGE_SIZE).await?;
+            MessagePage {
+                has_older: false,
+                has_newer: messages.len() as i64 >= PAGE_SIZE,
+                messages,
+                last_read: None,
+                blocked: Vec::new(),
+            }
+        }
+        Page::Around(around) => {
+            let (newer_limit, older_limit) = around_limits(PAGE_SIZE);
+            let (newer, older) = try_join!(
+                fetch_messages(pool, channel_id, thread, None, Some(around), newer_limit),
+                fetch_messages(
+                    pool,
+                    channel_id,
+                    thread,
+                    Some(including(around)),
+                    None,
+                    older_limit
+                ),
+            )?;
+            MessagePage {
+                has_older: older.len() as i64 >= older_limit,
+                has_newer: newer.len() as i64 >= newer_limit,
+                messages: newer.into_iter().chain(older).collect(),
+                last_read: None,
+                blocked: Vec::new(),
+            }
+        }
+    })
+}
+
+/// How many of the messages of a page around a message are newer and older than it, the message
+/// itself counts as older
+fn around_limits(limit: i64) -> (i64, i64) {
+    let newer = limit / 2;
+    (newer, limit - newer)
+}
+
+/// The bounds of [`fetch_messages`] are exclusive, this is the bound before which the message
+/// itself is still included
+fn including(id: Uuid) -> Uuid {
+    Uuid::from_u128(id.as_u128().saturating_add(1))
+}
+
+/// The messages between the bounds, newest first. When only `after` is given the messages right
+/// after it are loaded, otherwise those right before `before` or the latest.
+async fn fetch_messages(
+    pool: &PgPool,
+    channel_id: Uuid,
+    thread: Option<Uuid>,
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    limit: i64,
+) -> Result<Vec<Message>> {
+    let mut messages = query_as!(
         Message,
-        r#"SELECT m.id, m.content, m.updated, m.author, u.name as author_name 
+        r#"SELECT m.id, m.content, m.updated, m.author, COALESCE(u.display_name, u.name) as "author_name!",
+        u.avatar_small as author_avatar,
+        m.reply_to, r.author as "reply_author?", r.content as "reply_content?", COALESCE(ru.display_name, ru.name) as "reply_author_name?",
+        m.thread, (SELECT count(*) FROM messages AS t WHERE t.thread = m.id) as "thread_count!"
       FROM messages AS m
       JOIN chat_users AS u ON u.id = m.author
-      WHERE m.channel = $1 AND m.id < $2
-      ORDER BY m.id DESC
-      LIMIT 25"#,
+      LEFT JOIN messages AS r ON r.id = m.reply_to
+      LEFT JOIN chat_users AS ru ON ru.id = r.author
+      WHERE m.channel = $1 AND m.thread IS NOT DISTINCT FROM $2
+        AND ($3::uuid IS NULL OR m.id < $3)
+        AND ($4::uuid IS NULL OR m.id > $4)
+      ORDER BY
+        CASE WHEN $3::uuid IS NULL AND $4::uuid IS NOT NULL THEN m.id END ASC,
+        m.id DESC
+      LIMIT $5"#,
         channel_id,
-        before
+        thread,
+        before,
+        after,
+        limit,
     )
-    .fetch_all(&state.db)
+    .fetch_all(pool)
     .await?;
+    if before.is_none() && after.is_some() {
+        messages.reverse();
+    }
+    Ok(messages)
+}
 
-    render_messages(
-        &messages,
-        server_id,
-        channel_id,
-        user_id,
-        messages.len() >= 25,
+#[derive(Deserialize)]
+struct MoreOpts {
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    around: Option<Uuid>,
+    /// Pages through the thread of this message instead of the channel
+    thread: Option<Uuid>,
+}
+/// Loads older messages with `before`, newer ones with `after` or those surrounding a message with
+/// `around`. Once the newest page is reached the live stream of the channel is attached.
+async fn get_more_messages(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Query(MoreOpts {
+        before,
+        after,
+        around,
+        thread,
+    }): Query<MoreOpts>,
+    Path(ids @ ChannelIds { channel_id, .. }): Path<ChannelIds>,
+) -> Result<impl IntoResponse> {
+    let page = match (before, after, around) {
+        (Some(before), None, None) => Page::Before(before),
+        (None, Some(after), None) => Page::After(after),
+        (None, None, Some(around)) => Page::Around(around),
+        _ => return Err(Error::InvalidFormField { field: "before" }),
+    };
+    let page = MessagePage {
+        blocked: fetch_blocked(&state.db, user_id).await?,
+        ..fetch_page(&state.db, channel_id, thread, page).await?
+    };
+    let extras = fetch_extras(
+        &state.db,
+        &page.messages.iter().map(|msg| msg.id).collect::<Vec<_>>(),
     )
+    .await?;
+    let reached_newest = before.is_none() && !page.has_newer;
+
+    Ok(html!(
+        (render_messages(&page, &extras, ids, user_id, permissions)?)
+        @if reached_newest && thread.is_none() {
+            (render_live_connector(ids, true, true))
+        }
+    ))
 }
 
+/// The messages of the channel, centered on the message of `around` if it is set
 pub async fn fetch_render_message_list(
     pool: &PgPool,
-    server_id: Uuid,
-    channel_id: Uuid,
+    ids @ ChannelIds { channel_id, .. }: ChannelIds,
     user_id: Uuid,
+    permissions: Permissions,
+    around: Option<Uuid>,
+    last_read: Option<Uuid>,
 ) -> Result<Markup> {
-    let messages = query_as!(
-        Message,
-        r#"SELECT m.id, m.content, m.updated, m.author, u.name as author_name 
-      FROM messages AS m
-      JOIN chat_users AS u ON u.id = m.author
-      WHERE m.channel = $1
-      ORDER BY m.id DESC
-      LIMIT 25"#,
-        channel_id,
+    let page = match around {
+        Some(around) => {
+            // Messages in a thread are shown through their parent. Links to a message that was
+            // deleted since show the latest messages instead.
+            let msg = query!(
+                r#"SELECT thread FROM messages WHERE id = $1 AND channel = $2"#,
+                around,
+                channel_id,
+            )
+            .fetch_optional(pool)
+            .await?;
+            match msg {
+                Some(msg) => Page::Around(msg.thread.unwrap_or(around)),
+                None => Page::Latest,
+            }
+        }
+        None => Page::Latest,
+    };
+    let page = MessagePage {
+        last_read,
+        blocked: fetch_blocked(pool, user_id).await?,
+        ..fetch_page(pool, channel_id, None, page).await?
+    };
+    let extras = fetch_extras(
+        pool,
+        &page.messages.iter().map(|msg| msg.id).collect::<Vec<_>>(),
     )
-    .fetch_all(pool)
     .await?;
 
     Ok(html!(
-        ol #messages class="flex flex-col-reverse overflow-y-auto"
-            hx-ext="sse"
-            sse-connect={"/servers/"(server_id)"/channels/"(channel_id)"/messages/events"}
-            sse-swap="message"
-            hx-swap="afterbegin"
-        {
-            (render_messages(&messages,server_id, channel_id, user_id, messages.len() >= 25)?)
+        ol #messages class="flex flex-col-reverse overflow-y-auto" hx-ext="sse" {
+            // Only receives new messages once the newest ones are shown
+            (render_live_connector(ids, !page.has_newer, false))
+            (render_messages(&page, &extras, ids, user_id, permissions)?)
         }
     ))
 }
 
+/// Prepends the messages of the channel's event stream to the list, it only connects to the
+/// stream when `connected` is set
+fn render_live_connector(ids: ChannelIds, connected: bool, swap_oob: bool) -> Markup {
+    html!(
+        @if connected {
+            li.hidden #messages-live
+                hx-swap-oob=[swap_oob.then_some("true")]
+                sse-connect={(ids.url())"/messages/events"}
+                sse-swap="message"
+                hx-target="#messages"
+                hx-swap="afterbegin"
+            {
+                span sse-swap="typing" hx-swap="none" {}
+                // The user is looking at the channel, so new messages are read
+                span hx-post={(ids.url())"/read"}
+                    hx-trigger="sse:message delay:1s"
+                    hx-swap="none"
+                    {}
+            }
+        } @else {
+            li.hidden #messages-live {}
+        }
+    )
+}
+
+/// The form to send messages in the channel, or in the thread of a message
+pub fn render_message_form(
+    ids: ChannelIds,
+    thread: Option<Uuid>,
+    limits: &AttachmentLimits,
+) -> Markup {
+    html!(
+        form.relative.flex.flex-wrap.items-end.gap-2
+            id=(if thread.is_some() { "thread-form" } else { "message-form" })
+            hx-post={(ids.url())"/messages"}
+            hx-swap="none"
+            hx-encoding="multipart/form-data"
+            "hx-on::after-request"="if (event.detail.successful && event.detail.elt === this) { this.reset(); this.querySelector('#reply-bar')?.replaceChildren() }"
+        {
+            @if let Some(thread) = thread {
+                input type="hidden" name="thread" value=(thread);
+            } @else {
+                #reply-bar.basis-full {}
+            }
+            label.btn.btn-ghost.btn-square title="Attach files" {
+                "+"
+                input.hidden type="file" name="attachments" multiple
+                    accept=(limits.content_types.join(","));
+            }
+            (mentions::render_suggestions_list())
+            // Enter sends the message, Shift+Enter adds a new line for code blocks and quotes
+            textarea.textarea.textarea-bordered.grow name="content" rows="1" placeholder="Type here..."
+                maxlength=(MAX_CONTENT_LEN)
+                "hx-on:keydown"="if (event.key === 'Enter' && !event.shiftKey) { event.preventDefault(); this.form.requestSubmit() }"
+                hx-get={(ids.url())"/messages/mentions"}
+                hx-trigger="input changed delay:200ms"
+                hx-target="previous .mention-suggestions"
+                hx-swap="innerHTML"
+                hx-vals="js:{before: this.value.slice(0, this.selectionStart)}"
+                {}
+            button.btn.btn-primary { "Send" }
+            // Only the thread is sent along, as urlencoded instead of the multipart of the form
+            span.hidden
+                hx-post={(ids.url())"/messages/typing"}
+                hx-trigger="input from:closest form throttle:3s"
+                hx-params="thread"
+                hx-encoding="application/x-www-form-urlencoded"
+                hx-swap="none"
+                {}
+            (render_typing(thread.unwrap_or(ids.channel_id), &[], false))
+        }
+    )
+}
+
+/// Who else is typing below the message form, `scope` is the thread or else the channel the form
+/// sends to
+fn render_typing(scope: Uuid, names: &[&str], swap_oob: bool) -> Markup {
+    html!(
+        .basis-full."h-[1rem]".text-xs.opacity-70
+            #{"typing-"(scope)}
+            hx-swap-oob=[swap_oob.then_some("true")]
+        {
+            @match names {
+                [] => {},
+                [name] => { (name) " is typing…" },
+                [first, second] => { (first) " and " (second) " are typing…" },
+                _ => "Several people are typing…",
+            }
+        }
+    )
+}
+
 fn render_messages(
-    messages: &[Message],
-    server_id: Uuid,
-    channel_id: Uuid,
+    page: &MessagePage,
+    extras: &BTreeMap<Uuid, MessageExtras>,
+    ids: ChannelIds,
     user_id: Uuid,
-    should_load_more: bool,
+    permissions: Permissions,
 ) -> Result<Markup> {
+    let messages = &page.messages;
+    let more_url = |msg: &Message, direction: &str| {
+        html!(
+            (ids.url())"/messages/more?"(direction)"="(msg.id)
+            @if let Some(thread) = msg.thread { "&thread="(thread) }
+        )
+    };
     Ok(html!(
-        @for msg in messages {
-            (render_message(msg, &user_id, &channel_id, &server_id, false)?)
+        // The list is reversed, so newer messages are loaded at the bottom
+        @if let (Some(first_msg), true) = (messages.first(), page.has_newer) {
+            div class="loading loading-dots mx-auto mb-auto pb-8"
+                hx-trigger="intersect once"
+                hx-swap="outerHTML"
+                hx-get=(more_url(first_msg, "after"))
+                {}
         }
-        @if let Some(last_msg) = messages.last() {
-            @if should_load_more {
-                div class="loading loading-dots mx-auto mt-auto pt-8"
-                    hx-trigger="intersect once"
-                    hx-swap="outerHTML"
-                    hx-get={"/servers/"(server_id)"/channels/"(channel_id)"/messages/more?before="(last_msg.id)}
-                    {}
+        @for (i, msg) in messages.iter().enumerate() {
+            @let blocked = BlockedAuthors::of(msg, &page.blocked);
+            (render_message(msg, &extras[&msg.id], &user_id, &ids, permissions, blocked, false)?)
+            @if let Some(last_read) = page.last_read.filter(|last_read| &msg.id > last_read) {
+                @let older_is_read = match messages.get(i + 1) {
+                    Some(older) => older.id <= last_read,
+                    None => !page.has_older,
+                };
+                // Placed after the oldest unread message, which is above it once reversed
+                @if older_is_read {
+                    li.divider.divider-error.text-xs.text-error { "New messages" }
+                }
             }
         }
+        @if let (Some(last_msg), true) = (messages.last(), page.has_older) {
+            div class="loading loading-dots mx-auto mt-auto pt-8"
+                hx-trigger="intersect once"
+                hx-swap="outerHTML"
+                hx-get=(more_url(last_msg, "before"))
+                {}
+        }
     ))
 }
 
 fn render_message(
     msg: &Message,
+    extras: &MessageExtras,
     user_id: &Uuid,
-    channel_id: &Uuid,
-    server_id: &Uuid,
+    ids: &ChannelIds,
+    permissions: Permissions,
+    blocked: BlockedAuthors,
     swap_oob: bool,
 ) -> Result<Markup> {
     let is_author = &msg.author == user_id;
+    let can_delete = is_author || permissions.contains(Permissions::MANAGE_MESSAGES);
+    let can_see_history = can_delete;
+    let mentions_user = extras.mentions.includes(user_id);
+    // Threads are only one level deep, and replies are quoted within the channel
+    let in_thread = msg.thread.is_some();
+    let can_reply = !in_thread && permissions.contains(Permissions::SEND_MESSAGES);
+    let content = html!(
+        @if let Some(reply_to) = msg.reply_to {
+            // Loads the channel around the quoted message if it is not shown yet
+            a.block.border-l-2.border-current.pl-2.mb-1.text-xs.opacity-70
+                href={(ids.url())"?around="(reply_to)"#msg-"(reply_to)}
+                hx-boost="false"
+                "hx-on:click"={"if (document.getElementById('msg-"(reply_to)"')) { event.preventDefault(); location.hash = 'msg-"(reply_to)"' }"}
+            {
+                span.font-bold { (msg.reply_author_name.as_deref().unwrap_or_default()) } " "
+                @if blocked.reply_author {
+                    span.italic { "Blocked message" }
+                } @else {
+                    (truncate(msg.reply_content.as_deref().unwrap_or_default(), 100))
+                }
+            }
+        }
+        (markdown::render(&msg.content, &extras.mentions.names))
+        (render_attachments(&extras.attachments, ids))
+    );
     Ok(html!(
         li.group.chat
             .chat-end[is_author]
@@ -307,43 +948,95 @@ fn render_message(
             #{"msg-"(msg.id)}
             hx-swap-oob=[swap_oob.then_some("true")]
         {
+            button.chat-image
+                hx-get=(card_url(msg.author, ids.server_id))
+                hx-target="#modalInner"
+                hx-swap="outerHTML"
+                title="Show profile"
+            {
+                (render_avatar(&msg.author_name, msg.author_avatar, false))
+            }
             .chat-header.flex.items-center.gap-2.flex-row-reverse[!is_author] {
                 @let created_at = msg.id.get_datetime().ok_or(Error::NoTimestampFromUuid { id: msg.id })?;
                 relative-time.text-xs.opacity-50 datetime=(created_at.to_rfc3339()) {
                     (created_at.to_rfc2822()) " "
                 }
                 @if msg.updated.and_utc() > created_at {
-                    span.italic.text-xs.opacity-50 {
-                        "Edited "
+                    @if can_see_history {
+                        button.link.italic.text-xs.opacity-50
+                            hx-get={(ids.url())"/messages/"(msg.id)"/revisions"}
+                            hx-target="#modalInner"
+                            hx-swap="outerHTML"
+                            title="Show edit history"
+                            { "Edited" }
+                    } @else {
+                        span.italic.text-xs.opacity-50 {
+                            "Edited "
+                        }
                     }
                 }
-                (msg.author_name)
+                button.link.link-hover
+                    hx-get=(card_url(msg.author, ids.server_id))
+                    hx-target="#modalInner"
+                    hx-swap="outerHTML"
+                    { (msg.author_name) }
             }
-            .chat-bubble.chat-bubble-primary[is_author] {
-                (msg.content)
+            .chat-bubble.chat-bubble-primary[is_author].ring-2[mentions_user].ring-warning[mentions_user] {
+                @if blocked.author {
+                    details {
+                        summary.cursor-pointer.italic.opacity-70 { "Blocked message — show" }
+                        (content)
+                    }
+                } @else {
+                    (content)
+                }
             }
-            .chat-footer.transition-opacity hx-target="closest li" hx-swap="outerHTML" {
+            .chat-footer.transition-opacity.flex.flex-wrap.items-center.gap-1 hx-target="closest li" hx-swap="outerHTML" {
+                (render_reactions(&msg.id, &extras.reactions, user_id, ids, false))
+                (render_reaction_picker(&msg.id, ids))
+                @if can_reply {
+                    button
+                        class="link mr-2 opacity-0 group-hover:opacity-100"
+                        hx-get={(ids.url())"/messages/"(msg.id)"/reply"}
+                        hx-target="#reply-bar"
+                        "hx-on::after-request"="document.querySelector('#message-form textarea')?.focus()"
+                        { "Reply" }
+                }
+                @if !in_thread {
+                    (threads::render_thread_button(&msg.id, msg.thread_count, ids))
+                }
                 @if is_author {
                     button
                         class="link mr-2 opacity-0 group-hover:opacity-100"
-                        hx-get={"/servers/"(server_id)"/channels/"(channel_id)"/messages/"(msg.id)"/editable"}
+                        hx-get={(ids.url())"/messages/"(msg.id)"/editable"}
                         { "Edit" }
                 }
-                button
-                    class="link link-error opacity-0 group-hover:opacity-100"
-                    hx-delete={"/servers/"(server_id)"/channels/"(channel_id)"/messages/"(msg.id)}
-                    hx-confirm="Are you sure?"
-                    { "Delete" }
+                @if can_delete {
+                    button
+                        class="link link-error opacity-0 group-hover:opacity-100"
+                        hx-delete={(ids.url())"/messages/"(msg.id)}
+                        hx-confirm="Are you sure?"
+                        { "Delete" }
+                }
             }
         }
     ))
 }
 
-fn render_message_for_edit(msg: &Message, server_id: &Uuid, channel_id: &Uuid) -> Result<Markup> {
+/// Cuts the text to at most `max_chars` characters, marking that it was cut
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+fn render_message_for_edit(msg: &Message, ids: &ChannelIds) -> Result<Markup> {
     Ok(html!(
         li.group.chat.chat-end
             #{"msg-"(msg.id)}
         {
+            .chat-image { (render_avatar(&msg.author_name, msg.author_avatar, false)) }
             .chat-header.flex.items-center.gap-2 {
                 @let created_at = msg.id.get_datetime().ok_or(Error::NoTimestampFromUuid { id: msg.id })?;
                 relative-time.text-xs.opacity-50 datetime=(created_at.to_rfc3339()) {
@@ -357,21 +1050,57 @@ fn render_message_for_edit(msg: &Message, server_id: &Uuid, channel_id: &Uuid) -
                 (msg.author_name)
             }
             form.chat-bubble.chat-bubble-primary
-                hx-post={"/servers/"(server_id)"/channels/"(channel_id)"/messages/"(msg.id)}
+                hx-post={(ids.url())"/messages/"(msg.id)}
             {
-                input class="input text-base-content" name="content" value=(msg.content);
+                textarea class="textarea text-base-content" name="content" maxlength=(MAX_CONTENT_LEN)
+                    "hx-on:keydown"="if (event.key === 'Enter' && !event.shiftKey) { event.preventDefault(); this.form.requestSubmit() }"
+                    { (msg.content) }
             }
             .chat-footer hx-target="closest li" hx-swap="outerHTML" {
                 button
                     class="link mr-2"
-                    hx-get={"/servers/"(server_id)"/channels/"(channel_id)"/messages/"(msg.id)}
+                    hx-get={(ids.url())"/messages/"(msg.id)}
                     { "Cancel" }
                 button
                     class="link link-error"
-                    hx-delete={"/servers/"(server_id)"/channels/"(channel_id)"/messages/"(msg.id)}
+                    hx-delete={(ids.url())"/messages/"(msg.id)}
                     hx-confirm="Are you sure?"
                     { "Delete" }
             }
         }
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_length_counts_characters() {
+        assert!(check_content_len(&"a".repeat(MAX_CONTENT_LEN)).is_ok());
+        assert!(check_content_len(&"é".repeat(MAX_CONTENT_LEN)).is_ok());
+        assert!(matches!(
+            check_content_len(&"a".repeat(MAX_CONTENT_LEN + 1)),
+            Err(Error::MessageTooLong {
+                max_len: MAX_CONTENT_LEN
+            })
+        ));
+    }
+
+    #[test]
+    fn page_around_has_the_page_size() {
+        for limit in [PAGE_SIZE, 1, 2, 24] {
+            let (newer, older) = around_limits(limit);
+            assert_eq!(newer + older, limit);
+            assert!(older >= 1, "the message itself is always loaded");
+        }
+    }
+
+    #[test]
+    fn including_bound_is_right_after_the_message() {
+        let id = Uuid::now_v7();
+        assert!(id < including(id));
+        assert_eq!(including(id).as_u128(), id.as_u128() + 1);
+        assert_eq!(including(Uuid::max()), Uuid::max());
+    }
+}
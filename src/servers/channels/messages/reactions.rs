@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Form,
+};
+use chrono::Utc;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_as, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    AppState,
+};
+
+use super::{ChannelId, ChannelIds, MessageId};
+
+/// The emoji members can react with
+pub const REACTIONS: [&str; 8] = ["👍", "👎", "😄", "🎉", "😕", "❤️", "🚀", "👀"];
+
+pub struct Reaction {
+    pub message: Uuid,
+    pub emoji: String,
+    pub users: Vec<Uuid>,
+    pub user_names: Vec<String>,
+}
+
+/// The reactions on the messages in the order they were first used, keyed by message
+pub async fn fetch_reactions(
+    pool: &PgPool,
+    message_ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, Vec<Reaction>>> {
+    let rows = query_as!(
+        Reaction,
+        r#"SELECT r.message, r.emoji,
+        array_agg(r."user" ORDER BY r.created) as "users!",
+        array_agg(COALESCE(u.display_name, u.name) ORDER BY r.created) as "user_names!"
+    FROM message_reactions AS r
+    JOIN chat_users AS u ON u.id = r."user"
+    WHERE r.message = ANY($1)
+    GROUP BY r.message, r.emoji
+    ORDER BY min(r.created)"#,
+        message_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut reactions = BTreeMap::<Uuid, Vec<Reaction>>::new();
+    for reaction in rows {
+        reactions
+            .entry(reaction.message)
+            .or_default()
+            .push(reaction);
+    }
+    Ok(reactions)
+}
+
+#[derive(Deserialize)]
+pub(super) struct ToggledReaction {
+    emoji: String,
+}
+/// Adds the reaction, or removes it if the user already reacted with that emoji.
+///
+/// Everyone in the channel, including the user, gets the new counts through the event stream.
+pub(super) async fn toggle_reaction(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Form(ToggledReaction { emoji }): Form<ToggledReaction>,
+) -> Result<impl IntoResponse> {
+    if !REACTIONS.contains(&emoji.as_str()) {
+        return Err(Error::UnknownReaction { emoji });
+    }
+
+    let removed = query!(
+        r#"DELETE FROM message_reactions AS r
+    USING messages AS m
+    WHERE m.id = r.message AND r.message = $1 AND m.channel = $2 AND r."user" = $3 AND r.emoji = $4"#,
+        message_id,
+        channel_id,
+        user_id,
+        emoji,
+    )
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    if removed == 0 {
+        let rows_affected = query!(
+            r#"INSERT INTO message_reactions (message, "user", emoji, created)
+        SELECT id, $3, $4, $5 FROM messages WHERE id = $1 AND channel = $2
+        ON CONFLICT DO NOTHING"#,
+            message_id,
+            channel_id,
+            user_id,
+            emoji,
+            Utc::now().naive_utc(),
+        )
+        .execute(&state.db)
+        .await?;
+        if rows_affected.rows_affected() != 1 {
+            return Err(Error::DatabaseActionFailed);
+        }
+    }
+
+    Ok(html!())
+}
+
+/// The reaction counts of a message, swapped out of band when they change
+pub fn render_reactions(
+    message_id: &Uuid,
+    reactions: &[Reaction],
+    user_id: &Uuid,
+    ids: &ChannelIds,
+    swap_oob: bool,
+) -> Markup {
+    html!(
+        form.inline-flex.flex-wrap.gap-1
+            #{"reactions-"(message_id)}
+            hx-post={(ids.url())"/messages/"(message_id)"/reactions"}
+            hx-swap="none"
+            hx-swap-oob=[swap_oob.then_some("true")]
+        {
+            @for reaction in reactions {
+                @let reacted = reaction.users.contains(user_id);
+                button.btn.btn-xs.btn-outline[!reacted].btn-primary[reacted]
+                    name="emoji"
+                    value=(reaction.emoji)
+                    title=(reaction.user_names.join(", "))
+                {
+                    (reaction.emoji) " " (reaction.users.len())
+                }
+            }
+        }
+    )
+}
+
+pub fn render_reaction_picker(message_id: &Uuid, ids: &ChannelIds) -> Markup {
+    html!(
+        details.dropdown.dropdown-top {
+            summary class="link mr-2 opacity-0 group-hover:opacity-100" { "React" }
+            form.dropdown-content.z-10.flex.gap-1.rounded-box.bg-base-200.p-2.shadow
+                hx-post={(ids.url())"/messages/"(message_id)"/reactions"}
+                hx-swap="none"
+                "hx-on::after-request"="this.closest('details').removeAttribute('open')"
+            {
+                @for emoji in REACTIONS {
+                    button.btn.btn-ghost.btn-sm name="emoji" value=(emoji) { (emoji) }
+                }
+            }
+        }
+    )
+}
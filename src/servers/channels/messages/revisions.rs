@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Extension,
+};
+use axum_htmx::HxResponseTrigger;
+use chrono::NaiveDateTime;
+use maud::{html, Markup};
+use similar::{ChangeTag, TextDiff};
+use sqlx::{query, query_as};
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::Result,
+    servers::permissions::{ChannelPermissions, Permissions},
+    AppState,
+};
+
+use super::{ChannelId, MessageId};
+
+struct Version {
+    content: String,
+    written: NaiveDateTime,
+}
+
+/// The versions of the message, newest first, for its author and those that can manage messages
+pub(super) async fn get_revisions(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+) -> Result<impl IntoResponse> {
+    let msg = query!(
+        r#"SELECT author, content, updated FROM messages WHERE id = $1 AND channel = $2"#,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    if msg.author != user_id {
+        permissions.require(Permissions::MANAGE_MESSAGES)?;
+    }
+
+    let revisions = query_as!(
+        Version,
+        r#"SELECT content, written FROM message_revisions WHERE message = $1 ORDER BY id DESC"#,
+        message_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    let versions = std::iter::once(Version {
+        content: msg.content,
+        written: msg.updated,
+    })
+    .chain(revisions)
+    .collect::<Vec<_>>();
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html!(
+            h3.text-lg.font-bold { "Edit history" }
+            ol.mt-2.flex.flex-col.gap-4 {
+                @for (i, version) in versions.iter().enumerate() {
+                    @let previous = versions.get(i + 1);
+                    li {
+                        .flex.items-center.gap-2.text-xs.opacity-70 {
+                            span.font-bold {
+                                @if i == 0 { "Current" }
+                                @else if previous.is_none() { "Original" }
+                                @else { "Version " (versions.len() - i) }
+                            }
+                            relative-time datetime=(version.written.and_utc().to_rfc3339()) {
+                                (version.written.and_utc().to_rfc2822())
+                            }
+                        }
+                        p.whitespace-pre-wrap.break-words.rounded.bg-base-200.p-2 {
+                            @if let Some(previous) = previous {
+                                (render_diff(&previous.content, &version.content))
+                            } @else {
+                                (version.content)
+                            }
+                        }
+                    }
+                }
+            }
+        )),
+    ))
+}
+
+/// The words that were removed and added by an edit, within the rest of the text
+fn render_diff(old: &str, new: &str) -> Markup {
+    let diff = TextDiff::from_words(old, new);
+    html!(
+        @for change in diff.iter_all_changes() {
+            @match change.tag() {
+                ChangeTag::Equal => (change.value()),
+                ChangeTag::Delete => del.bg-error.text-error-content { (change.value()) },
+                ChangeTag::Insert => ins.bg-success.text-success-content.no-underline { (change.value()) },
+            }
+        }
+    )
+}
@@ -0,0 +1,151 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Path, State},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse,
+    },
+    routing, Extension, Router,
+};
+use maud::{html, Markup};
+use sqlx::query;
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::Result,
+    servers::permissions::{ChannelPermissions, Permissions},
+    users::blocks::fetch_blocked,
+    AppState,
+};
+
+use super::{
+    fetch_extras, fetch_page, live, markdown, render_message_form, render_messages, subscribe,
+    ChannelIds, MessageId, MessagePage, Page,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_thread))
+        .route("/events", routing::get(thread_event_stream))
+}
+
+/// Opens the thread of a message in the side panel of the chat page
+async fn open_thread(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Path(ids @ ChannelIds { channel_id, .. }): Path<ChannelIds>,
+) -> Result<impl IntoResponse> {
+    let parent = query!(
+        r#"SELECT m.content, COALESCE(u.display_name, u.name) as "author_name!"
+      FROM messages AS m
+      JOIN chat_users AS u ON u.id = m.author
+      WHERE m.id = $1 AND m.channel = $2 AND m.thread IS NULL"#,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    let page = MessagePage {
+        blocked: fetch_blocked(&state.db, user_id).await?,
+        ..fetch_page(&state.db, channel_id, Some(message_id), Page::Latest).await?
+    };
+    let extras = fetch_extras(
+        &state.db,
+        &page.messages.iter().map(|msg| msg.id).collect::<Vec<_>>(),
+    )
+    .await?;
+
+    Ok(html!(
+        aside #thread-panel.grid.w-96.gap-2.border-l.border-base-300.pl-2
+            style="grid-template-rows: auto auto minmax(0,1fr) auto"
+        {
+            .flex.items-center.justify-between {
+                h2.font-bold { "Thread" }
+                button.btn.btn-circle.btn-ghost.btn-sm
+                    "hx-on:click"="htmx.remove('#thread-panel')"
+                    aria-label="close"
+                    { "✕" }
+            }
+            a.block.rounded.bg-base-200.p-2.text-sm href={"#msg-"(message_id)} hx-boost="false" {
+                span.font-bold { (parent.author_name) }
+                (markdown::render(&parent.content, &[]))
+            }
+            ol #thread-messages.flex.flex-col-reverse.overflow-y-auto
+                hx-ext="sse"
+                sse-connect={(ids.url())"/messages/"(message_id)"/thread/events"}
+                sse-swap="message"
+                hx-swap="afterbegin"
+            {
+                li.hidden sse-swap="typing" hx-swap="none" {}
+                (render_messages(&page, &extras, ids, user_id, permissions)?)
+            }
+            @if permissions.contains(Permissions::SEND_MESSAGES) {
+                (render_message_form(ids, Some(message_id), &state.attachment_limits))
+            }
+        }
+    ))
+}
+
+/// Only receives the messages of the thread, the channel stream only those outside of threads
+async fn thread_event_stream(
+    State(state): State<AppState>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+    Extension(ChannelPermissions(permissions)): Extension<ChannelPermissions>,
+    Path(MessageId { message_id }): Path<MessageId>,
+    Path(ids @ ChannelIds { channel_id, .. }): Path<ChannelIds>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = std::result::Result<Event, Infallible>>>> {
+    query!(
+        r#"SELECT id FROM messages WHERE id = $1 AND channel = $2 AND thread IS NULL"#,
+        message_id,
+        channel_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    subscribe(
+        &state,
+        ids,
+        live::Subscriber {
+            user_id,
+            session_id,
+            permissions,
+            thread: Some(message_id),
+        },
+    )
+    .await
+}
+
+/// Opens the thread, showing how many replies it has
+pub fn render_thread_button(message_id: &Uuid, thread_count: i64, ids: &ChannelIds) -> Markup {
+    html!(
+        button
+            class={"link mr-2 " @if thread_count == 0 { "opacity-0 group-hover:opacity-100" }}
+            hx-get={(ids.url())"/messages/"(message_id)"/thread"}
+            hx-target="#thread-wrapper"
+            hx-swap="innerHTML"
+        {
+            (render_thread_count(message_id, thread_count, false))
+        }
+    )
+}
+
+/// Swapped out of band when a message is added to the thread
+pub fn render_thread_count(message_id: &Uuid, thread_count: i64, swap_oob: bool) -> Markup {
+    html!(
+        span #{"thread-count-"(message_id)} hx-swap-oob=[swap_oob.then_some("true")] {
+            @match thread_count {
+                0 => "Thread",
+                1 => "1 reply",
+                n => (n) " replies",
+            }
+        }
+    )
+}
@@ -1,24 +1,38 @@
 use axum::{
-    extract::{Path, Query, State},
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::{from_fn_with_state, Next},
     response::IntoResponse,
-    routing, Form, Router,
+    routing, Extension, Form, Router,
 };
 use axum_htmx::HxResponseTrigger;
 use maud::{html, Markup};
 use serde::Deserialize;
-use sqlx::{query, PgPool};
+use sqlx::{query, query_as, PgPool};
+use tokio::try_join;
 use uuid::Uuid;
 
 use crate::{
+    auth::Auth,
     base_modal,
     chat::get_chat_page,
     error::{Error, Result},
     AppState,
 };
 
-use super::ServerId;
+use messages::attachments;
+
+use super::{
+    permissions::{
+        apply_overwrites, fetch_channel_permissions, fetch_overwrites, ChannelPermissions,
+        Permissions,
+    },
+    ServerId,
+};
 
 pub mod messages;
+mod overwrites;
+pub mod unread;
 
 #[derive(Deserialize)]
 pub struct ChannelId {
@@ -29,25 +43,55 @@ pub struct MaybeChannelId {
     pub channel_id: Option<Uuid>,
 }
 
-pub fn router() -> Router<AppState> {
+pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
-        .nest("/:channel_id/messages", messages::router())
+        .nest("/:channel_id/messages", messages::router(state.clone()))
+        .nest("/:channel_id/overwrites", overwrites::router())
+        .route("/:channel_id/read", routing::post(unread::read_channel))
         .route(
             "/:channel_id",
             routing::get(get_chat_page).delete(delete_channel),
         )
+        .layer(from_fn_with_state(state.clone(), can_user_view_channel))
         .route("/", routing::get(get_channels).post(create_channel))
 }
 
+/// Also makes the member's [`ChannelPermissions`] available to handlers as an `Extension`
+async fn can_user_view_channel(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    mut request: Request,
+    next: Next,
+) -> Result<impl IntoResponse> {
+    match fetch_channel_permissions(&state.db, user_id, server_id, channel_id, permissions).await? {
+        Some(permissions) if permissions.contains(Permissions::VIEW_CHANNEL) => {
+            request
+                .extensions_mut()
+                .insert(ChannelPermissions(permissions));
+            Ok(next.run(request).await)
+        }
+        Some(_) => Err(Error::MissingPermissions {
+            needed: Permissions::VIEW_CHANNEL,
+        }),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
 #[derive(Deserialize)]
 struct NewChannel {
     name: String,
 }
 async fn create_channel(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
     new_channel: Option<Form<NewChannel>>,
 ) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
     fn render_new_channel_form_inners(server_id: Uuid) -> Markup {
         base_modal(html!(
             form method="post" hx-post={"/servers/"(server_id)"/channels"} {
@@ -91,32 +135,54 @@ async fn create_channel(
 
 async fn delete_channel(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
     Path(ChannelId { channel_id }): Path<ChannelId>,
 ) -> Result<impl IntoResponse> {
-    let rows_affected = query!(r#"DELETE FROM channels WHERE id = $1"#, channel_id)
-        .execute(&state.db)
-        .await?;
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
+    let attachment_ids =
+        attachments::fetch_attachment_ids(&state.db, attachments::Scope::Channel(channel_id))
+            .await?;
+    let rows_affected = query!(
+        r#"DELETE FROM channels WHERE id = $1 AND server = $2"#,
+        channel_id,
+        server_id,
+    )
+    .execute(&state.db)
+    .await?;
 
     if rows_affected.rows_affected() != 1 {
         return Err(Error::DatabaseActionFailed);
     }
+    attachments::remove_files(state.storage.as_ref(), &attachment_ids).await;
 
     Ok(html!())
 }
 
 async fn get_channels(
     State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
     Query(MaybeChannelId { channel_id }): Query<MaybeChannelId>,
 ) -> Result<impl IntoResponse> {
-    fetch_render_channel_list(&state.db, server_id, channel_id).await
+    fetch_render_channel_list(&state.db, server_id, channel_id, user_id, permissions).await
 }
-pub async fn fetch_render_channel_list(
+pub struct Channel {
+    pub id: Uuid,
+    pub name: String,
+}
+
+/// The channels of the server the user can see after applying the overwrites of each channel
+pub async fn fetch_visible_channels(
     pool: &PgPool,
     server_id: Uuid,
-    active_channel: Option<Uuid>,
-) -> Result<Markup> {
-    let channels = query!(
+    user_id: Uuid,
+    permissions: Permissions,
+) -> Result<Vec<Channel>> {
+    let channels = query_as!(
+        Channel,
         r#"SELECT c.id, c.name
     FROM channels AS c
     WHERE c.server = $1"#,
@@ -124,6 +190,28 @@ pub async fn fetch_render_channel_list(
     )
     .fetch_all(pool)
     .await?;
+    let overwrites = fetch_overwrites(pool, user_id, server_id, None).await?;
+    Ok(channels
+        .into_iter()
+        .filter(|channel| {
+            apply_overwrites(permissions, &overwrites, channel.id)
+                .contains(Permissions::VIEW_CHANNEL)
+        })
+        .collect())
+}
+
+pub async fn fetch_render_channel_list(
+    pool: &PgPool,
+    server_id: Uuid,
+    active_channel: Option<Uuid>,
+    user_id: Uuid,
+    permissions: Permissions,
+) -> Result<Markup> {
+    let can_manage = permissions.contains(Permissions::MANAGE_CHANNELS);
+    let (channels, unread) = try_join!(
+        fetch_visible_channels(pool, server_id, user_id, permissions),
+        unread::fetch_unread(pool, user_id, server_id, permissions),
+    )?;
 
     Ok(html!(
         ul #channels-list
@@ -132,22 +220,34 @@ pub async fn fetch_render_channel_list(
             hx-trigger="get-channel-list from:body"
             hx-swap="outerHTML"
         {
-            li.menu-title {
-                button class="btn btn-ghost btn-sm" hx-post={"/servers/"(server_id)"/channels"} hx-target="#modalInner" { "New" }
+            @if can_manage {
+                li.menu-title {
+                    button class="btn btn-ghost btn-sm" hx-post={"/servers/"(server_id)"/channels"} hx-target="#modalInner" { "New" }
+                }
             }
             @for channel in channels {
+                @let unread = unread.get(&channel.id).copied().unwrap_or_default();
                 li #{"channel-"(channel.id)} {
                     div.active[active_channel.is_some_and(|id| id == channel.id)].flex {
                         a.grow href={"/servers/"(server_id)"/channels/"(channel.id)} {
                             (channel.name)
                         }
-                        button
-                            class="btn btn-circle btn-ghost btn-sm hover:btn-error"
-                            hx-delete={"/servers/"(server_id)"/channels/"(channel.id)}
-                            hx-confirm={"Are you sure you want to delete '"(channel.name)"'?"}
-                            hx-target="closest li"
-                            hx-swap="outerHTML"
-                            { "✕" }
+                        (unread::render_channel_unread(channel.id, unread, false))
+                        @if can_manage {
+                            button
+                                class="btn btn-circle btn-ghost btn-sm"
+                                hx-get={"/servers/"(server_id)"/channels/"(channel.id)"/overwrites"}
+                                hx-target="#modalInner"
+                                title="Channel permissions"
+                                { "..." }
+                            button
+                                class="btn btn-circle btn-ghost btn-sm hover:btn-error"
+                                hx-delete={"/servers/"(server_id)"/channels/"(channel.id)}
+                                hx-confirm={"Are you sure you want to delete '"(channel.name)"'?"}
+                                hx-target="closest li"
+                                hx-swap="outerHTML"
+                                { "✕" }
+                        }
                     }
                 }
             }
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing, Extension, Form, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    base_modal,
+    error::{Error, Result},
+    servers::{permissions::Permissions, ServerId},
+    AppState,
+};
+
+use super::ChannelId;
+
+#[derive(Deserialize)]
+struct OverwriteId {
+    overwrite_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_overwrites_page).post(add_overwrite))
+        .route("/list", routing::get(get_overwrites_list))
+        .route(
+            "/:overwrite_id",
+            routing::put(update_overwrite).delete(delete_overwrite),
+        )
+}
+
+async fn open_overwrites_page(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
+    let channel = query!(r#"SELECT name FROM channels WHERE id = $1"#, channel_id)
+        .fetch_one(&state.db)
+        .await?;
+    let add_form = fetch_render_add_overwrite_form(&state.db, server_id, channel_id).await?;
+    let overwrites_list = fetch_render_overwrites_list(&state.db, server_id, channel_id).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html! {
+            h3.text-lg.font-bold { "Permissions for " (channel.name) }
+            (add_form)
+            (overwrites_list)
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct NewOverwrite {
+    /// Either `everyone`, `role:<id>` or `user:<id>`
+    target: String,
+}
+async fn add_overwrite(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    Form(new_overwrite): Form<NewOverwrite>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
+    let new_id = Uuid::now_v7();
+    // Adding an overwrite that already exists is a no-op, the list is refreshed either way
+    match new_overwrite.target.split_once(':') {
+        None if new_overwrite.target == "everyone" => {
+            query!(
+                r#"INSERT INTO channel_overwrites (id, channel) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING"#,
+                new_id,
+                channel_id,
+            )
+            .execute(&state.db)
+            .await?;
+        }
+        Some(("role", role_id)) => {
+            let role_id = Uuid::try_parse(role_id).or(Err(Error::DatabaseActionFailed))?;
+            query!(
+                r#"INSERT INTO channel_overwrites (id, channel, role)
+                SELECT $1, $2, id FROM roles WHERE id = $3 AND server = $4
+                ON CONFLICT DO NOTHING"#,
+                new_id,
+                channel_id,
+                role_id,
+                server_id,
+            )
+            .execute(&state.db)
+            .await?;
+        }
+        Some(("user", user_id)) => {
+            let user_id = Uuid::try_parse(user_id).or(Err(Error::DatabaseActionFailed))?;
+            query!(
+                r#"INSERT INTO channel_overwrites (id, channel, "user")
+                SELECT $1, $2, "user" FROM users_member_of_servers WHERE "user" = $3 AND server = $4
+                ON CONFLICT DO NOTHING"#,
+                new_id,
+                channel_id,
+                user_id,
+                server_id,
+            )
+            .execute(&state.db)
+            .await?;
+        }
+        _ => return Err(Error::DatabaseActionFailed),
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-overwrites-list", "get-channel-list"]),
+        html!(),
+    ))
+}
+
+/// Each channel permission is sent as `inherit`, `allow` or `deny`, named after the flag
+#[derive(Deserialize)]
+struct UpdatedOverwrite {
+    #[serde(flatten)]
+    permissions: HashMap<String, String>,
+}
+async fn update_overwrite(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    Path(OverwriteId { overwrite_id }): Path<OverwriteId>,
+    Form(updated): Form<UpdatedOverwrite>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
+    let mut allow = Permissions::empty();
+    let mut deny = Permissions::empty();
+    for (name, flag, _) in Permissions::labeled(Permissions::CHANNEL) {
+        match updated.permissions.get(name).map(String::as_str) {
+            Some("allow") => allow |= flag,
+            Some("deny") => deny |= flag,
+            _ => {}
+        }
+    }
+
+    let rows_affected = query!(
+        r#"UPDATE channel_overwrites SET allow = $1, deny = $2 WHERE id = $3 AND channel = $4"#,
+        allow.bits(),
+        deny.bits(),
+        overwrite_id,
+        channel_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((HxResponseTrigger::normal(["get-channel-list"]), html!()))
+}
+
+async fn delete_overwrite(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+    Path(OverwriteId { overwrite_id }): Path<OverwriteId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+
+    let rows_affected = query!(
+        r#"DELETE FROM channel_overwrites WHERE id = $1 AND channel = $2"#,
+        overwrite_id,
+        channel_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((HxResponseTrigger::normal(["get-channel-list"]), html!()))
+}
+
+async fn fetch_render_add_overwrite_form(
+    pool: &PgPool,
+    server_id: Uuid,
+    channel_id: Uuid,
+) -> Result<Markup> {
+    let roles = query!(
+        r#"SELECT id, name FROM roles WHERE server = $1 ORDER BY id"#,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let members = query!(
+        r#"SELECT u.id, u.name
+    FROM chat_users AS u
+    JOIN users_member_of_servers AS m ON m."user" = u.id
+    WHERE m.server = $1
+    ORDER BY u.name"#,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        form.flex.items-end.gap-2
+            hx-post={"/servers/"(server_id)"/channels/"(channel_id)"/overwrites"}
+            hx-swap="none"
+        {
+            .form-control.grow {
+                .label { .label-text { "Add overwrite for" } }
+                select name="target" class="select select-bordered w-full" {
+                    option value="everyone" { "Everyone" }
+                    optgroup label="Roles" {
+                        @for role in roles {
+                            option value={"role:"(role.id)} { (role.name) }
+                        }
+                    }
+                    optgroup label="Members" {
+                        @for member in members {
+                            option value={"user:"(member.id)} { (member.name) }
+                        }
+                    }
+                }
+            }
+            button type="submit" class="btn btn-primary" { "Add" }
+        }
+    ))
+}
+
+async fn get_overwrites_list(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_CHANNELS)?;
+    fetch_render_overwrites_list(&state.db, server_id, channel_id).await
+}
+async fn fetch_render_overwrites_list(
+    pool: &PgPool,
+    server_id: Uuid,
+    channel_id: Uuid,
+) -> Result<Markup> {
+    let overwrites = query!(
+        r#"SELECT o.id, o.allow, o.deny, r.name as "role_name?", u.name as "user_name?"
+    FROM channel_overwrites AS o
+    LEFT JOIN roles AS r ON r.id = o.role
+    LEFT JOIN chat_users AS u ON u.id = o."user"
+    WHERE o.channel = $1
+    ORDER BY o."user" IS NOT NULL, o.role IS NOT NULL, o.id"#,
+        channel_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        #overwrites-list.flex.flex-col.gap-2.mt-4
+            hx-get={"/servers/"(server_id)"/channels/"(channel_id)"/overwrites/list"}
+            hx-trigger="update-overwrites-list from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            @for overwrite in overwrites {
+                @let allow = Permissions::from_bits_truncate(overwrite.allow);
+                @let deny = Permissions::from_bits_truncate(overwrite.deny);
+                @let overwrite_url = format!("/servers/{server_id}/channels/{channel_id}/overwrites/{}", overwrite.id);
+                form.card.bg-base-200 hx-put=(overwrite_url) hx-swap="none" {
+                    .card-body.p-4.gap-1 {
+                        h4.font-medium {
+                            @if let Some(role_name) = &overwrite.role_name {
+                                span.badge.mr-1 { "Role" } (role_name)
+                            } @else if let Some(user_name) = &overwrite.user_name {
+                                span.badge.mr-1 { "Member" } (user_name)
+                            } @else {
+                                "Everyone"
+                            }
+                        }
+                        @for (flag_name, flag, label) in Permissions::labeled(Permissions::CHANNEL) {
+                            label.flex.items-center.justify-between {
+                                span.label-text { (label) }
+                                select name=(flag_name) class="select select-bordered select-sm" {
+                                    option value="inherit" { "Inherit" }
+                                    option value="allow" selected[allow.contains(flag)] { "Allow" }
+                                    option value="deny" selected[deny.contains(flag)] { "Deny" }
+                                }
+                            }
+                        }
+                        .card-actions.justify-end {
+                            button type="button" class="btn btn-error btn-sm"
+                                hx-delete=(overwrite_url)
+                                hx-target="closest form"
+                                hx-swap="outerHTML"
+                                { "Remove" }
+                            button type="submit" class="btn btn-primary btn-sm" { "Save" }
+                        }
+                    }
+                }
+            }
+        }
+    ))
+}
@@ -0,0 +1,374 @@
+use std::{collections::BTreeMap, convert::Infallible};
+
+use axum::{
+    extract::{Path, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Extension,
+};
+use maud::{html, Markup};
+use sqlx::{query, query_scalar, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    servers::{
+        permissions::{
+            apply_overwrites, fetch_members_permissions, fetch_server_overwrites, Overwrite,
+            Permissions,
+        },
+        ServerId,
+    },
+    AppState,
+};
+
+use super::{fetch_visible_channels, messages::live::ActivitySubscriber, ChannelId};
+
+/// Messages by others sent after the member last read the channel
+#[derive(Default, Clone, Copy)]
+pub struct Unread {
+    pub messages: i64,
+    /// Of the unread messages, how many mention the member
+    pub mentions: i64,
+}
+
+impl Unread {
+    /// The unread messages of all the channels of a server
+    pub fn sum<'a>(unread: impl IntoIterator<Item = &'a Unread>) -> Unread {
+        unread
+            .into_iter()
+            .fold(Unread::default(), |sum, unread| Unread {
+                messages: sum.messages + unread.messages,
+                mentions: sum.mentions + unread.mentions,
+            })
+    }
+}
+
+/// What the user has not read in each channel of the server they can see, keyed by channel.
+///
+/// Channels the user never opened count all of their messages as unread. The counting itself is
+/// done by the `channel_unread` function of the database, so every list counts the same way.
+pub async fn fetch_unread(
+    pool: &PgPool,
+    user_id: Uuid,
+    server_id: Uuid,
+    permissions: Permissions,
+) -> Result<BTreeMap<Uuid, Unread>> {
+    let rows = query!(
+        r#"SELECT c.id, u.messages as "messages!", u.mentions as "mentions!"
+    FROM channels AS c
+    CROSS JOIN LATERAL channel_unread($1, c.id) AS u
+    WHERE c.server = $2"#,
+        user_id,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let visible = fetch_visible_channels(pool, server_id, user_id, permissions)
+        .await?
+        .into_iter()
+        .map(|channel| channel.id)
+        .collect::<Vec<_>>();
+
+    Ok(rows
+        .into_iter()
+        .filter(|row| visible.contains(&row.id))
+        .map(|row| {
+            let unread = Unread {
+                messages: row.messages,
+                mentions: row.mentions,
+            };
+            (row.id, unread)
+        })
+        .collect())
+}
+
+/// What the user has not read in each of their conversations, keyed by conversation
+pub async fn fetch_conversation_unread(
+    pool: &PgPool,
+    user_id: Uuid,
+) -> Result<BTreeMap<Uuid, Unread>> {
+    let rows = query!(
+        r#"SELECT cm.channel, u.messages as "messages!", u.mentions as "mentions!"
+    FROM conversation_members AS cm
+    CROSS JOIN LATERAL channel_unread($1, cm.channel) AS u
+    WHERE cm."user" = $1"#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let unread = Unread {
+                messages: row.messages,
+                mentions: row.mentions,
+            };
+            (row.channel, unread)
+        })
+        .collect())
+}
+
+/// Marks everything in the channel as read, returning the newest message that was read before
+pub async fn mark_read(pool: &PgPool, user_id: Uuid, channel_id: Uuid) -> Result<Option<Uuid>> {
+    let previous = query_scalar!(
+        r#"WITH previous AS (
+        SELECT last_read FROM channel_reads WHERE "user" = $1 AND channel = $2
+    )
+    INSERT INTO channel_reads ("user", channel, last_read)
+    SELECT $1, $2, id FROM messages WHERE channel = $2 ORDER BY id DESC LIMIT 1
+    ON CONFLICT ("user", channel)
+        DO UPDATE SET last_read = GREATEST(channel_reads.last_read, EXCLUDED.last_read)
+    RETURNING (SELECT last_read FROM previous)"#,
+        user_id,
+        channel_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(previous.flatten())
+}
+
+/// Sent while the user is looking at the channel and new messages come in
+pub(super) async fn read_channel(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(ChannelId { channel_id }): Path<ChannelId>,
+) -> Result<impl IntoResponse> {
+    mark_read(&state.db, user_id, channel_id).await?;
+    let unread = fetch_unread(&state.db, user_id, server_id, permissions).await?;
+
+    Ok(html!((render_channel_unread(
+        channel_id,
+        Unread::default(),
+        true
+    ))(render_server_unread(
+        server_id,
+        Unread::sum(unread.values()),
+        true
+    ))))
+}
+
+/// The unread counts of the server and channel lists as messages are sent anywhere the user can see
+pub async fn activity_stream(
+    State(state): State<AppState>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+) -> Result<Sse<impl tokio_stream::Stream<Item = std::result::Result<Event, Infallible>>>> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    state
+        .message_live
+        .register_activity
+        .send((
+            ActivitySubscriber {
+                user_id,
+                session_id,
+            },
+            tx,
+        ))
+        .await
+        .map_err(|_| Error::SSEChannelRegistrationChannelFailed)?;
+
+    // The user counts as online for as long as the stream is open
+    let stream = state.presence.track(
+        user_id,
+        tokio_stream::wrappers::UnboundedReceiverStream::new(
+            rx.await
+                .map_err(|_| Error::SSERegistationDidNotRecvChannel)?,
+        ),
+    );
+
+    Ok(Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(std::time::Duration::from_secs(5))
+            .text("heartbeat"),
+    ))
+}
+
+/// The updated counts of the channel and its server for each of the users that can see it, after
+/// a message was sent in it. The counts of all of them are fetched at once.
+pub async fn render_activity(
+    pool: &PgPool,
+    channel_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<BTreeMap<Uuid, Markup>> {
+    let Some(server_id) = query_scalar!(r#"SELECT server FROM channels WHERE id = $1"#, channel_id)
+        .fetch_optional(pool)
+        .await?
+    else {
+        // Deleted since the message was sent
+        return Ok(BTreeMap::new());
+    };
+    let Some(server_id) = server_id else {
+        // Conversations are listed on their own, without a server
+        let rows = query!(
+            r#"SELECT cm."user", u.messages as "messages!", u.mentions as "mentions!"
+        FROM conversation_members AS cm
+        CROSS JOIN LATERAL channel_unread(cm."user", cm.channel) AS u
+        WHERE cm.channel = $1 AND cm."user" = ANY($2)"#,
+            channel_id,
+            user_ids,
+        )
+        .fetch_all(pool)
+        .await?;
+        return Ok(rows
+            .into_iter()
+            .map(|row| {
+                let unread = Unread {
+                    messages: row.messages,
+                    mentions: row.mentions,
+                };
+                (row.user, render_channel_unread(channel_id, unread, true))
+            })
+            .collect());
+    };
+
+    let members = fetch_members_permissions(pool, server_id, user_ids).await?;
+    let member_ids = members
+        .iter()
+        .map(|member| member.user_id)
+        .collect::<Vec<_>>();
+    let overwrites = fetch_server_overwrites(pool, server_id).await?;
+    let rows = query!(
+        r#"SELECT m."user" as "user!", c.id, u.messages as "messages!", u.mentions as "mentions!"
+    FROM unnest($2::uuid[]) AS m("user")
+    CROSS JOIN channels AS c
+    CROSS JOIN LATERAL channel_unread(m."user", c.id) AS u
+    WHERE c.server = $1"#,
+        server_id,
+        &member_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut unread = BTreeMap::<Uuid, BTreeMap<Uuid, Unread>>::new();
+    for row in rows {
+        let channel_unread = Unread {
+            messages: row.messages,
+            mentions: row.mentions,
+        };
+        unread
+            .entry(row.user)
+            .or_default()
+            .insert(row.id, channel_unread);
+    }
+
+    let no_overwrites = Vec::new();
+    Ok(members
+        .iter()
+        .filter_map(|member| {
+            // Only the channels the member can see count towards the server
+            let visible = unread
+                .get(&member.user_id)?
+                .iter()
+                .filter(|(channel, _)| {
+                    member
+                        .in_channel(overwrites.get(channel).unwrap_or(&no_overwrites))
+                        .contains(Permissions::VIEW_CHANNEL)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let channel_unread = visible.get(&channel_id)?;
+            let markup = html!((render_channel_unread(channel_id, **channel_unread, true))(
+                render_server_unread(server_id, Unread::sum(visible.into_values()), true)
+            ));
+            Some((member.user_id, markup))
+        })
+        .collect())
+}
+
+/// What the user has not read in each of their servers, in the channels they can see. All servers
+/// are counted at once, `permissions` are the user's in each of them.
+pub async fn fetch_server_unread(
+    pool: &PgPool,
+    user_id: Uuid,
+    permissions: &BTreeMap<Uuid, Permissions>,
+) -> Result<BTreeMap<Uuid, Unread>> {
+    let rows = query!(
+        r#"SELECT c.server as "server!", c.id, u.messages as "messages!", u.mentions as "mentions!"
+    FROM channels AS c
+    JOIN users_member_of_servers AS s ON s.server = c.server AND s."user" = $1
+    CROSS JOIN LATERAL channel_unread($1, c.id) AS u"#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let overwrites = query!(
+        r#"SELECT o.channel, o.role, o."user", o.allow, o.deny
+    FROM channel_overwrites AS o
+    JOIN channels AS c ON c.id = o.channel
+    JOIN users_member_of_servers AS s ON s.server = c.server AND s."user" = $1
+    WHERE (o.role IS NULL AND o."user" IS NULL)
+        OR o."user" = $1
+        OR o.role IN (SELECT role FROM members_have_roles WHERE "user" = $1)"#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut channel_overwrites = BTreeMap::<Uuid, Vec<Overwrite>>::new();
+    for row in overwrites {
+        channel_overwrites
+            .entry(row.channel)
+            .or_default()
+            .push(Overwrite {
+                role: row.role,
+                user: row.user,
+                allow: row.allow,
+                deny: row.deny,
+            });
+    }
+
+    let mut unread = BTreeMap::<Uuid, Unread>::new();
+    for row in rows {
+        let Some(permissions) = permissions.get(&row.server) else {
+            continue;
+        };
+        let can_view = apply_overwrites(*permissions, &channel_overwrites, row.id)
+            .contains(Permissions::VIEW_CHANNEL);
+        if can_view {
+            let sum = unread.entry(row.server).or_default();
+            sum.messages += row.messages;
+            sum.mentions += row.mentions;
+        }
+    }
+    Ok(unread)
+}
+
+pub fn render_channel_unread(channel_id: Uuid, unread: Unread, swap_oob: bool) -> Markup {
+    html!(
+        span.flex.gap-1 #{"channel-unread-"(channel_id)} hx-swap-oob=[swap_oob.then_some("true")] {
+            @if unread.mentions > 0 {
+                span.badge.badge-error.badge-sm title="Mentions" { (unread.mentions) }
+            }
+            @if unread.messages > 0 {
+                span.badge.badge-sm title="Unread messages" { (format_count(unread.messages)) }
+            }
+        }
+    )
+}
+
+pub fn render_server_unread(server_id: Uuid, unread: Unread, swap_oob: bool) -> Markup {
+    html!(
+        span.flex.items-center.gap-1 #{"server-unread-"(server_id)} hx-swap-oob=[swap_oob.then_some("true")] {
+            @if unread.mentions > 0 {
+                span.badge.badge-error.badge-sm title="Mentions" { (unread.mentions) }
+            } @else if unread.messages > 0 {
+                span.badge.badge-primary.badge-xs aria-label="Unread messages" {}
+            }
+        }
+    )
+}
+
+fn format_count(count: i64) -> String {
+    if count > 99 {
+        "99+".to_owned()
+    } else {
+        count.to_string()
+    }
+}
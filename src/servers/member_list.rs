@@ -0,0 +1,207 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query_as, query_scalar};
+use uuid::Uuid;
+
+use crate::{
+    error::Result,
+    users::{
+        avatars::render_avatar,
+        cards::card_url,
+        presence::{render_status_dot, status_text_class},
+    },
+    AppState,
+};
+
+use super::ServerId;
+
+const PAGE_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+pub(super) struct MemberListQuery {
+    /// The last member of the page before
+    after: Option<Uuid>,
+}
+
+struct Member {
+    id: Uuid,
+    name: String,
+    avatar_small: Option<Uuid>,
+    status_text: String,
+    is_owner: bool,
+    /// The name of the member's role with the most permissions
+    role: Option<String>,
+    online: bool,
+}
+
+impl Member {
+    /// Online members are listed under their role with the most permissions, the offline ones
+    /// after all of them
+    fn group(&self) -> &str {
+        match self.online {
+            true => self.role.as_deref().unwrap_or("Members"),
+            false => "Offline",
+        }
+    }
+}
+
+pub(super) async fn get_member_list(
+    State(state): State<AppState>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Query(MemberListQuery { after }): Query<MemberListQuery>,
+) -> Result<impl IntoResponse> {
+    fetch_render_member_list(&state, server_id, after, false).await
+}
+
+/// The members panel beside the chat, it can be collapsed to its title
+pub async fn fetch_render_member_panel(state: &AppState, server_id: Uuid) -> Result<Markup> {
+    let member_list = fetch_render_member_list(state, server_id, None, false).await?;
+
+    Ok(html!(
+        details #members-panel.w-56.overflow-y-auto.border-l.border-base-300.pl-2 open {
+            summary.cursor-pointer.font-bold.py-2 { "Members" }
+            (member_list)
+        }
+    ))
+}
+
+/// A page of the members sorted into their groups. The first page is the whole list, later pages
+/// replace the loader at the end of the one before.
+///
+/// Pages follow the member they were loaded after, so members joining or leaving do not shift
+/// them. Members are only moved between the online and offline groups when the list is loaded
+/// again, their status dot is updated live in the meantime.
+async fn fetch_render_member_list(
+    state: &AppState,
+    server_id: Uuid,
+    after: Option<Uuid>,
+    swap_oob: bool,
+) -> Result<Markup> {
+    // The member the page follows is loaded along with it, to know which group it ends in
+    let limit = PAGE_SIZE as i64 + 1 + i64::from(after.is_some());
+    let statuses = state.presence.online().await;
+    let online = statuses.keys().copied().collect::<Vec<_>>();
+    let mut members = query_as!(
+        Member,
+        r#"WITH members AS (
+        SELECT u.id, COALESCE(u.display_name, u.name) as name, u.avatar_small, u.status_text,
+            s.owner IS NOT DISTINCT FROM u.id as is_owner,
+            top.name as role,
+            u.id = ANY($3) as online,
+            -- Offline members come after every role, in a single group
+            CASE WHEN u.id = ANY($3) THEN COALESCE(top.permissions, -1) ELSE -2 END as rank,
+            CASE WHEN u.id = ANY($3) THEN COALESCE(top.name, '') ELSE '' END as role_key
+        FROM users_member_of_servers AS m
+        JOIN chat_users AS u ON u.id = m."user"
+        JOIN servers AS s ON s.id = m.server
+        LEFT JOIN LATERAL (
+            SELECT r.name, r.permissions
+            FROM members_have_roles AS mr
+            JOIN roles AS r ON r.id = mr.role
+            WHERE mr."user" = m."user" AND mr.server = m.server
+            ORDER BY r.permissions DESC, r.name
+            LIMIT 1
+        ) AS top ON true
+        WHERE m.server = $1
+    )
+    SELECT m.id as "id!", m.name as "name!", m.avatar_small, m.status_text as "status_text!",
+        m.is_owner as "is_owner!", m.role as "role?", m.online as "online!"
+    FROM members AS m
+    LEFT JOIN members AS c ON c.id = $2
+    WHERE $2::uuid IS NULL
+        OR m.rank < c.rank
+        OR (m.rank = c.rank AND (m.role_key, m.name, m.id) >= (c.role_key, c.name, c.id))
+    ORDER BY m.rank DESC, m.role_key, m.name, m.id
+    LIMIT $4"#,
+        server_id,
+        after,
+        &online,
+        limit,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    let previous = match after {
+        Some(after) if members.first().is_some_and(|member| member.id == after) => {
+            Some(members.remove(0))
+        }
+        _ => None,
+    };
+    let has_more = members.len() > PAGE_SIZE;
+    members.truncate(PAGE_SIZE);
+
+    let items = html!(
+        @for (i, member) in members.iter().enumerate() {
+            @let previous = match i {
+                0 => previous.as_ref(),
+                i => members.get(i - 1),
+            };
+            @if previous.map(Member::group) != Some(member.group()) {
+                li.menu-title { (member.group()) }
+            }
+            @let status = statuses.get(&member.id).copied().unwrap_or_default();
+            li {
+                button.flex.items-center.gap-2
+                    hx-get=(card_url(member.id, Some(server_id)))
+                    hx-target="#modalInner"
+                    hx-swap="outerHTML"
+                {
+                    (render_avatar(&member.name, member.avatar_small, false))
+                    div.text-left.min-w-0 {
+                        .flex.items-center.gap-1 {
+                            (render_status_dot(member.id, status, false))
+                            span.truncate.opacity-50[!member.online] { (member.name) }
+                            @if member.is_owner {
+                                span title="Owner" { "👑" }
+                            }
+                        }
+                        .text-xs.opacity-70.truncate."empty:hidden".{(status_text_class(member.id))} { (member.status_text) }
+                    }
+                }
+            }
+        }
+        @if let (Some(last), true) = (members.last(), has_more) {
+            li.loading.loading-dots.mx-auto
+                hx-trigger="intersect once"
+                hx-swap="outerHTML"
+                hx-get={"/servers/"(server_id)"/members?after="(last.id)}
+                {}
+        }
+    );
+
+    Ok(if after.is_none() {
+        html!(
+            ul.menu.menu-sm.p-0
+                #{"members-list-"(server_id)}
+                hx-swap-oob=[swap_oob.then_some("true")]
+            {
+                (items)
+            }
+        )
+    } else {
+        items
+    })
+}
+
+/// Sends the list again to the members after someone joined or left the server, it only replaces
+/// the list on the pages showing this server
+pub async fn send_member_list(state: &AppState, server_id: Uuid) -> Result<()> {
+    let members = query_scalar!(
+        r#"SELECT "user" FROM users_member_of_servers WHERE server = $1"#,
+        server_id
+    )
+    .fetch_all(&state.db)
+    .await?;
+    // The list is the same for everyone, so it is only rendered once
+    let member_list = fetch_render_member_list(state, server_id, None, true).await?;
+    for member in members {
+        state
+            .message_live
+            .send_to_user(member, member_list.clone())
+            .await;
+    }
+    Ok(())
+}
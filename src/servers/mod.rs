@@ -1,9 +1,11 @@
+use std::collections::BTreeMap;
+
 use axum::{
     extract::{Path, Query, Request, State},
     http::StatusCode,
     middleware::{from_fn_with_state, Next},
     response::IntoResponse,
-    routing, Form, Router,
+    routing, Extension, Form, Router,
 };
 use axum_htmx::HxResponseTrigger;
 use maud::{html, Markup};
@@ -20,8 +22,17 @@ use crate::{
 };
 
 pub mod channels;
+pub mod member_list;
+pub mod permissions;
+pub mod search;
 mod settings;
 
+use channels::{
+    messages::attachments,
+    unread::{activity_stream, fetch_server_unread, render_server_unread},
+};
+use permissions::{fetch_permissions, Permissions};
+
 #[derive(Deserialize)]
 pub struct ServerId {
     pub server_id: Uuid,
@@ -33,11 +44,16 @@ pub struct MaybeServerId {
 
 pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
-        .nest("/:server_id/channels", channels::router())
+        .nest("/:server_id/channels", channels::router(state.clone()))
         .route(
             "/:server_id",
             routing::get(get_chat_page).delete(delete_server),
         )
+        .route("/:server_id/search", routing::get(search::search_messages))
+        .route(
+            "/:server_id/members",
+            routing::get(member_list::get_member_list),
+        )
         .layer(from_fn_with_state(state.clone(), is_user_member_of_server))
         .nest(
             "/:server_id/settings",
@@ -45,23 +61,23 @@ pub fn router(state: AppState) -> Router<AppState> {
             settings::router(state.clone()),
         )
         .route("/", routing::get(get_servers).post(create_server))
+        .route("/activity", routing::get(activity_stream))
 }
 
+/// Also makes the member's [`Permissions`] available to handlers as an `Extension`
 async fn is_user_member_of_server(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     Path(ServerId { server_id }): Path<ServerId>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Result<impl IntoResponse> {
-    match query!(
-        r#"SELECT EXISTS(SELECT * FROM users_member_of_servers WHERE "user" = $1 AND server = $2) as "is_member!""#,
-        user_id,
-        server_id,
-    )
-    .fetch_one(&state.db).await?.is_member {
-        true => Ok(next.run(request).await),
-        false => Ok(StatusCode::UNAUTHORIZED.into_response())
+    match fetch_permissions(&state.db, user_id, server_id).await? {
+        Some(permissions) => {
+            request.extensions_mut().insert(permissions);
+            Ok(next.run(request).await)
+        }
+        None => Ok(StatusCode::UNAUTHORIZED.into_response()),
     }
 }
 
@@ -71,7 +87,7 @@ struct NewServer {
 }
 async fn create_server(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     new_server: Option<Form<NewServer>>,
 ) -> Result<impl IntoResponse> {
     fn render_new_server_form_inners() -> Markup {
@@ -99,9 +115,10 @@ async fn create_server(
 
     let new_id = Uuid::now_v7();
     let rows_affected = query!(
-        r#"INSERT INTO servers (id, name) VALUES ($1, $2)"#,
+        r#"INSERT INTO servers (id, name, owner) VALUES ($1, $2, $3)"#,
         new_id,
         new_server.name,
+        user_id,
     )
     .execute(&mut *transaction)
     .await?;
@@ -118,6 +135,19 @@ async fn create_server(
     if rows_affected.rows_affected() != 1 {
         return Err(Error::DatabaseActionFailed);
     }
+    let rows_affected = query!(
+        r#"INSERT INTO roles (id, server, name, permissions) VALUES ($1, $3, 'Admin', $4), ($2, $3, 'Moderator', $5)"#,
+        Uuid::now_v7(),
+        Uuid::now_v7(),
+        new_id,
+        Permissions::ADMIN.bits(),
+        Permissions::MODERATOR.bits(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if rows_affected.rows_affected() != 2 {
+        return Err(Error::DatabaseActionFailed);
+    }
     transaction.commit().await?;
 
     Ok((
@@ -128,8 +158,12 @@ async fn create_server(
 
 async fn delete_server(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
 ) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_SERVER)?;
+    let attachment_ids =
+        attachments::fetch_attachment_ids(&state.db, attachments::Scope::Server(server_id)).await?;
     let rows_affected = query!(r#"DELETE FROM servers WHERE id = $1"#, server_id)
         .execute(&state.db)
         .await?;
@@ -137,13 +171,14 @@ async fn delete_server(
     if rows_affected.rows_affected() != 1 {
         return Err(Error::DatabaseActionFailed);
     }
+    attachments::remove_files(state.storage.as_ref(), &attachment_ids).await;
 
     Ok(html!())
 }
 
 async fn get_servers(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     Query(MaybeServerId { server_id }): Query<MaybeServerId>,
 ) -> Result<impl IntoResponse> {
     fetch_render_server_list(&state.db, user_id, server_id).await
@@ -154,16 +189,31 @@ pub async fn fetch_render_server_list(
     active_server: Option<Uuid>,
 ) -> Result<Markup> {
     let servers = query!(
-        r#"SELECT s.id, s.name
+        r#"SELECT s.id, s.name,
+        s.owner IS NOT DISTINCT FROM m."user" as "is_owner!",
+        COALESCE(bit_or(r.permissions), 0) as "permissions!"
     FROM servers AS s
-    WHERE EXISTS (
-        SELECT * FROM users_member_of_servers 
-        WHERE "user" = $1 AND server = s.id
-    )"#,
+    JOIN users_member_of_servers AS m ON m.server = s.id
+    LEFT JOIN members_have_roles AS mr
+        ON mr."user" = m."user" AND mr.server = m.server
+    LEFT JOIN roles AS r ON r.id = mr.role
+    WHERE m."user" = $1
+    GROUP BY s.id, m."user"
+    ORDER BY s.id"#,
         user_id,
     )
     .fetch_all(pool)
     .await?;
+    let permissions: BTreeMap<_, _> = servers
+        .iter()
+        .map(|server| {
+            (
+                server.id,
+                Permissions::of_member(server.is_owner, server.permissions),
+            )
+        })
+        .collect();
+    let unread = fetch_server_unread(pool, user_id, &permissions).await?;
 
     Ok(html!(
         ul #server-list
@@ -178,10 +228,15 @@ pub async fn fetch_render_server_list(
             @for server in servers {
                 li #{"server-"(server.id)} {
                     div.active[active_server.is_some_and(|id| id == server.id)].flex {
+                        @let unread = unread.get(&server.id).copied().unwrap_or_default();
                         a.grow href={"/servers/"(server.id)} {
                             (server.name)
                         }
-                        button class="btn btn-circle btn-ghost btn-sm" hx-get={"/servers/"(server.id)"/settings"} hx-target="#modalInner" { "..." }
+                        (render_server_unread(server.id, unread, false))
+                        @let permissions = permissions[&server.id];
+                        @if permissions.intersects(Permissions::SETTINGS) {
+                            button class="btn btn-circle btn-ghost btn-sm" hx-get=(settings::settings_path(server.id, permissions)) hx-target="#modalInner" { "..." }
+                        }
                     }
                 }
             }
@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+
+use bitflags::bitflags;
+use sqlx::{query, PgPool};
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+
+bitflags! {
+    /// What a member is allowed to do in a server, stored as a bit set on each role.
+    ///
+    /// The owner of a server always has every permission.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Permissions: i64 {
+        const MANAGE_SERVER = 1 << 0;
+        const MANAGE_ROLES = 1 << 1;
+        const MANAGE_CHANNELS = 1 << 2;
+        const MANAGE_MESSAGES = 1 << 3;
+        const ADD_MEMBERS = 1 << 4;
+        const KICK_MEMBERS = 1 << 5;
+        const VIEW_CHANNEL = 1 << 6;
+        const SEND_MESSAGES = 1 << 7;
+        const BAN_MEMBERS = 1 << 8;
+
+        /// Granted to every member of a server
+        const DEFAULT = Self::VIEW_CHANNEL.bits() | Self::SEND_MESSAGES.bits();
+        /// Can be allowed or denied per channel with an [`Overwrite`]
+        const CHANNEL = Self::VIEW_CHANNEL.bits()
+            | Self::SEND_MESSAGES.bits()
+            | Self::MANAGE_MESSAGES.bits();
+        /// Any of these gives access to the server settings
+        const SETTINGS = Self::MANAGE_SERVER.bits()
+            | Self::MANAGE_ROLES.bits()
+            | Self::ADD_MEMBERS.bits()
+            | Self::KICK_MEMBERS.bits()
+            | Self::BAN_MEMBERS.bits();
+    }
+}
+
+impl Permissions {
+    pub const ADMIN: Self = Self::all();
+    pub const MODERATOR: Self = Self::MANAGE_MESSAGES
+        .union(Self::KICK_MEMBERS)
+        .union(Self::BAN_MEMBERS);
+
+    /// A member's permissions in the server, from whether they own it and the bits of all their
+    /// roles
+    pub fn of_member(is_owner: bool, role_bits: i64) -> Permissions {
+        if is_owner {
+            Permissions::all()
+        } else {
+            Permissions::from_bits_truncate(role_bits) | Permissions::DEFAULT
+        }
+    }
+
+    /// Fails with [`Error::MissingPermissions`] unless all of `needed` are granted
+    pub fn require(self, needed: Permissions) -> Result<()> {
+        if self.contains(needed) {
+            Ok(())
+        } else {
+            Err(Error::MissingPermissions {
+                needed: needed.difference(self),
+            })
+        }
+    }
+
+    /// The single permissions within `mask` with a human readable label, in display order
+    pub fn labeled(mask: Permissions) -> impl Iterator<Item = (&'static str, Permissions, String)> {
+        Self::all()
+            .iter_names()
+            .filter(move |(_, flag)| flag.bits().count_ones() == 1 && mask.contains(*flag))
+            .map(|(name, flag)| {
+                let label = name.replace('_', " ").to_lowercase();
+                let mut chars = label.chars();
+                let label = chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect())
+                    .unwrap_or_default();
+                (name, flag, label)
+            })
+    }
+}
+
+/// The permissions the user has in the server, or `None` if they are not a member
+pub async fn fetch_permissions(
+    pool: &PgPool,
+    user_id: Uuid,
+    server_id: Uuid,
+) -> Result<Option<Permissions>> {
+    let member = query!(
+        r#"SELECT
+        s.owner IS NOT DISTINCT FROM m."user" as "is_owner!",
+        COALESCE(bit_or(r.permissions), 0) as "permissions!"
+    FROM users_member_of_servers AS m
+    JOIN servers AS s ON s.id = m.server
+    LEFT JOIN members_have_roles AS mr
+        ON mr."user" = m."user" AND mr.server = m.server
+    LEFT JOIN roles AS r ON r.id = mr.role
+    WHERE m."user" = $1 AND m.server = $2
+    GROUP BY s.id, m."user"
+    "#,
+        user_id,
+        server_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(member.map(|member| Permissions::of_member(member.is_owner, member.permissions)))
+}
+
+/// A member's permissions in the channel of the request, after applying its overwrites
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelPermissions(pub Permissions);
+
+/// Channel permissions allowed or denied for everyone, a role or a single member
+#[derive(Clone, Copy)]
+pub struct Overwrite {
+    pub role: Option<Uuid>,
+    pub user: Option<Uuid>,
+    pub allow: i64,
+    pub deny: i64,
+}
+
+impl Permissions {
+    /// Applies the overwrites of a channel that concern the member to their server permissions.
+    ///
+    /// Overwrites for everyone are applied first, then those of the member's roles and last the
+    /// member's own, so the most specific one wins. Members that can manage channels are not
+    /// affected, so they can not lock themselves out.
+    pub fn with_overwrites(self, overwrites: &[Overwrite]) -> Permissions {
+        if self.contains(Permissions::MANAGE_CHANNELS) {
+            return self;
+        }
+
+        let apply = |permissions: Permissions, allow: i64, deny: i64| {
+            let allow = Permissions::from_bits_truncate(allow) & Permissions::CHANNEL;
+            let deny = Permissions::from_bits_truncate(deny) & Permissions::CHANNEL;
+            permissions.difference(deny).union(allow)
+        };
+
+        let mut permissions = self;
+        if let Some(everyone) = overwrites
+            .iter()
+            .find(|o| o.role.is_none() && o.user.is_none())
+        {
+            permissions = apply(permissions, everyone.allow, everyone.deny);
+        }
+        let (allow, deny) = overwrites
+            .iter()
+            .filter(|o| o.role.is_some())
+            .fold((0, 0), |(allow, deny), o| (allow | o.allow, deny | o.deny));
+        permissions = apply(permissions, allow, deny);
+        if let Some(member) = overwrites.iter().find(|o| o.user.is_some()) {
+            permissions = apply(permissions, member.allow, member.deny);
+        }
+        permissions
+    }
+}
+
+/// The overwrites that concern the user, of one channel or of every channel in the server, keyed
+/// by channel
+pub async fn fetch_overwrites(
+    pool: &PgPool,
+    user_id: Uuid,
+    server_id: Uuid,
+    channel_id: Option<Uuid>,
+) -> Result<BTreeMap<Uuid, Vec<Overwrite>>> {
+    let rows = query!(
+        r#"SELECT o.channel, o.role, o."user", o.allow, o.deny
+    FROM channel_overwrites AS o
+    JOIN channels AS c ON c.id = o.channel
+    WHERE c.server = $2 AND ($3::uuid IS NULL OR o.channel = $3) AND (
+        (o.role IS NULL AND o."user" IS NULL)
+        OR o."user" = $1
+        OR o.role IN (SELECT role FROM members_have_roles WHERE "user" = $1 AND server = $2)
+    )"#,
+        user_id,
+        server_id,
+        channel_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut overwrites = BTreeMap::<Uuid, Vec<Overwrite>>::new();
+    for row in rows {
+        overwrites.entry(row.channel).or_default().push(Overwrite {
+            role: row.role,
+            user: row.user,
+            allow: row.allow,
+            deny: row.deny,
+        });
+    }
+    Ok(overwrites)
+}
+
+/// The permissions in the channel, from the server permissions and the overwrites keyed by
+/// channel. Every check of what a member can do in a channel goes through here.
+pub fn apply_overwrites(
+    permissions: Permissions,
+    overwrites: &BTreeMap<Uuid, Vec<Overwrite>>,
+    channel_id: Uuid,
+) -> Permissions {
+    permissions.with_overwrites(overwrites.get(&channel_id).map_or(&[], Vec::as_slice))
+}
+
+/// The permissions the user has in the channel, or `None` if the channel is not in the server
+pub async fn fetch_channel_permissions(
+    pool: &PgPool,
+    user_id: Uuid,
+    server_id: Uuid,
+    channel_id: Uuid,
+    permissions: Permissions,
+) -> Result<Option<Permissions>> {
+    let exists = query!(
+        r#"SELECT EXISTS(SELECT * FROM channels WHERE id = $1 AND server = $2) as "exists!""#,
+        channel_id,
+        server_id,
+    )
+    .fetch_one(pool)
+    .await?
+    .exists;
+    if !exists {
+        return Ok(None);
+    }
+
+    let overwrites = fetch_overwrites(pool, user_id, server_id, Some(channel_id)).await?;
+    Ok(Some(apply_overwrites(permissions, &overwrites, channel_id)))
+}
+
+/// A member's permissions in the server along with their roles, so the overwrites of any channel
+/// can be applied to them
+pub struct MemberPermissions {
+    pub user_id: Uuid,
+    pub permissions: Permissions,
+    roles: Vec<Uuid>,
+}
+
+impl MemberPermissions {
+    /// The member's permissions in a channel, `overwrites` can be all of the channel's
+    pub fn in_channel(&self, overwrites: &[Overwrite]) -> Permissions {
+        let concerning = overwrites
+            .iter()
+            .filter(|o| match (o.role, o.user) {
+                (Some(role), _) => self.roles.contains(&role),
+                (_, Some(user)) => user == self.user_id,
+                (None, None) => true,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        self.permissions.with_overwrites(&concerning)
+    }
+}
+
+/// The permissions of those among `user_ids` that are members of the server, fetched at once
+pub async fn fetch_members_permissions(
+    pool: &PgPool,
+    server_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<Vec<MemberPermissions>> {
+    let members = query!(
+        r#"SELECT m."user",
+        s.owner IS NOT DISTINCT FROM m."user" as "is_owner!",
+        COALESCE(bit_or(r.permissions), 0) as "permissions!",
+        array_remove(array_agg(mr.role), NULL) as "roles!"
+    FROM users_member_of_servers AS m
+    JOIN servers AS s ON s.id = m.server
+    LEFT JOIN members_have_roles AS mr
+        ON mr."user" = m."user" AND mr.server = m.server
+    LEFT JOIN roles AS r ON r.id = mr.role
+    WHERE m.server = $1 AND m."user" = ANY($2)
+    GROUP BY s.id, m."user"
+    "#,
+        server_id,
+        user_ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(members
+        .into_iter()
+        .map(|member| MemberPermissions {
+            user_id: member.user,
+            permissions: Permissions::of_member(member.is_owner, member.permissions),
+            roles: member.roles,
+        })
+        .collect())
+}
+
+/// Every overwrite of every channel in the server, keyed by channel
+pub async fn fetch_server_overwrites(
+    pool: &PgPool,
+    server_id: Uuid,
+) -> Result<BTreeMap<Uuid, Vec<Overwrite>>> {
+    let rows = query!(
+        r#"SELECT o.channel, o.role, o."user", o.allow, o.deny
+    FROM channel_overwrites AS o
+    JOIN channels AS c ON c.id = o.channel
+    WHERE c.server = $1"#,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut overwrites = BTreeMap::<Uuid, Vec<Overwrite>>::new();
+    for row in rows {
+        overwrites.entry(row.channel).or_default().push(Overwrite {
+            role: row.role,
+            user: row.user,
+            allow: row.allow,
+            deny: row.deny,
+        });
+    }
+    Ok(overwrites)
+}
+
+/// The users among `user_ids` that can view the channel, resolved for all of them at once
+pub async fn fetch_channel_viewers(
+    pool: &PgPool,
+    server_id: Uuid,
+    channel_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<Vec<Uuid>> {
+    let members = fetch_members_permissions(pool, server_id, user_ids).await?;
+    let overwrites = fetch_server_overwrites(pool, server_id)
+        .await?
+        .remove(&channel_id)
+        .unwrap_or_default();
+
+    Ok(members
+        .into_iter()
+        .filter(|member| {
+            member
+                .in_channel(&overwrites)
+                .contains(Permissions::VIEW_CHANNEL)
+        })
+        .map(|member| member.user_id)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn everyone(allow: Permissions, deny: Permissions) -> Overwrite {
+        Overwrite {
+            role: None,
+            user: None,
+            allow: allow.bits(),
+            deny: deny.bits(),
+        }
+    }
+
+    fn role(allow: Permissions, deny: Permissions) -> Overwrite {
+        Overwrite {
+            role: Some(Uuid::now_v7()),
+            ..everyone(allow, deny)
+        }
+    }
+
+    fn member(allow: Permissions, deny: Permissions) -> Overwrite {
+        Overwrite {
+            user: Some(Uuid::now_v7()),
+            ..everyone(allow, deny)
+        }
+    }
+
+    #[test]
+    fn without_overwrites_nothing_changes() {
+        assert_eq!(
+            Permissions::DEFAULT.with_overwrites(&[]),
+            Permissions::DEFAULT
+        );
+    }
+
+    #[test]
+    fn most_specific_overwrite_wins() {
+        let hidden = everyone(Permissions::empty(), Permissions::VIEW_CHANNEL);
+        let role_allowed = role(Permissions::VIEW_CHANNEL, Permissions::empty());
+        let member_denied = member(Permissions::empty(), Permissions::VIEW_CHANNEL);
+
+        assert!(!Permissions::DEFAULT
+            .with_overwrites(&[hidden])
+            .contains(Permissions::VIEW_CHANNEL));
+        assert!(Permissions::DEFAULT
+            .with_overwrites(&[hidden, role_allowed])
+            .contains(Permissions::VIEW_CHANNEL));
+        assert!(!Permissions::DEFAULT
+            .with_overwrites(&[member_denied, role_allowed, hidden])
+            .contains(Permissions::VIEW_CHANNEL));
+    }
+
+    #[test]
+    fn role_allows_win_over_role_denies() {
+        let overwrites = [
+            role(Permissions::empty(), Permissions::SEND_MESSAGES),
+            role(Permissions::SEND_MESSAGES, Permissions::empty()),
+        ];
+        assert!(Permissions::DEFAULT
+            .with_overwrites(&overwrites)
+            .contains(Permissions::SEND_MESSAGES));
+    }
+
+    #[test]
+    fn only_channel_permissions_are_changed() {
+        let overwrites = [member(Permissions::all(), Permissions::empty())];
+        assert_eq!(
+            Permissions::DEFAULT.with_overwrites(&overwrites),
+            Permissions::DEFAULT | Permissions::CHANNEL
+        );
+        let overwrites = [member(Permissions::empty(), Permissions::all())];
+        assert_eq!(
+            Permissions::MODERATOR.with_overwrites(&overwrites),
+            Permissions::KICK_MEMBERS | Permissions::BAN_MEMBERS
+        );
+    }
+
+    #[test]
+    fn channel_managers_are_not_affected() {
+        let overwrites = [everyone(Permissions::empty(), Permissions::all())];
+        assert_eq!(
+            Permissions::ADMIN.with_overwrites(&overwrites),
+            Permissions::ADMIN
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension,
+};
+use chrono::{Days, NaiveDate};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::query_as;
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    utils::MyUuidExt,
+    AppState,
+};
+
+use super::{channels::fetch_visible_channels, permissions::Permissions, ServerId};
+
+/// Marks the start and end of the matches in the snippets, so the snippet can be escaped before
+/// the matches are highlighted. A stray one in a message only misplaces a highlight.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// What was typed in the search box, split into the text to look for and the filters
+#[derive(Debug, Default, PartialEq)]
+struct SearchQuery {
+    text: String,
+    /// The username or display name of the author
+    from: Option<String>,
+    channel: Option<String>,
+    before: Option<NaiveDate>,
+    after: Option<NaiveDate>,
+    has_link: bool,
+}
+
+impl SearchQuery {
+    /// Filters are written as `from:<user>`, `in:<channel>`, `before:<date>`, `after:<date>` and
+    /// `has:link`, values with spaces can be quoted. Everything else is searched for, using the
+    /// syntax of `websearch_to_tsquery` so quoted phrases and `-excluded` words work.
+    fn parse(query: &str) -> std::result::Result<Self, &'static str> {
+        let mut parsed = SearchQuery::default();
+        let mut text = Vec::new();
+        for token in tokenize(query) {
+            let Some((filter, value)) = token.split_once(':') else {
+                text.push(token);
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match filter {
+                "from" => parsed.from = Some(value.to_owned()),
+                "in" => parsed.channel = Some(value.trim_start_matches('#').to_owned()),
+                "before" => parsed.before = Some(parse_date(value)?),
+                "after" => parsed.after = Some(parse_date(value)?),
+                "has" if value == "link" => parsed.has_link = true,
+                "has" => return Err("Only has:link is supported"),
+                _ => text.push(token),
+            }
+        }
+        parsed.text = text.join(" ");
+        Ok(parsed)
+    }
+
+    fn is_empty(&self) -> bool {
+        self == &SearchQuery::default()
+    }
+}
+
+/// Splits on whitespace outside of double quotes, keeping the quotes
+fn tokenize(query: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in query.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&query[start..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        tokens.push(&query[start..]);
+    }
+    tokens
+}
+
+fn parse_date(value: &str) -> std::result::Result<NaiveDate, &'static str> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| "Dates are written as YYYY-MM-DD")
+}
+
+/// The smallest v7 uuid created on the day, so messages can be filtered by date through their ids
+fn first_id_of_day(date: NaiveDate) -> Uuid {
+    let millis = date
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight to be a valid time")
+        .and_utc()
+        .timestamp_millis();
+    uuid::Builder::from_unix_timestamp_millis(millis as u64, &[0; 10]).into_uuid()
+}
+
+struct SearchResult {
+    id: Uuid,
+    channel: Uuid,
+    channel_name: String,
+    thread: Option<Uuid>,
+    author_name: String,
+    snippet: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct SearchOpts {
+    q: String,
+    /// Loads the results older than this message
+    before: Option<Uuid>,
+}
+/// Searches the messages of the channels in the server the user can see, newest first
+pub(super) async fn search_messages(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Query(SearchOpts { q, before }): Query<SearchOpts>,
+) -> Result<impl IntoResponse> {
+    let query = match SearchQuery::parse(&q) {
+        Ok(query) if query.is_empty() => {
+            return Ok(render_search_panel(
+                &q,
+                html!(li.italic.opacity-50 {
+                    "Type something to search for, or filter with from:, in:, before:, after: and has:link"
+                }),
+            ));
+        }
+        Ok(query) => query,
+        Err(message) => return Ok(render_search_panel(&q, html!(li.text-error { (message) }))),
+    };
+
+    let channels = fetch_visible_channels(&state.db, server_id, user_id, permissions)
+        .await?
+        .into_iter()
+        .map(|channel| channel.id)
+        .collect::<Vec<_>>();
+    // Both are upper bounds, paging only ever goes further back
+    let older_than = [query.before.map(first_id_of_day), before]
+        .into_iter()
+        .flatten()
+        .min();
+    let newer_than = query
+        .after
+        .and_then(|after| after.checked_add_days(Days::new(1)))
+        .map(first_id_of_day);
+    let headline_options = format!(
+        "StartSel={MATCH_START}, StopSel={MATCH_END}, MaxFragments=2, MinWords=5, MaxWords=20"
+    );
+
+    let results = query_as!(
+        SearchResult,
+        r#"SELECT m.id, m.channel, c.name as channel_name, m.thread, COALESCE(u.display_name, u.name) as "author_name!",
+        ts_headline('english', m.content, q.query, $8) as "snippet!"
+      FROM messages AS m
+      JOIN channels AS c ON c.id = m.channel
+      JOIN chat_users AS u ON u.id = m.author
+      CROSS JOIN websearch_to_tsquery('english', $2) AS q(query)
+      WHERE m.channel = ANY($1)
+        AND ($2 = '' OR m.search @@ q.query)
+        AND ($3::text IS NULL OR lower(u.name) = lower($3) OR lower(u.display_name) = lower($3))
+        AND ($4::text IS NULL OR lower(c.name) = lower($4))
+        AND ($5::uuid IS NULL OR m.id < $5)
+        AND ($6::uuid IS NULL OR m.id >= $6)
+        AND (NOT $7 OR m.content ~* 'https?://')
+      ORDER BY m.id DESC
+      LIMIT 25"#,
+        &channels,
+        query.text,
+        query.from,
+        query.channel,
+        older_than,
+        newer_than,
+        query.has_link,
+        headline_options,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(match before {
+        Some(_) => render_results(&results, server_id)?,
+        None if results.is_empty() => {
+            render_search_panel(&q, html!(li.italic.opacity-50 { "No messages found" }))
+        }
+        None => render_search_panel(&q, render_results(&results, server_id)?),
+    })
+}
+
+/// Shown in the side panel of the chat page, the query is kept so more results can be loaded
+fn render_search_panel(query: &str, results: Markup) -> Markup {
+    html!(
+        aside #search-panel.grid.w-96.gap-2.border-l.border-base-300.pl-2
+            style="grid-template-rows: auto minmax(0,1fr)"
+        {
+            .flex.items-center.justify-between {
+                h2.font-bold { "Search results" }
+                input #search-query type="hidden" name="q" value=(query);
+                button.btn.btn-circle.btn-ghost.btn-sm
+                    "hx-on:click"="htmx.remove('#search-panel')"
+                    aria-label="close"
+                    { "✕" }
+            }
+            ol.flex.flex-col.gap-2.overflow-y-auto {
+                (results)
+            }
+        }
+    )
+}
+
+fn render_results(results: &[SearchResult], server_id: Uuid) -> Result<Markup> {
+    Ok(html!(
+        @for result in results {
+            @let created_at = result.id.get_datetime().ok_or(Error::NoTimestampFromUuid { id: result.id })?;
+            li {
+                // Messages in a thread are found through their parent in the channel
+                a class="block rounded bg-base-200 p-2 text-sm hover:bg-base-300"
+                    href={"/servers/"(server_id)"/channels/"(result.channel)"?around="(result.id)"#msg-"(result.thread.unwrap_or(result.id))}
+                    hx-boost="false"
+                {
+                    .flex.items-center.gap-2.text-xs {
+                        span.font-bold { (result.author_name) }
+                        span.opacity-70 {
+                            "#" (result.channel_name)
+                            @if result.thread.is_some() { " (thread)" }
+                        }
+                        relative-time.ml-auto.opacity-50 datetime=(created_at.to_rfc3339()) {
+                            (created_at.to_rfc2822())
+                        }
+                    }
+                    p.break-words { (render_snippet(&result.snippet)) }
+                }
+            }
+        }
+        @if let (Some(last), 25) = (results.last(), results.len()) {
+            li.loading.loading-dots.mx-auto
+                hx-trigger="intersect once"
+                hx-swap="outerHTML"
+                hx-get={"/servers/"(server_id)"/search?before="(last.id)}
+                hx-include="#search-query"
+                {}
+        }
+    ))
+}
+
+/// Escapes the snippet and highlights the matches in it
+fn render_snippet(snippet: &str) -> Markup {
+    html!(
+        @for (i, part) in snippet.split(MATCH_START).enumerate() {
+            @if i == 0 {
+                (part)
+            } @else {
+                @let (matched, rest) = part.split_once(MATCH_END).unwrap_or((part, ""));
+                mark { (matched) }
+                (rest)
+            }
+        }
+    )
+}
+
+pub fn render_search_form(server_id: Uuid) -> Markup {
+    html!(
+        form.flex.gap-2
+            hx-get={"/servers/"(server_id)"/search"}
+            hx-target="#thread-wrapper"
+            hx-swap="innerHTML"
+        {
+            input.input.input-bordered.input-sm.grow type="search" name="q"
+                placeholder="Search, e.g. from:alice in:general has:link"
+                aria-label="Search messages";
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_filters_from_text() {
+        let query = SearchQuery::parse(
+            r#"deploy from:"Jane Doe" in:#general "exact phrase" -wip has:link"#,
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            SearchQuery {
+                text: r#"deploy "exact phrase" -wip"#.to_owned(),
+                from: Some("Jane Doe".to_owned()),
+                channel: Some("general".to_owned()),
+                has_link: true,
+                ..SearchQuery::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_dates() {
+        let query = SearchQuery::parse("after:2024-01-31 before:2024-02-01").unwrap();
+        assert_eq!(query.after, NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(query.before, NaiveDate::from_ymd_opt(2024, 2, 1));
+        assert!(query.text.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(SearchQuery::parse("before:yesterday").is_err());
+        assert!(SearchQuery::parse("has:image").is_err());
+    }
+
+    #[test]
+    fn unknown_filters_are_searched_for() {
+        let query = SearchQuery::parse("time: 10:30").unwrap();
+        assert_eq!(query.text, "time: 10:30");
+    }
+
+    #[test]
+    fn empty_query() {
+        assert!(SearchQuery::parse("  ").unwrap().is_empty());
+        assert!(!SearchQuery::parse("has:link").unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_id_of_day_orders_by_date() {
+        let day = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let next_day = day.checked_add_days(Days::new(1)).unwrap();
+        let during_day = uuid::Builder::from_unix_timestamp_millis(
+            day.and_hms_opt(12, 0, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp_millis() as u64,
+            &[0xff; 10],
+        )
+        .into_uuid();
+        assert!(first_id_of_day(day) < during_day);
+        assert!(during_day < first_id_of_day(next_day));
+    }
+}
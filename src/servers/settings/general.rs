@@ -1,7 +1,7 @@
 use axum::{
     extract::{Path, State},
     response::IntoResponse,
-    routing, Form, Router,
+    routing, Extension, Form, Router,
 };
 use axum_htmx::HxResponseTrigger;
 use maud::{html, Markup};
@@ -15,15 +15,15 @@ use crate::{
     AppState,
 };
 
-use super::{render_settings_nav, ServerId, SettingsTab};
+use super::{render_settings_nav, Permissions, ServerId, SettingsTab};
 
 pub fn router() -> Router<AppState> {
     Router::new().route("/", routing::get(open_general_page).put(update_server))
 }
 
-fn render_form(server_id: Uuid) -> Markup {
+fn render_form(server_id: Uuid, permissions: Permissions) -> Markup {
     base_modal(html!(
-        (render_settings_nav(server_id, SettingsTab::General))
+        (render_settings_nav(server_id, SettingsTab::General, permissions))
         form hx-put={"/servers/"(server_id)"/settings"} {
             label class="form-control m-auto w-full max-w-xs" {
                 .label { .label-text { "Server name" } }
@@ -43,11 +43,16 @@ fn render_form(server_id: Uuid) -> Markup {
     ))
 }
 
-async fn open_general_page(Path(ServerId { server_id }): Path<ServerId>) -> impl IntoResponse {
-    (
+async fn open_general_page(
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_SERVER)?;
+
+    Ok((
         HxResponseTrigger::normal(["open-main-modal"]),
-        render_form(server_id),
-    )
+        render_form(server_id, permissions),
+    ))
 }
 
 #[derive(Deserialize)]
@@ -56,9 +61,12 @@ struct UpdatedServer {
 }
 async fn update_server(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
     Form(updated_server): Form<UpdatedServer>,
 ) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_SERVER)?;
+
     let mut transaction = state.db.begin().await?;
     let rows_affected = query!(
         r#"UPDATE servers SET name = $1 WHERE id = $2"#,
@@ -74,6 +82,6 @@ async fn update_server(
 
     Ok((
         HxResponseTrigger::normal(["get-server-list"]),
-        render_form(server_id),
+        render_form(server_id, permissions),
     ))
 }
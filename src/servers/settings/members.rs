@@ -1,22 +1,31 @@
 use axum::{
     extract::{Path, State},
     response::IntoResponse,
-    routing, Form, Router,
+    routing, Extension, Form, Router,
 };
 use axum_htmx::HxResponseTrigger;
+use chrono::Utc;
 use maud::{html, Markup};
 use serde::Deserialize;
-use sqlx::{query, PgPool};
+use sqlx::{query, query_scalar, PgPool};
 use uuid::Uuid;
 
 use crate::{
     auth::Auth,
     base_modal,
     error::{Error, Result},
+    servers::member_list::send_member_list,
+    users::{
+        avatars::render_avatar,
+        cards::card_url,
+        presence::{render_status_dot, status_text_class},
+    },
     AppState,
 };
 
-use super::{render_settings_nav, ServerId, SettingsTab};
+use super::{
+    fetch_permissions, render_settings_nav, Permissions, ServerId, SettingsTab, MEMBER_PERMISSIONS,
+};
 
 #[derive(Deserialize)]
 struct MemberId {
@@ -27,30 +36,54 @@ pub fn router() -> Router<AppState> {
     Router::new()
         .route("/", routing::get(open_member_page).post(add_member))
         .route("/:member_id", routing::delete(remove_member))
+        .route(
+            "/:member_id/ban",
+            routing::post(ban_member).delete(unban_member),
+        )
         .route("/table", routing::get(get_member_table))
+        .route("/bans", routing::get(get_ban_table))
 }
 
 async fn open_member_page(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
 ) -> Result<impl IntoResponse> {
+    if !permissions.intersects(MEMBER_PERMISSIONS) {
+        return Err(Error::MissingPermissions {
+            needed: MEMBER_PERMISSIONS,
+        });
+    }
+
     Ok((
         HxResponseTrigger::normal(["open-main-modal"]),
-        fetch_render_members_page(&state.db, server_id, user_id).await?,
+        fetch_render_members_page(&state, server_id, user_id, permissions).await?,
     ))
 }
 async fn fetch_render_members_page(
-    pool: &PgPool,
+    state: &AppState,
     server_id: Uuid,
     user_id: Uuid,
+    permissions: Permissions,
 ) -> Result<Markup> {
-    let member_table = fetch_render_member_table(pool, server_id, user_id).await?;
+    let member_table = fetch_render_member_table(state, server_id, user_id, permissions).await?;
+    let ban_table = if permissions.contains(Permissions::BAN_MEMBERS) {
+        Some(fetch_render_ban_table(&state.db, server_id).await?)
+    } else {
+        None
+    };
 
     Ok(base_modal(html! {
-        (render_settings_nav(server_id, SettingsTab::Members))
-        (render_add_member_form(server_id))
+        (render_settings_nav(server_id, SettingsTab::Members, permissions))
+        @if permissions.contains(Permissions::ADD_MEMBERS) {
+            (render_add_member_form(server_id))
+        }
         (member_table)
+        @if let Some(ban_table) = ban_table {
+            h3.font-bold.mt-4 { "Bans" }
+            (ban_table)
+        }
     }))
 }
 
@@ -60,10 +93,23 @@ struct AddMember {
 }
 async fn add_member(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
     add_member: Option<Form<AddMember>>,
 ) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::ADD_MEMBERS)?;
+
     if let Some(Form(add_member)) = add_member {
+        let banned = query_scalar!(
+            r#"SELECT EXISTS(SELECT * FROM server_bans WHERE server = $1 AND "user" = $2) as "banned!""#,
+            server_id,
+            add_member.id,
+        )
+        .fetch_one(&state.db)
+        .await?;
+        if banned {
+            return Err(Error::UserBanned);
+        }
         let rows_affected = query!(
             r#"INSERT INTO users_member_of_servers ("user", server) VALUES ($1, $2)"#,
             add_member.id,
@@ -74,6 +120,7 @@ async fn add_member(
         if rows_affected.rows_affected() != 1 {
             return Err(Error::DatabaseActionFailed);
         }
+        send_member_list(&state, server_id).await?;
     }
     Ok((
         HxResponseTrigger::normal(["update-member-table"]),
@@ -83,12 +130,21 @@ async fn add_member(
 
 async fn remove_member(
     State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
     Path(MemberId { member_id }): Path<MemberId>,
 ) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::KICK_MEMBERS)?;
+    // Members can not kick someone with permissions they do not have themselves
+    if let Some(member_permissions) = fetch_permissions(&state.db, member_id, server_id).await? {
+        permissions.require(member_permissions)?;
+    }
+
     let mut transaction = state.db.begin().await?;
     let rows_affected = query!(
-        r#"DELETE FROM users_member_of_servers WHERE "user" = $1 AND server = $2"#,
+        r#"DELETE FROM users_member_of_servers AS m
+        WHERE m."user" = $1 AND m.server = $2
+            AND NOT EXISTS (SELECT * FROM servers WHERE id = m.server AND owner = m."user")"#,
         member_id,
         server_id,
     )
@@ -98,6 +154,80 @@ async fn remove_member(
         return Err(Error::DatabaseActionFailed);
     }
     transaction.commit().await?;
+    state
+        .message_live
+        .close_user_streams(member_id, server_id)
+        .await;
+    send_member_list(&state, server_id).await?;
+
+    Ok(html!())
+}
+
+/// Removes the member from the server and keeps them from being added back
+async fn ban_member(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(MemberId { member_id }): Path<MemberId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::BAN_MEMBERS)?;
+    // The same as for kicking, and the owner can not be banned at all
+    if let Some(member_permissions) = fetch_permissions(&state.db, member_id, server_id).await? {
+        permissions.require(member_permissions)?;
+    }
+
+    let mut transaction = state.db.begin().await?;
+    let rows_affected = query!(
+        r#"INSERT INTO server_bans (server, "user", created)
+        SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT * FROM servers WHERE id = $1 AND owner = $2)
+        ON CONFLICT DO NOTHING"#,
+        server_id,
+        member_id,
+        Utc::now().naive_utc(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+    query!(
+        r#"DELETE FROM users_member_of_servers WHERE "user" = $1 AND server = $2"#,
+        member_id,
+        server_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    state
+        .message_live
+        .close_user_streams(member_id, server_id)
+        .await;
+    send_member_list(&state, server_id).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["update-member-table", "update-ban-table"]),
+        html!(),
+    ))
+}
+
+async fn unban_member(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(MemberId { member_id }): Path<MemberId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::BAN_MEMBERS)?;
+
+    let rows_affected = query!(
+        r#"DELETE FROM server_bans WHERE server = $1 AND "user" = $2"#,
+        server_id,
+        member_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
 
     Ok(html!())
 }
@@ -125,27 +255,40 @@ fn render_add_member_form(server_id: Uuid) -> Markup {
 
 async fn get_member_table(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
+    Extension(permissions): Extension<Permissions>,
     Path(ServerId { server_id }): Path<ServerId>,
 ) -> impl IntoResponse {
-    fetch_render_member_table(&state.db, server_id, user_id).await
+    fetch_render_member_table(&state, server_id, user_id, permissions).await
 }
 async fn fetch_render_member_table(
-    pool: &PgPool,
+    state: &AppState,
     server_id: Uuid,
     user_id: Uuid,
+    permissions: Permissions,
 ) -> Result<Markup> {
     let members = query!(
-        r#"SELECT u.id, u.name 
+        r#"SELECT u.id, u.name, u.display_name, u.avatar_small, u.status_text,
+        s.owner IS NOT DISTINCT FROM u.id as "is_owner!",
+        COALESCE(array_agg(r.name ORDER BY r.name) FILTER (WHERE r.id IS NOT NULL), '{}') as "roles!"
     FROM chat_users as u
     JOIN users_member_of_servers AS m 
         ON u.id = m."user"
+    JOIN servers AS s ON s.id = m.server
+    LEFT JOIN members_have_roles AS mr
+        ON mr."user" = m."user" AND mr.server = m.server
+    LEFT JOIN roles AS r ON r.id = mr.role
     WHERE m.server = $1 
+    GROUP BY u.id, s.owner
     "#,
         server_id
     )
-    .fetch_all(pool)
+    .fetch_all(&state.db)
     .await?;
+    let statuses = state
+        .presence
+        .statuses(members.iter().map(|member| member.id).collect())
+        .await;
 
     Ok(html!(
         table class="table"
@@ -157,25 +300,117 @@ async fn fetch_render_member_table(
             thead {
                 tr {
                     th { "name" }
+                    th { "roles" }
                     th {}
                 }
             }
             tbody {
                 @for member in members {
                     tr {
-                        td { (member.name) }
+                        @let display_name = member.display_name.as_deref().unwrap_or(&member.name);
                         td {
-                            @if member.id != user_id {
-                                button class="link link-error"
-                                    hx-delete={"/servers/"(server_id)"/settings/members/"(member.id)}
-                                    hx-target="closest tr"
-                                    { "Remove" }
-                            } @else {
+                            button.flex.items-center.gap-2.text-left
+                                hx-get=(card_url(member.id, Some(server_id)))
+                                hx-target="#modalInner"
+                                title="Show profile"
+                            {
+                                (render_avatar(display_name, member.avatar_small, false))
+                                div {
+                                    .flex.items-center.gap-2 {
+                                        (render_status_dot(member.id, statuses.get(&member.id).copied().unwrap_or_default(), false))
+                                        (display_name)
+                                    }
+                                    @if member.display_name.is_some() {
+                                        .text-xs.opacity-50 { (member.name) }
+                                    }
+                                    .opacity-70.text-xs."empty:hidden".{(status_text_class(member.id))} { (member.status_text) }
+                                }
+                            }
+                        }
+                        td {
+                            @if member.is_owner {
+                                span.badge.badge-primary.mr-1 { "Owner" }
+                            }
+                            @for role in &member.roles {
+                                span.badge.mr-1 { (role) }
+                            }
+                        }
+                        td {
+                            @if member.id == user_id {
                                 .italic.opacity-50 { "You" }
+                            } @else if !member.is_owner {
+                                @if permissions.contains(Permissions::KICK_MEMBERS) {
+                                    button class="link link-error mr-2"
+                                        hx-delete={"/servers/"(server_id)"/settings/members/"(member.id)}
+                                        hx-target="closest tr"
+                                        { "Remove" }
+                                }
+                                @if permissions.contains(Permissions::BAN_MEMBERS) {
+                                    button class="link link-error"
+                                        hx-post={"/servers/"(server_id)"/settings/members/"(member.id)"/ban"}
+                                        hx-confirm={"Ban " (display_name) "? They will not be able to be added back until unbanned."}
+                                        hx-swap="none"
+                                        { "Ban" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    ))
+}
+
+async fn get_ban_table(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::BAN_MEMBERS)?;
+    fetch_render_ban_table(&state.db, server_id).await
+}
+async fn fetch_render_ban_table(pool: &PgPool, server_id: Uuid) -> Result<Markup> {
+    let bans = query!(
+        r#"SELECT u.id, u.name, b.created
+    FROM server_bans AS b
+    JOIN chat_users AS u ON u.id = b."user"
+    WHERE b.server = $1
+    ORDER BY b.created DESC"#,
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        table class="table"
+            hx-get={"/servers/"(server_id)"/settings/members/bans"}
+            hx-trigger="update-ban-table from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            tbody {
+                @for ban in &bans {
+                    tr {
+                        td { (ban.name) }
+                        td {
+                            span.text-xs.opacity-70 {
+                                "Banned "
+                                relative-time datetime=(ban.created.and_utc().to_rfc3339()) {
+                                    (ban.created.and_utc().to_rfc2822())
+                                }
                             }
                         }
+                        td {
+                            button class="link"
+                                hx-delete={"/servers/"(server_id)"/settings/members/"(ban.id)"/ban"}
+                                hx-target="closest tr"
+                                { "Unban" }
+                        }
                     }
                 }
+                @if bans.is_empty() {
+                    tr { td.italic.opacity-50 colspan="3" { "Nobody is banned" } }
+                }
             }
         }
     ))
@@ -6,39 +6,63 @@ use axum::{
     Router,
 };
 use maud::{html, Markup};
-use sqlx::query;
 use uuid::Uuid;
 
-use crate::{auth::Auth, error::Result, AppState};
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    AppState,
+};
 
-use super::ServerId;
+use super::{
+    permissions::{fetch_permissions, Permissions},
+    ServerId,
+};
 
 mod general;
 mod members;
+mod roles;
+
+const MEMBER_PERMISSIONS: Permissions = Permissions::ADD_MEMBERS
+    .union(Permissions::KICK_MEMBERS)
+    .union(Permissions::BAN_MEMBERS);
 
 pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
         .nest("/", general::router())
         .nest("/members", members::router())
+        .nest("/roles", roles::router())
         .layer(from_fn_with_state(state.clone(), is_allowed_to_edit_server))
 }
 
+/// Also makes the member's [`Permissions`] available to handlers as an `Extension`
 async fn is_allowed_to_edit_server(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     Path(ServerId { server_id }): Path<ServerId>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Result<impl IntoResponse> {
-    // FIXME: Check for edit rights
-    match query!(
-        r#"SELECT EXISTS(SELECT * FROM users_member_of_servers WHERE "user" = $1 AND server = $2) as "is_member!""#,
-        user_id,
-        server_id,
-    )
-    .fetch_one(&state.db).await?.is_member {
-        true => Ok(next.run(request).await),
-        false => Ok(StatusCode::UNAUTHORIZED.into_response())
+    match fetch_permissions(&state.db, user_id, server_id).await? {
+        Some(permissions) if permissions.intersects(Permissions::SETTINGS) => {
+            request.extensions_mut().insert(permissions);
+            Ok(next.run(request).await)
+        }
+        Some(permissions) => Err(Error::MissingPermissions {
+            needed: Permissions::SETTINGS.difference(permissions),
+        }),
+        None => Ok(StatusCode::UNAUTHORIZED.into_response()),
+    }
+}
+
+/// The first settings tab the member is allowed to open
+pub fn settings_path(server_id: Uuid, permissions: Permissions) -> String {
+    if permissions.contains(Permissions::MANAGE_SERVER) {
+        format!("/servers/{server_id}/settings")
+    } else if permissions.intersects(MEMBER_PERMISSIONS) {
+        format!("/servers/{server_id}/settings/members")
+    } else {
+        format!("/servers/{server_id}/settings/roles")
     }
 }
 
@@ -46,13 +70,21 @@ async fn is_allowed_to_edit_server(
 enum SettingsTab {
     General,
     Members,
+    Roles,
 }
-fn render_settings_nav(server_id: Uuid, active: SettingsTab) -> Markup {
+fn render_settings_nav(server_id: Uuid, active: SettingsTab, permissions: Permissions) -> Markup {
     use SettingsTab::*;
     html!(
         div class="tabs-boxed tabs" {
-            button.tab.tab-active[active == General] hx-get={"/servers/"(server_id)"/settings"} { "General" }
-            button.tab.tab-active[active == Members] hx-get={"/servers/"(server_id)"/settings/members"} { "Members" }
+            @if permissions.contains(Permissions::MANAGE_SERVER) {
+                button.tab.tab-active[active == General] hx-get={"/servers/"(server_id)"/settings"} { "General" }
+            }
+            @if permissions.intersects(MEMBER_PERMISSIONS) {
+                button.tab.tab-active[active == Members] hx-get={"/servers/"(server_id)"/settings/members"} { "Members" }
+            }
+            @if permissions.contains(Permissions::MANAGE_ROLES) {
+                button.tab.tab-active[active == Roles] hx-get={"/servers/"(server_id)"/settings/roles"} { "Roles" }
+            }
         }
     )
 }
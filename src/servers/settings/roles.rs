@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing, Extension, Form, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    base_modal,
+    error::{Error, Result},
+    AppState,
+};
+
+use super::{render_settings_nav, Permissions, ServerId, SettingsTab};
+
+#[derive(Deserialize)]
+struct RoleId {
+    role_id: Uuid,
+}
+#[derive(Deserialize)]
+struct MemberId {
+    member_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_roles_page).post(create_role))
+        .route("/list", routing::get(get_roles_list))
+        .route("/:role_id", routing::put(update_role).delete(delete_role))
+        .route("/:role_id/members", routing::post(assign_role))
+        .route(
+            "/:role_id/members/:member_id",
+            routing::delete(unassign_role),
+        )
+}
+
+async fn open_roles_page(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+
+    let roles_list = fetch_render_roles_list(&state.db, server_id, permissions).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html! {
+            (render_settings_nav(server_id, SettingsTab::Roles, permissions))
+            (render_new_role_form(server_id))
+            (roles_list)
+        }),
+    ))
+}
+
+/// Permissions are sent as one checkbox per permission, named after the flag
+#[derive(Deserialize)]
+struct RoleForm {
+    name: String,
+    #[serde(flatten)]
+    permissions: HashMap<String, String>,
+}
+impl RoleForm {
+    fn permissions(&self) -> Permissions {
+        self.permissions
+            .keys()
+            .filter_map(|name| Permissions::from_name(name))
+            .fold(Permissions::empty(), Permissions::union)
+    }
+}
+
+#[derive(Deserialize)]
+struct NewRole {
+    name: String,
+}
+async fn create_role(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Form(new_role): Form<NewRole>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+
+    let rows_affected = query!(
+        r#"INSERT INTO roles (id, server, name, permissions) VALUES ($1, $2, $3, 0)"#,
+        Uuid::now_v7(),
+        server_id,
+        new_role.name,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-roles-list"]),
+        render_new_role_form(server_id),
+    ))
+}
+
+async fn update_role(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(RoleId { role_id }): Path<RoleId>,
+    Form(role_form): Form<RoleForm>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+    let role_permissions = fetch_role_permissions(&state.db, server_id, role_id).await?;
+    // Members can only hand out, or take away, permissions they have themselves
+    permissions.require(role_permissions)?;
+    permissions.require(role_form.permissions())?;
+
+    let rows_affected = query!(
+        r#"UPDATE roles SET name = $1, permissions = $2 WHERE id = $3 AND server = $4"#,
+        role_form.name,
+        role_form.permissions().bits(),
+        role_id,
+        server_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-roles-list", "update-member-table"]),
+        html!(),
+    ))
+}
+
+async fn delete_role(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(RoleId { role_id }): Path<RoleId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+    permissions.require(fetch_role_permissions(&state.db, server_id, role_id).await?)?;
+
+    let rows_affected = query!(
+        r#"DELETE FROM roles WHERE id = $1 AND server = $2"#,
+        role_id,
+        server_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok(html!())
+}
+
+#[derive(Deserialize)]
+struct AssignRole {
+    member: Uuid,
+}
+async fn assign_role(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(RoleId { role_id }): Path<RoleId>,
+    Form(assign): Form<AssignRole>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+    permissions.require(fetch_role_permissions(&state.db, server_id, role_id).await?)?;
+
+    let rows_affected = query!(
+        r#"INSERT INTO members_have_roles ("user", server, role) VALUES ($1, $2, $3)"#,
+        assign.member,
+        server_id,
+        role_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-roles-list", "update-member-table"]),
+        html!(),
+    ))
+}
+
+async fn unassign_role(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+    Path(RoleId { role_id }): Path<RoleId>,
+    Path(MemberId { member_id }): Path<MemberId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+    permissions.require(fetch_role_permissions(&state.db, server_id, role_id).await?)?;
+
+    let rows_affected = query!(
+        r#"DELETE FROM members_have_roles WHERE "user" = $1 AND server = $2 AND role = $3"#,
+        member_id,
+        server_id,
+        role_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok((HxResponseTrigger::normal(["update-member-table"]), html!()))
+}
+
+async fn fetch_role_permissions(
+    pool: &PgPool,
+    server_id: Uuid,
+    role_id: Uuid,
+) -> Result<Permissions> {
+    let role = query!(
+        r#"SELECT permissions FROM roles WHERE id = $1 AND server = $2"#,
+        role_id,
+        server_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(Permissions::from_bits_truncate(role.permissions))
+}
+
+fn render_new_role_form(server_id: Uuid) -> Markup {
+    html!(
+        form
+            class="flex items-end"
+            hx-post={"/servers/"(server_id)"/settings/roles"}
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            .form-control.grow {
+                .label {
+                    .label-text {
+                        "Role name"
+                    }
+                }
+                input type="text" name="name" class="input input-bordered w-full" required;
+            }
+            button type="submit" class="btn btn-primary" { "Create role" }
+        }
+    )
+}
+
+async fn get_roles_list(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(ServerId { server_id }): Path<ServerId>,
+) -> Result<impl IntoResponse> {
+    permissions.require(Permissions::MANAGE_ROLES)?;
+    fetch_render_roles_list(&state.db, server_id, permissions).await
+}
+async fn fetch_render_roles_list(
+    pool: &PgPool,
+    server_id: Uuid,
+    permissions: Permissions,
+) -> Result<Markup> {
+    let roles = query!(
+        r#"SELECT id, name, permissions FROM roles WHERE server = $1 ORDER BY id"#,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let members = query!(
+        r#"SELECT u.id, u.name,
+        COALESCE(array_agg(mr.role) FILTER (WHERE mr.role IS NOT NULL), '{}') as "roles!"
+    FROM chat_users AS u
+    JOIN users_member_of_servers AS m ON m."user" = u.id
+    LEFT JOIN members_have_roles AS mr
+        ON mr."user" = m."user" AND mr.server = m.server
+    WHERE m.server = $1
+    GROUP BY u.id
+    ORDER BY u.name"#,
+        server_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        #roles-list.flex.flex-col.gap-2.mt-4
+            hx-get={"/servers/"(server_id)"/settings/roles/list"}
+            hx-trigger="update-roles-list from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            @for role in roles {
+                @let role_permissions = Permissions::from_bits_truncate(role.permissions);
+                @let can_edit = permissions.contains(role_permissions);
+                @let role_url = format!("/servers/{server_id}/settings/roles/{}", role.id);
+                .collapse.collapse-arrow.bg-base-200 {
+                    input type="checkbox";
+                    .collapse-title.font-medium { (role.name) }
+                    .collapse-content {
+                        form.flex.flex-col.gap-2 hx-put=(role_url) hx-swap="none" {
+                            input type="text" name="name" class="input input-bordered input-sm" value=(role.name) disabled[!can_edit];
+                            @for (flag_name, flag, label) in Permissions::labeled(Permissions::all().difference(Permissions::DEFAULT)) {
+                                label.label.cursor-pointer.justify-start.gap-2 {
+                                    input type="checkbox" class="checkbox checkbox-sm"
+                                        name=(flag_name)
+                                        checked[role_permissions.contains(flag)]
+                                        disabled[!can_edit || !permissions.contains(flag)];
+                                    span.label-text { (label) }
+                                }
+                            }
+                            @if can_edit {
+                                .flex.justify-end.gap-2 {
+                                    button type="button" class="btn btn-error btn-sm"
+                                        hx-delete=(role_url)
+                                        hx-confirm={"Are you sure you want to delete '"(role.name)"'?"}
+                                        hx-target="closest .collapse"
+                                        hx-swap="outerHTML"
+                                        { "Delete" }
+                                    button type="submit" class="btn btn-primary btn-sm" { "Save" }
+                                }
+                            }
+                        }
+                        .divider.my-1 { "Members" }
+                        .flex.flex-wrap.gap-1 {
+                            @for member in members.iter().filter(|m| m.roles.contains(&role.id)) {
+                                span.badge.gap-1 {
+                                    (member.name)
+                                    @if can_edit {
+                                        button
+                                            hx-delete={(role_url)"/members/"(member.id)}
+                                            hx-target="closest .badge"
+                                            hx-swap="outerHTML"
+                                            { "✕" }
+                                    }
+                                }
+                            }
+                        }
+                        @if can_edit {
+                            form.flex.items-end.gap-2.mt-2 hx-post={(role_url)"/members"} hx-swap="none" {
+                                select name="member" class="select select-bordered select-sm grow" required {
+                                    @for member in members.iter().filter(|m| !m.roles.contains(&role.id)) {
+                                        option value=(member.id) { (member.name) }
+                                    }
+                                }
+                                button type="submit" class="btn btn-sm" { "Assign" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    ))
+}
@@ -0,0 +1,51 @@
+use std::{fmt::Debug, future::Future, io, path::PathBuf, pin::Pin};
+
+use axum::body::Bytes;
+use uuid::Uuid;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where uploaded files are kept, addressed by the id of the row that describes them
+pub trait Storage: Debug + Send + Sync {
+    fn put(&self, id: Uuid, data: Bytes) -> BoxFuture<'_, io::Result<()>>;
+    fn get(&self, id: Uuid) -> BoxFuture<'_, io::Result<Bytes>>;
+    /// Deleting a file that does not exist is not an error
+    fn delete(&self, id: Uuid) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Stores files in a directory on the local filesystem, the default backend
+#[derive(Debug)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, id: Uuid, data: Bytes) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(tokio::fs::write(self.path(id), data))
+    }
+
+    fn get(&self, id: Uuid) -> BoxFuture<'_, io::Result<Bytes>> {
+        Box::pin(async move { tokio::fs::read(self.path(id)).await.map(Bytes::from) })
+    }
+
+    fn delete(&self, id: Uuid) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(id)).await {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        })
+    }
+}
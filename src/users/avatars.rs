@@ -0,0 +1,261 @@
+use std::io::Cursor;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Multipart, Path, State},
+    http::header,
+    response::IntoResponse,
+    routing, Router,
+};
+use image::{imageops::FilterType, ImageError, ImageFormat, ImageReader, ImageResult, Limits};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_scalar};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    storage::Storage,
+    AppState,
+};
+
+/// Edge length in pixels of the avatar shown next to messages and in lists
+pub const SMALL_SIZE: u32 = 48;
+/// Edge length in pixels of the avatar shown on profiles
+pub const LARGE_SIZE: u32 = 256;
+/// Size in bytes of the largest image that can be uploaded as an avatar
+const MAX_UPLOAD_SIZE: usize = 4 * 1024 * 1024;
+/// Larger images are rejected before they are decoded
+const MAX_DIMENSION: u32 = 2048;
+/// Bytes the decoder may allocate, enough for an image of the largest dimensions with 8 bit
+/// channels
+const MAX_DECODE_ALLOC: u64 = 32 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct AvatarId {
+    avatar_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/",
+            routing::post(upload_avatar)
+                .delete(remove_avatar)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE + 64 * 1024)),
+        )
+        .route("/:avatar_id", routing::get(get_avatar))
+}
+
+/// Avatars are stored as png files, cropped to a square at each size
+fn resize(data: &[u8]) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    let encode = |size| -> ImageResult<Vec<u8>> {
+        let mut png = Vec::new();
+        image
+            .resize_to_fill(size, size, FilterType::Lanczos3)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(png)
+    };
+    Ok((encode(SMALL_SIZE)?, encode(LARGE_SIZE)?))
+}
+
+async fn upload_avatar(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse> {
+    let mut data = Vec::new();
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() != Some("avatar") {
+            continue;
+        }
+        while let Some(chunk) = field.chunk().await? {
+            if data.len() + chunk.len() > MAX_UPLOAD_SIZE {
+                return fetch_render_avatar_form(
+                    &state,
+                    user_id,
+                    Some("The image is larger than 4 MB"),
+                )
+                .await;
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+    if data.is_empty() {
+        return fetch_render_avatar_form(&state, user_id, Some("Pick an image to upload")).await;
+    }
+
+    // Decoding and resizing is too slow to do on the runtime
+    let resized = tokio::task::spawn_blocking(move || resize(&data))
+        .await
+        .map_err(|_| Error::ImageProcessingFailed)?;
+    let (small, large) = match resized {
+        Ok(resized) => resized,
+        Err(ImageError::Limits(_)) => {
+            return fetch_render_avatar_form(
+                &state,
+                user_id,
+                Some("The image is larger than 2048×2048 pixels"),
+            )
+            .await;
+        }
+        Err(_) => {
+            return fetch_render_avatar_form(
+                &state,
+                user_id,
+                Some("The file is not a png, jpeg, gif or webp image"),
+            )
+            .await;
+        }
+    };
+
+    let (small_id, large_id) = (Uuid::now_v7(), Uuid::now_v7());
+    state
+        .storage
+        .put(small_id, Bytes::from(small))
+        .await
+        .map_err(Error::Storage)?;
+    state
+        .storage
+        .put(large_id, Bytes::from(large))
+        .await
+        .map_err(Error::Storage)?;
+    let old = query!(
+        r#"UPDATE chat_users AS u SET avatar_small = $2, avatar_large = $3
+    FROM (SELECT avatar_small, avatar_large FROM chat_users WHERE id = $1) AS old
+    WHERE u.id = $1
+    RETURNING old.avatar_small, old.avatar_large"#,
+        user_id,
+        small_id,
+        large_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    remove_files(state.storage.as_ref(), [old.avatar_small, old.avatar_large]).await;
+
+    fetch_render_avatar_form(&state, user_id, None).await
+}
+
+async fn remove_avatar(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> Result<impl IntoResponse> {
+    let old = query!(
+        r#"UPDATE chat_users AS u SET avatar_small = NULL, avatar_large = NULL
+    FROM (SELECT avatar_small, avatar_large FROM chat_users WHERE id = $1) AS old
+    WHERE u.id = $1
+    RETURNING old.avatar_small, old.avatar_large"#,
+        user_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    remove_files(state.storage.as_ref(), [old.avatar_small, old.avatar_large]).await;
+
+    fetch_render_avatar_form(&state, user_id, None).await
+}
+
+/// Removes the files of a replaced avatar, failures are only logged since it is no longer used
+async fn remove_files(storage: &dyn Storage, avatar_ids: [Option<Uuid>; 2]) {
+    for id in avatar_ids.into_iter().flatten() {
+        if let Err(err) = storage.delete(id).await {
+            error!(?err, avatar_id = %id, "Failed to remove avatar file");
+        }
+    }
+}
+
+/// The outcome of an upload is shown below the form
+pub async fn fetch_render_avatar_form(
+    state: &AppState,
+    user_id: Uuid,
+    error: Option<&str>,
+) -> Result<Markup> {
+    let user = query!(
+        r#"SELECT name, avatar_large FROM chat_users WHERE id = $1"#,
+        user_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(html!(
+        form.flex.flex-wrap.items-center.gap-4
+            hx-post="/users/avatars"
+            hx-encoding="multipart/form-data"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            (render_avatar(&user.name, user.avatar_large, true))
+            .flex.flex-col.gap-2 {
+                input.file-input.file-input-bordered.file-input-sm.file-input-error[error.is_some()]
+                    type="file"
+                    name="avatar"
+                    accept="image/png,image/jpeg,image/gif,image/webp";
+                .flex.gap-2 {
+                    button type="submit" class="btn btn-primary btn-sm" { "Upload" }
+                    @if user.avatar_large.is_some() {
+                        button type="button" class="btn btn-ghost btn-sm" hx-delete="/users/avatars" { "Remove" }
+                    }
+                }
+            }
+            @if let Some(error) = error {
+                .label.basis-full { span.label-text-alt.text-error { (error) } }
+            }
+        }
+    ))
+}
+
+async fn get_avatar(
+    State(state): State<AppState>,
+    _: Auth,
+    Path(AvatarId { avatar_id }): Path<AvatarId>,
+) -> Result<impl IntoResponse> {
+    query_scalar!(
+        r#"SELECT id FROM chat_users WHERE avatar_small = $1 OR avatar_large = $1"#,
+        avatar_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    let data = state.storage.get(avatar_id).await.map_err(Error::Storage)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            // A new avatar is stored under new ids, so these never change
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable",
+            ),
+        ],
+        data,
+    ))
+}
+
+/// The avatar of the user, or the first letter of their name if they have none
+pub fn render_avatar(name: &str, avatar_id: Option<Uuid>, large: bool) -> Markup {
+    let initial = name.chars().next().unwrap_or('?').to_uppercase();
+    html!(
+        @if let Some(avatar_id) = avatar_id {
+            .avatar {
+                .rounded-full.w-10[!large].w-24[large] {
+                    img src={"/users/avatars/"(avatar_id)} alt=(name) loading="lazy";
+                }
+            }
+        } @else {
+            .avatar.placeholder {
+                .rounded-full.bg-neutral.text-neutral-content.w-10[!large].w-24[large].text-3xl[large] {
+                    span { (initial) }
+                }
+            }
+        }
+    )
+}
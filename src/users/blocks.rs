@@ -0,0 +1,193 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_scalar, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::{Error, Result},
+    AppState,
+};
+
+use super::{render_user_nav, UserTab};
+
+#[derive(Deserialize)]
+struct UserId {
+    user_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_blocked_users))
+        .route("/table", routing::get(get_blocked_table))
+        .route("/:user_id", routing::post(block_user).delete(unblock_user))
+}
+
+/// The users the user blocked
+pub async fn fetch_blocked(pool: &PgPool, user_id: Uuid) -> Result<Vec<Uuid>> {
+    Ok(query_scalar!(
+        r#"SELECT blocked FROM user_blocks WHERE blocker = $1"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// The users that blocked the user
+pub async fn fetch_blocked_by(pool: &PgPool, user_id: Uuid) -> Result<Vec<Uuid>> {
+    Ok(query_scalar!(
+        r#"SELECT blocker FROM user_blocks WHERE blocked = $1"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+async fn open_blocked_users(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> Result<impl IntoResponse> {
+    let blocked_table = fetch_render_blocked_table(&state.db, user_id).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html! {
+            (render_user_nav(UserTab::Blocked))
+            (blocked_table)
+        }),
+    ))
+}
+
+/// Also ends the friendship and any pending friend requests between the two
+async fn block_user(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: blocked }): Path<UserId>,
+) -> Result<impl IntoResponse> {
+    if blocked == user_id {
+        return Err(Error::InvalidFormField { field: "user_id" });
+    }
+
+    let mut transaction = state.db.begin().await?;
+    query!(
+        r#"INSERT INTO user_blocks (blocker, blocked) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+        user_id,
+        blocked,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    query!(
+        r#"DELETE FROM users_friends
+    WHERE ("user" = $1 AND friend = $2) OR ("user" = $2 AND friend = $1)"#,
+        user_id,
+        blocked,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    query!(
+        r#"DELETE FROM friend_requests
+    WHERE (sender = $1 AND recipient = $2) OR (sender = $2 AND recipient = $1)"#,
+        user_id,
+        blocked,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok((
+        HxResponseTrigger::normal([
+            "update-friends-table",
+            "update-friend-requests",
+            "update-blocked-table",
+        ]),
+        html!(),
+    ))
+}
+
+async fn unblock_user(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: blocked }): Path<UserId>,
+) -> Result<impl IntoResponse> {
+    let rows_affected = query!(
+        r#"DELETE FROM user_blocks WHERE blocker = $1 AND blocked = $2"#,
+        user_id,
+        blocked,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+
+    Ok(html!())
+}
+
+async fn get_blocked_table(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> impl IntoResponse {
+    fetch_render_blocked_table(&state.db, user_id).await
+}
+async fn fetch_render_blocked_table(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
+    let blocked = query!(
+        r#"SELECT u.id, COALESCE(u.display_name, u.name) as "name!"
+    FROM user_blocks AS b
+    JOIN chat_users AS u ON u.id = b.blocked
+    WHERE b.blocker = $1
+    ORDER BY 2"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        table class="table"
+            hx-get={"/users/blocks/table"}
+            hx-trigger="update-blocked-table from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            thead {
+                tr {
+                    th { "name" }
+                    th {}
+                }
+            }
+            tbody {
+                @for user in &blocked {
+                    tr {
+                        td { (user.name) }
+                        td {
+                            button class="link"
+                                hx-delete={"/users/blocks/"(user.id)}
+                                hx-target="closest tr"
+                                { "Unblock" }
+                        }
+                    }
+                }
+                @if blocked.is_empty() {
+                    tr { td.italic.opacity-50 colspan="2" { "You have not blocked anyone" } }
+                }
+            }
+        }
+    ))
+}
+
+/// Blocks the user after confirming
+pub fn render_block_button(user_id: Uuid, name: &str) -> Markup {
+    html!(
+        button class="link link-error"
+            hx-post={"/users/blocks/"(user_id)}
+            hx-confirm={"Block " (name) "? They will no longer be able to message you or send you friend requests."}
+            hx-swap="none"
+            { "Block" }
+    )
+}
@@ -0,0 +1,207 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    routing, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use maud::html;
+use serde::Deserialize;
+use sqlx::{query, query_scalar};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::Result,
+    servers::permissions::{fetch_permissions, Permissions},
+    AppState,
+};
+
+use super::{
+    avatars::render_avatar,
+    presence::{render_status_dot, status_text_class},
+};
+
+#[derive(Deserialize)]
+struct UserId {
+    user_id: Uuid,
+}
+
+/// The server the card was opened from, moderators of it can act on the user from the card
+#[derive(Deserialize)]
+struct CardQuery {
+    server_id: Option<Uuid>,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/:user_id", routing::get(open_card))
+}
+
+/// Where the card of the user is loaded from, to be opened in the main modal
+pub fn card_url(user_id: Uuid, server_id: Option<Uuid>) -> String {
+    match server_id {
+        Some(server_id) => format!("/users/cards/{user_id}?server_id={server_id}"),
+        None => format!("/users/cards/{user_id}"),
+    }
+}
+
+/// What the user has in common with the viewer and what the viewer can do with them
+async fn open_card(
+    State(state): State<AppState>,
+    Auth { id: viewer_id, .. }: Auth,
+    Path(UserId { user_id }): Path<UserId>,
+    Query(CardQuery { server_id }): Query<CardQuery>,
+) -> Result<impl IntoResponse> {
+    let user = query!(
+        r#"SELECT u.id, u.name, u.display_name, u.bio, u.avatar_large, u.status_text,
+        EXISTS(SELECT * FROM users_friends WHERE "user" = $1 AND friend = u.id) as "is_friend!",
+        EXISTS(SELECT * FROM friend_requests WHERE sender = $1 AND recipient = u.id) as "requested!",
+        EXISTS(SELECT * FROM user_blocks WHERE blocker = $1 AND blocked = u.id) as "blocked!"
+    FROM chat_users AS u
+    WHERE u.id = $2"#,
+        viewer_id,
+        user_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    let is_self = user.id == viewer_id;
+    let status = state
+        .presence
+        .statuses(vec![user.id])
+        .await
+        .remove(&user.id)
+        .unwrap_or_default();
+
+    let mutual_servers = query!(
+        r#"SELECT s.id, s.name
+    FROM servers AS s
+    JOIN users_member_of_servers AS viewer ON viewer.server = s.id AND viewer."user" = $1
+    JOIN users_member_of_servers AS other ON other.server = s.id AND other."user" = $2
+    ORDER BY s.name"#,
+        viewer_id,
+        user_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    let mutual_friends = query!(
+        r#"SELECT u.id, COALESCE(u.display_name, u.name) as "name!"
+    FROM users_friends AS viewer
+    JOIN users_friends AS other ON other.friend = viewer.friend AND other."user" = $2
+    JOIN chat_users AS u ON u.id = viewer.friend
+    WHERE viewer."user" = $1
+    ORDER BY 2"#,
+        viewer_id,
+        user_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    // Members can only be moderated by those with every permission they have themselves, and the
+    // owner not at all
+    let moderation = match server_id {
+        Some(server_id) if !is_self => {
+            let viewer_permissions = fetch_permissions(&state.db, viewer_id, server_id).await?;
+            let user_permissions = fetch_permissions(&state.db, user_id, server_id).await?;
+            let user_is_owner = query_scalar!(
+                r#"SELECT EXISTS(SELECT * FROM servers WHERE id = $1 AND owner = $2) as "exists!""#,
+                server_id,
+                user_id,
+            )
+            .fetch_one(&state.db)
+            .await?;
+            viewer_permissions
+                .filter(|viewer| {
+                    !user_is_owner
+                        && viewer.contains(user_permissions.unwrap_or(Permissions::empty()))
+                })
+                .map(|viewer| (server_id, viewer, user_permissions.is_some()))
+        }
+        _ => None,
+    };
+
+    let display_name = user.display_name.as_deref().unwrap_or(&user.name);
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html!(
+            .flex.items-center.gap-4 {
+                (render_avatar(display_name, user.avatar_large, true))
+                div {
+                    h3.text-lg.font-bold.flex.items-center.gap-2 {
+                        (render_status_dot(user.id, status, false))
+                        (display_name)
+                    }
+                    @if user.display_name.is_some() {
+                        .text-sm.opacity-70 { (user.name) }
+                    }
+                    @if user.blocked {
+                        span.badge.badge-error.badge-sm { "Blocked" }
+                    }
+                }
+            }
+            p.mt-2.opacity-70.text-sm."empty:hidden".{(status_text_class(user.id))} { (user.status_text) }
+            @if !user.bio.is_empty() {
+                p.my-2.whitespace-pre-line { (user.bio) }
+            }
+            @if !is_self {
+                .my-2 {
+                    h4.text-sm.font-bold.opacity-70 { "Mutual servers" }
+                    @for server in &mutual_servers {
+                        a.link.mr-2 href={"/servers/"(server.id)} { (server.name) }
+                    }
+                    @if mutual_servers.is_empty() {
+                        span.italic.opacity-50 { "None" }
+                    }
+                }
+                .my-2 {
+                    h4.text-sm.font-bold.opacity-70 { "Mutual friends" }
+                    @for friend in &mutual_friends {
+                        button.link.mr-2 hx-get=(card_url(friend.id, None)) { (friend.name) }
+                    }
+                    @if mutual_friends.is_empty() {
+                        span.italic.opacity-50 { "None" }
+                    }
+                }
+            }
+            .modal-action.flex-wrap.items-center {
+                button class="btn btn-ghost btn-sm"
+                    onclick={"navigator.clipboard.writeText('"(user.id)"')"}
+                    title="Copy user id"
+                    { "Copy id" }
+                @if !is_self && user.is_friend {
+                    button class="btn btn-primary btn-sm"
+                        hx-post="/conversations"
+                        hx-vals={"{\""(user.id)"\": \"on\"}"}
+                        { "Message" }
+                }
+                @if !is_self && !user.is_friend && !user.blocked {
+                    @if user.requested {
+                        button class="btn btn-sm" disabled { "Request sent" }
+                    } @else {
+                        button class="btn btn-primary btn-sm"
+                            hx-post={"/users/friend-requests/outgoing/"(user.id)}
+                            hx-target="this"
+                            { "Add friend" }
+                    }
+                }
+                @if let Some((server_id, permissions, is_member)) = moderation {
+                    @if is_member && permissions.contains(Permissions::KICK_MEMBERS) {
+                        button class="btn btn-error btn-outline btn-sm"
+                            hx-delete={"/servers/"(server_id)"/settings/members/"(user.id)}
+                            hx-confirm={"Remove " (display_name) " from the server?"}
+                            hx-swap="none"
+                            "hx-on::after-request"="if (event.detail.successful) mainModal.close()"
+                            { "Kick" }
+                    }
+                    @if permissions.contains(Permissions::BAN_MEMBERS) {
+                        button class="btn btn-error btn-sm"
+                            hx-post={"/servers/"(server_id)"/settings/members/"(user.id)"/ban"}
+                            hx-confirm={"Ban " (display_name) "? They will not be able to be added back until unbanned."}
+                            hx-swap="none"
+                            "hx-on::after-request"="if (event.detail.successful) mainModal.close()"
+                            { "Ban" }
+                    }
+                }
+            }
+        )),
+    ))
+}
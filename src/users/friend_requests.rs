@@ -0,0 +1,386 @@
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing, Form, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use chrono::Utc;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_scalar, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::{Error, Result},
+    AppState,
+};
+
+use super::{blocks::render_block_button, render_user_nav, UserTab};
+
+#[derive(Deserialize)]
+struct UserId {
+    user_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_friend_requests).post(send_request))
+        .route("/count", routing::get(get_request_count))
+        .route("/table", routing::get(get_requests_table))
+        .route(
+            "/incoming/:user_id",
+            routing::post(accept_request).delete(decline_request),
+        )
+        .route(
+            "/outgoing/:user_id",
+            routing::post(send_request_to).delete(cancel_request),
+        )
+}
+
+async fn open_friend_requests(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> Result<impl IntoResponse> {
+    let requests_table = fetch_render_requests_table(&state.db, user_id).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        base_modal(html! {
+            (render_user_nav(UserTab::Pending))
+            (render_add_friend_form(None))
+            (requests_table)
+        }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct AddFriend {
+    id: String,
+}
+async fn send_request(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Form(AddFriend { id }): Form<AddFriend>,
+) -> Result<Response> {
+    let Ok(friend_id) = Uuid::try_parse(id.trim()) else {
+        return Ok(render_add_friend_form(Some(Err("That is not a user id"))).into_response());
+    };
+    let result = request_friendship(&state, user_id, friend_id).await?;
+    if result.is_err() {
+        return Ok(render_add_friend_form(Some(result)).into_response());
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-friends-table", "update-friend-requests"]),
+        render_add_friend_form(Some(result)),
+    )
+        .into_response())
+}
+
+/// Sent from a profile card, the outcome replaces the button
+async fn send_request_to(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: friend_id }): Path<UserId>,
+) -> Result<Response> {
+    let result = request_friendship(&state, user_id, friend_id).await?;
+    let outcome = match result {
+        Ok(message) => html!(span.text-sm.text-success { (message) }),
+        Err(error) => html!(span.text-sm.text-error { (error) }),
+    };
+    if result.is_err() {
+        return Ok(outcome.into_response());
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-friends-table", "update-friend-requests"]),
+        outcome,
+    )
+        .into_response())
+}
+
+/// Asks the user to become friends. If they already asked the same the two become friends right
+/// away, as both want it.
+///
+/// Returns what happened, or why they could not be asked.
+async fn request_friendship(
+    state: &AppState,
+    user_id: Uuid,
+    friend_id: Uuid,
+) -> Result<std::result::Result<&'static str, &'static str>> {
+    if friend_id == user_id {
+        return Ok(Err("You can not add yourself"));
+    }
+    let relation = query!(
+        r#"SELECT
+    EXISTS(SELECT * FROM chat_users WHERE id = $2) as "exists!",
+    EXISTS(SELECT * FROM users_friends WHERE "user" = $1 AND friend = $2) as "is_friend!",
+    EXISTS(SELECT * FROM friend_requests WHERE sender = $1 AND recipient = $2) as "requested!",
+    EXISTS(SELECT * FROM user_blocks WHERE blocker = $1 AND blocked = $2) as "blocked!",
+    EXISTS(SELECT * FROM user_blocks WHERE blocker = $2 AND blocked = $1) as "blocked_by!""#,
+        user_id,
+        friend_id,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    if !relation.exists {
+        return Ok(Err("There is no user with that id"));
+    } else if relation.blocked {
+        return Ok(Err("Unblock them first to send a friend request"));
+    } else if relation.blocked_by {
+        // Vague on purpose, so being blocked is not spelled out
+        return Ok(Err("You can not send them a friend request"));
+    } else if relation.is_friend {
+        return Ok(Err("You are already friends"));
+    } else if relation.requested {
+        return Ok(Err("You already sent them a friend request"));
+    }
+
+    if accept(&state.db, friend_id, user_id).await? {
+        return Ok(Ok("They had already asked you, you are now friends"));
+    }
+    let rows_affected = query!(
+        r#"INSERT INTO friend_requests (sender, recipient, created) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING"#,
+        user_id,
+        friend_id,
+        Utc::now().naive_utc(),
+    )
+    .execute(&state.db)
+    .await?;
+    // Sent twice at once, the first one got in
+    if rows_affected.rows_affected() != 1 {
+        return Ok(Err("You already sent them a friend request"));
+    }
+    notify_recipient(state, friend_id).await?;
+
+    Ok(Ok("Friend request sent"))
+}
+
+/// Updates the count of pending requests on the pages the recipient has open
+async fn notify_recipient(state: &AppState, recipient: Uuid) -> Result<()> {
+    let count = fetch_request_count(&state.db, recipient).await?;
+    state
+        .message_live
+        .send_to_user(
+            recipient,
+            html!(span #friend-request-count hx-swap-oob="innerHTML" { (render_request_count(count)) }),
+        )
+        .await;
+    Ok(())
+}
+
+/// Turns the request into a friendship, returns if there was a request to accept
+async fn accept(pool: &PgPool, sender: Uuid, recipient: Uuid) -> Result<bool> {
+    let mut transaction = pool.begin().await?;
+    let rows_affected = query!(
+        r#"DELETE FROM friend_requests WHERE sender = $1 AND recipient = $2"#,
+        sender,
+        recipient,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Ok(false);
+    }
+    let rows_affected = query!(
+        r#"INSERT INTO users_friends ("user", friend) VALUES ($1, $2), ($2, $1)"#,
+        sender,
+        recipient,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if rows_affected.rows_affected() != 2 {
+        return Err(Error::DatabaseActionFailed);
+    }
+    transaction.commit().await?;
+    Ok(true)
+}
+
+async fn accept_request(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: sender }): Path<UserId>,
+) -> Result<impl IntoResponse> {
+    if !accept(&state.db, sender, user_id).await? {
+        return Err(Error::DB(sqlx::Error::RowNotFound));
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-friends-table", "update-friend-requests"]),
+        html!(),
+    ))
+}
+
+async fn decline_request(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: sender }): Path<UserId>,
+) -> Result<impl IntoResponse> {
+    delete_request(&state.db, sender, user_id).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["update-friend-requests"]),
+        html!(),
+    ))
+}
+
+async fn cancel_request(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(UserId { user_id: recipient }): Path<UserId>,
+) -> Result<impl IntoResponse> {
+    delete_request(&state.db, user_id, recipient).await?;
+    notify_recipient(&state, recipient).await?;
+
+    Ok((
+        HxResponseTrigger::normal(["update-friend-requests"]),
+        html!(),
+    ))
+}
+
+async fn delete_request(pool: &PgPool, sender: Uuid, recipient: Uuid) -> Result<()> {
+    let rows_affected = query!(
+        r#"DELETE FROM friend_requests WHERE sender = $1 AND recipient = $2"#,
+        sender,
+        recipient,
+    )
+    .execute(pool)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DB(sqlx::Error::RowNotFound));
+    }
+    Ok(())
+}
+
+/// The outcome of the last request is shown below the input
+pub fn render_add_friend_form(result: Option<std::result::Result<&str, &str>>) -> Markup {
+    html!(
+        form
+            class="flex flex-wrap items-end"
+            hx-post={"/users/friend-requests"}
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            .form-control.grow {
+                .label {
+                    .label-text {
+                        "Add friend by id"
+                    }
+                }
+                input.input.input-bordered.w-full.input-error[matches!(result, Some(Err(_)))] type="text" name="id";
+            }
+            button type="submit" class="btn btn-primary" { "Send request" }
+            @match result {
+                Some(Ok(message)) => .label.basis-full { span.label-text-alt.text-success { (message) } },
+                Some(Err(error)) => .label.basis-full { span.label-text-alt.text-error { (error) } },
+                None => {}
+            }
+        }
+    )
+}
+
+async fn get_requests_table(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> impl IntoResponse {
+    fetch_render_requests_table(&state.db, user_id).await
+}
+async fn fetch_render_requests_table(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
+    let requests = query!(
+        r#"SELECT r.sender = $1 as "outgoing!", u.id, COALESCE(u.display_name, u.name) as "name!", r.created
+    FROM friend_requests AS r
+    JOIN chat_users AS u ON u.id = CASE WHEN r.sender = $1 THEN r.recipient ELSE r.sender END
+    WHERE r.sender = $1 OR r.recipient = $1
+    ORDER BY r.sender = $1, r.created DESC"#,
+        user_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        table class="table"
+            hx-get={"/users/friend-requests/table"}
+            hx-trigger="update-friend-requests from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            thead {
+                tr {
+                    th { "name" }
+                    th {}
+                    th {}
+                }
+            }
+            tbody {
+                @for request in &requests {
+                    tr {
+                        td { (request.name) }
+                        td {
+                            span.text-xs.opacity-70 {
+                                @if request.outgoing { "Sent " } @else { "Received " }
+                                relative-time datetime=(request.created.and_utc().to_rfc3339()) {
+                                    (request.created.and_utc().to_rfc2822())
+                                }
+                            }
+                        }
+                        td {
+                            @if request.outgoing {
+                                button class="link link-error"
+                                    hx-delete={"/users/friend-requests/outgoing/"(request.id)}
+                                    hx-swap="none"
+                                    { "Cancel" }
+                            } @else {
+                                button class="link link-success mr-2"
+                                    hx-post={"/users/friend-requests/incoming/"(request.id)}
+                                    hx-swap="none"
+                                    { "Accept" }
+                                button class="link link-error mr-2"
+                                    hx-delete={"/users/friend-requests/incoming/"(request.id)}
+                                    hx-swap="none"
+                                    { "Decline" }
+                                (render_block_button(request.id, &request.name))
+                            }
+                        }
+                    }
+                }
+                @if requests.is_empty() {
+                    tr { td.italic.opacity-50 colspan="3" { "No pending friend requests" } }
+                }
+            }
+        }
+    ))
+}
+
+async fn fetch_request_count(pool: &PgPool, user_id: Uuid) -> Result<i64> {
+    Ok(query_scalar!(
+        r#"SELECT count(*) as "count!" FROM friend_requests WHERE recipient = $1"#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Shown on the friends button, it is also on the login page so it does not redirect there
+async fn get_request_count(
+    State(state): State<AppState>,
+    auth: Option<Auth>,
+) -> Result<impl IntoResponse> {
+    let Some(Auth { id: user_id, .. }) = auth else {
+        return Ok(html!());
+    };
+    let count = fetch_request_count(&state.db, user_id).await?;
+
+    Ok(render_request_count(count))
+}
+
+/// The incoming requests waiting for an answer
+fn render_request_count(count: i64) -> Markup {
+    html!(
+        @if count > 0 {
+            span.badge.badge-primary.badge-sm title="Friend requests" { (count) }
+        }
+    )
+}
@@ -1,12 +1,12 @@
 use axum::{
     extract::{Path, State},
     response::IntoResponse,
-    routing, Form, Router,
+    routing, Router,
 };
 use axum_htmx::HxResponseTrigger;
 use maud::{html, Markup};
 use serde::Deserialize;
-use sqlx::{query, PgPool};
+use sqlx::query;
 use uuid::Uuid;
 
 use crate::{
@@ -16,7 +16,12 @@ use crate::{
     AppState,
 };
 
-use super::{render_user_nav, UserTab};
+use super::{
+    blocks::render_block_button,
+    friend_requests::render_add_friend_form,
+    presence::{render_status_dot, status_text_class},
+    render_user_nav, UserTab,
+};
 
 #[derive(Deserialize)]
 struct FriendId {
@@ -25,72 +30,33 @@ struct FriendId {
 
 pub fn router() -> Router<AppState> {
     Router::new()
-        .route("/", routing::get(open_user_friends).post(add_friends))
+        .route("/", routing::get(open_user_friends))
         .route("/:friend_id", routing::delete(remove_friend))
         .route("/table", routing::get(get_friends_table))
 }
 
 async fn open_user_friends(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
 ) -> Result<impl IntoResponse> {
     Ok((
         HxResponseTrigger::normal(["open-main-modal"]),
-        fetch_render_user_friends(&state.db, user_id).await?,
+        fetch_render_user_friends(&state, user_id).await?,
     ))
 }
-async fn fetch_render_user_friends(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
-    let friends_table = fetch_render_friends_table(pool, user_id).await?;
+async fn fetch_render_user_friends(state: &AppState, user_id: Uuid) -> Result<Markup> {
+    let friends_table = fetch_render_friends_table(state, user_id).await?;
 
     Ok(base_modal(html! {
         (render_user_nav(UserTab::Friends))
-        (render_add_friend_form())
+        (render_add_friend_form(None))
         (friends_table)
     }))
 }
 
-#[derive(Deserialize)]
-struct AddFriend {
-    id: Uuid,
-}
-async fn add_friends(
-    State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
-    add_friend: Option<Form<AddFriend>>,
-) -> Result<impl IntoResponse> {
-    if let Some(Form(add_friend)) = add_friend {
-        let mut transaction = state.db.begin().await?;
-        let rows_affected = query!(
-            r#"INSERT INTO users_friends ("user", friend) VALUES ($1, $2)"#,
-            user_id,
-            add_friend.id,
-        )
-        .execute(&mut *transaction)
-        .await?;
-        if rows_affected.rows_affected() != 1 {
-            return Err(Error::DatabaseActionFailed);
-        }
-        let rows_affected = query!(
-            r#"INSERT INTO users_friends ("user", friend) VALUES ($1, $2)"#,
-            add_friend.id,
-            user_id,
-        )
-        .execute(&mut *transaction)
-        .await?;
-        if rows_affected.rows_affected() != 1 {
-            return Err(Error::DatabaseActionFailed);
-        }
-        transaction.commit().await?;
-    }
-    Ok((
-        HxResponseTrigger::normal(["update-friends-table"]),
-        render_add_friend_form(),
-    ))
-}
-
 async fn remove_friend(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
     Path(FriendId { friend_id }): Path<FriendId>,
 ) -> Result<impl IntoResponse> {
     let mut transaction = state.db.begin().await?;
@@ -119,36 +85,15 @@ async fn remove_friend(
     Ok(html!())
 }
 
-fn render_add_friend_form() -> Markup {
-    html!(
-        form
-            class="flex items-end"
-            hx-post={"/users/friends"}
-            hx-swap="outerHTML"
-            hx-target="this"
-        {
-            .form-control.grow {
-                .label {
-                    .label-text {
-                        "Add friend by id"
-                    }
-                }
-                input type="text" name="id" class="input input-bordered w-full";
-            }
-            button type="submit" class="btn btn-primary" { "Add friend" }
-        }
-    )
-}
-
 async fn get_friends_table(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
 ) -> impl IntoResponse {
-    fetch_render_friends_table(&state.db, user_id).await
+    fetch_render_friends_table(&state, user_id).await
 }
-async fn fetch_render_friends_table(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
+async fn fetch_render_friends_table(state: &AppState, user_id: Uuid) -> Result<Markup> {
     let friends = query!(
-        r#"SELECT u.id, u.name 
+        r#"SELECT u.id, COALESCE(u.display_name, u.name) as "name!", u.status_text
     FROM users_friends as f
     RIGHT JOIN chat_users AS u
         ON u.id = f.friend
@@ -156,8 +101,12 @@ async fn fetch_render_friends_table(pool: &PgPool, user_id: Uuid) -> Result<Mark
     "#,
         user_id
     )
-    .fetch_all(pool)
+    .fetch_all(&state.db)
     .await?;
+    let statuses = state
+        .presence
+        .statuses(friends.iter().map(|friend| friend.id).collect())
+        .await;
 
     Ok(html!(
         table class="table"
@@ -182,12 +131,23 @@ async fn fetch_render_friends_table(pool: &PgPool, user_id: Uuid) -> Result<Mark
                                 title="Copy user id"
                                 { "ID" }
                         }
-                        td { (friend.name) }
                         td {
-                            button class="link link-error"
+                            .flex.items-center.gap-2 {
+                                (render_status_dot(friend.id, statuses.get(&friend.id).copied().unwrap_or_default(), false))
+                                (friend.name)
+                            }
+                            .opacity-70.text-xs."empty:hidden".{(status_text_class(friend.id))} { (friend.status_text) }
+                        }
+                        td {
+                            button class="link mr-2"
+                                hx-post="/conversations"
+                                hx-vals={"{\""(friend.id)"\": \"on\"}"}
+                                { "Message" }
+                            button class="link link-error mr-2"
                                 hx-delete={"/users/friends/"(friend.id)}
                                 hx-target="closest tr"
                                 { "Remove" }
+                            (render_block_button(friend.id, &friend.name))
                         }
                     }
                 }
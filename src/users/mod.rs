@@ -3,25 +3,42 @@ use maud::{html, Markup};
 
 use crate::{auth::Auth, base_tempalte, AppState};
 
+pub mod avatars;
+pub mod blocks;
+pub mod cards;
+mod friend_requests;
 mod friends;
+mod notifications;
+pub mod presence;
 mod profile;
+mod sessions;
 
 pub fn router() -> Router<AppState> {
     Router::new()
         .route(
             "/",
-            routing::get(|Auth { id: user_id }: Auth| async move {
+            routing::get(|Auth { id: user_id, .. }: Auth| async move {
                 base_tempalte(html!(h1 { "hello "(user_id)"!" }))
             }),
         )
         .nest("/profile", profile::router())
+        .nest("/avatars", avatars::router())
+        .nest("/cards", cards::router())
         .nest("/friends", friends::router())
+        .nest("/friend-requests", friend_requests::router())
+        .nest("/blocks", blocks::router())
+        .nest("/notifications", notifications::router())
+        .nest("/presence", presence::router())
+        .nest("/sessions", sessions::router())
 }
 
 #[derive(PartialEq)]
 enum UserTab {
     Profile,
     Friends,
+    Pending,
+    Blocked,
+    Sessions,
 }
 fn render_user_nav(active: UserTab) -> Markup {
     use UserTab::*;
@@ -29,6 +46,9 @@ fn render_user_nav(active: UserTab) -> Markup {
         div class="tabs-boxed tabs" {
             button.tab.tab-active[active == Profile] hx-get={"/users/profile"} { "Profile" }
             button.tab.tab-active[active == Friends] hx-get={"/users/friends"} { "Friends" }
+            button.tab.tab-active[active == Pending] hx-get={"/users/friend-requests"} { "Pending" }
+            button.tab.tab-active[active == Blocked] hx-get={"/users/blocks"} { "Blocked" }
+            button.tab.tab-active[active == Sessions] hx-get={"/users/sessions"} { "Sessions" }
         }
     )
 }
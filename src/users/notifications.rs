@@ -0,0 +1,114 @@
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use axum_htmx::HxResponseTrigger;
+use maud::html;
+use sqlx::query;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::{Error, Result},
+    servers::channels::messages::ChannelIds,
+    utils::MyUuidExt,
+    AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::get(open_inbox))
+        .route("/count", routing::get(get_unread_count))
+}
+
+/// The messages the user was mentioned in, opening the inbox marks them as read
+async fn open_inbox(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> Result<impl IntoResponse> {
+    // Only servers and conversations the user is still in, they could have left since being mentioned
+    let notifications = query!(
+        r#"SELECT n.message, n.read, m.content, m.channel, c.name as channel_name,
+        c.server, s.name as "server_name?", COALESCE(u.display_name, u.name) as "author_name!"
+    FROM notifications AS n
+    JOIN messages AS m ON m.id = n.message
+    JOIN channels AS c ON c.id = m.channel
+    LEFT JOIN servers AS s ON s.id = c.server
+    JOIN chat_users AS u ON u.id = m.author
+    WHERE n."user" = $1 AND (
+        EXISTS (SELECT * FROM users_member_of_servers WHERE server = c.server AND "user" = n."user")
+        OR EXISTS (SELECT * FROM conversation_members WHERE channel = c.id AND "user" = n."user")
+    )
+    ORDER BY n.message DESC
+    LIMIT 50"#,
+        user_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    query!(
+        r#"UPDATE notifications SET read = true WHERE "user" = $1 AND NOT read"#,
+        user_id,
+    )
+    .execute(&state.db)
+    .await?;
+
+    let inbox = base_modal(html!(
+        h3.text-lg.font-bold { "Inbox" }
+        ul.mt-2.flex.flex-col.gap-2 {
+            @for notification in &notifications {
+                @let created_at = notification.message.get_datetime().ok_or(Error::NoTimestampFromUuid { id: notification.message })?;
+                @let ids = ChannelIds { channel_id: notification.channel, server_id: notification.server };
+                li {
+                    a class="block rounded bg-base-200 p-2 text-sm hover:bg-base-300"
+                        href={(ids.url())"?around="(notification.message)"#msg-"(notification.message)}
+                        hx-boost="false"
+                    {
+                        .flex.items-center.gap-2.text-xs {
+                            @if !notification.read {
+                                span.badge.badge-primary.badge-xs aria-label="unread" {}
+                            }
+                            span.font-bold { (notification.author_name) }
+                            @if let Some(server_name) = &notification.server_name {
+                                span.opacity-70 { (server_name) " #" (notification.channel_name) }
+                            } @else {
+                                span.opacity-70 { "Direct message" }
+                            }
+                            relative-time.ml-auto.opacity-50 datetime=(created_at.to_rfc3339()) {
+                                (created_at.to_rfc2822())
+                            }
+                        }
+                        p.truncate { (notification.content) }
+                    }
+                }
+            }
+            @if notifications.is_empty() {
+                li.italic.opacity-50 { "Nobody mentioned you yet" }
+            }
+        }
+    ));
+
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal", "update-inbox-count"]),
+        inbox,
+    ))
+}
+
+/// Shown on the inbox button, it is also on the login page so it does not redirect there
+async fn get_unread_count(
+    State(state): State<AppState>,
+    auth: Option<Auth>,
+) -> Result<impl IntoResponse> {
+    let Some(Auth { id: user_id, .. }) = auth else {
+        return Ok(html!());
+    };
+    let count = query!(
+        r#"SELECT count(*) as "count!" FROM notifications WHERE "user" = $1 AND NOT read"#,
+        user_id,
+    )
+    .fetch_one(&state.db)
+    .await?
+    .count;
+
+    Ok(html!(
+        @if count > 0 {
+            span.badge.badge-primary.badge-sm { (count) }
+        }
+    ))
+}
@@ -0,0 +1,522 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{extract::State, response::IntoResponse, routing, Form, Router};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, query_scalar, PgPool};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tokio_stream::Stream;
+use tracing::{debug_span, error, Instrument};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    error::{Error, Result},
+    servers::channels::messages::live::MessageRegistry,
+    AppState,
+};
+
+/// Connected users without any input for this long are shown as idle
+const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const STATUS_TEXT_LEN: usize = 128;
+
+type ConnectionMsg = (Uuid, Connection);
+type StatusesMsg = (Vec<Uuid>, oneshot::Sender<BTreeMap<Uuid, Status>>);
+type OnlineMsg = oneshot::Sender<BTreeMap<Uuid, Status>>;
+/// The new mode of the user, and their custom status if it changed
+type SetStatusMsg = (Uuid, StatusMode, Option<String>);
+
+#[derive(Debug)]
+enum Connection {
+    Opened,
+    Closed,
+}
+
+/// Chosen by the user, stored as `status_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusMode {
+    Online,
+    DoNotDisturb,
+    /// Shown as offline while connected
+    Invisible,
+}
+
+impl StatusMode {
+    const ALL: [Self; 3] = [Self::Online, Self::DoNotDisturb, Self::Invisible];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::DoNotDisturb => "dnd",
+            Self::Invisible => "invisible",
+        }
+    }
+
+    fn parse(mode: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str() == mode)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Online => "Online",
+            Self::DoNotDisturb => "Do not disturb",
+            Self::Invisible => "Invisible",
+        }
+    }
+}
+
+/// What others see of a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Status {
+    Online,
+    Idle,
+    DoNotDisturb,
+    #[default]
+    Offline,
+}
+
+impl Status {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Online => "Online",
+            Self::Idle => "Idle",
+            Self::DoNotDisturb => "Do not disturb",
+            Self::Offline => "Offline",
+        }
+    }
+}
+
+/// A connected user, kept by the presence task
+struct Presence {
+    /// Event streams the user has open, over all their tabs and sessions
+    connections: usize,
+    last_active: Instant,
+    /// Loaded from the database when the user connects, they are offline until then
+    mode: Option<StatusMode>,
+    /// The status others were last sent
+    shown: Status,
+}
+
+impl Presence {
+    fn status(&self) -> Status {
+        match self.mode {
+            _ if self.connections == 0 => Status::Offline,
+            None | Some(StatusMode::Invisible) => Status::Offline,
+            Some(StatusMode::DoNotDisturb) => Status::DoNotDisturb,
+            Some(StatusMode::Online) if self.last_active.elapsed() >= IDLE_AFTER => Status::Idle,
+            Some(StatusMode::Online) => Status::Online,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PresenceRegistry {
+    connections: mpsc::UnboundedSender<ConnectionMsg>,
+    active: mpsc::Sender<Uuid>,
+    set_status: mpsc::Sender<SetStatusMsg>,
+    statuses: mpsc::Sender<StatusesMsg>,
+    online: mpsc::Sender<OnlineMsg>,
+}
+
+/// Counts as a connection of the user for as long as it is kept, event streams hold one so the
+/// user goes offline once the last of them is closed
+#[derive(Debug)]
+pub struct PresenceGuard {
+    user_id: Uuid,
+    connections: mpsc::UnboundedSender<ConnectionMsg>,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        // Fails only if the task is gone, then there is nothing to update
+        let _ = self.connections.send((self.user_id, Connection::Closed));
+    }
+}
+
+/// An event stream that counts as a connection of the user for as long as it is open
+pub struct PresenceStream<S> {
+    stream: S,
+    _guard: PresenceGuard,
+}
+
+impl<S: Stream + Unpin> Stream for PresenceStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl PresenceRegistry {
+    /// Keeps the user online until the stream is dropped
+    pub fn track<S: Stream>(&self, user_id: Uuid, stream: S) -> PresenceStream<S> {
+        PresenceStream {
+            stream,
+            _guard: self.connect(user_id),
+        }
+    }
+
+    fn connect(&self, user_id: Uuid) -> PresenceGuard {
+        if let Err(err) = self.connections.send((user_id, Connection::Opened)) {
+            error!(?err, %user_id, "Failed to register connection");
+        }
+        PresenceGuard {
+            user_id,
+            connections: self.connections.clone(),
+        }
+    }
+
+    /// The user did something on a page, so they are not idle
+    pub async fn mark_active(&self, user_id: Uuid) {
+        if let Err(err) = self.active.send(user_id).await {
+            error!(?err, %user_id, "Failed to mark user as active");
+        }
+    }
+
+    async fn set_status(&self, user_id: Uuid, mode: StatusMode, text: Option<String>) {
+        if let Err(err) = self.set_status.send((user_id, mode, text)).await {
+            error!(?err, %user_id, "Failed to set status");
+        }
+    }
+
+    /// The status of each of the users, those that are not connected are offline
+    pub async fn statuses(&self, user_ids: Vec<Uuid>) -> BTreeMap<Uuid, Status> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(err) = self.statuses.send((user_ids, tx)).await {
+            error!(?err, "Failed to request statuses");
+            return BTreeMap::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    /// The status of every user that is not shown as offline
+    pub async fn online(&self) -> BTreeMap<Uuid, Status> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(err) = self.online.send(tx).await {
+            error!(?err, "Failed to request online users");
+            return BTreeMap::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+}
+
+pub fn spawn_presence_task(pool: PgPool, message_live: MessageRegistry) -> PresenceRegistry {
+    let (connections_tx, mut connections_rx) = mpsc::unbounded_channel::<ConnectionMsg>();
+    let (active_tx, mut active_rx) = mpsc::channel::<Uuid>(16);
+    let (set_status_tx, mut set_status_rx) = mpsc::channel::<SetStatusMsg>(4);
+    let (statuses_tx, mut statuses_rx) = mpsc::channel::<StatusesMsg>(16);
+    let (online_tx, mut online_rx) = mpsc::channel::<OnlineMsg>(16);
+    // Database work is done outside of the task, so asking for statuses is never held up by it
+    let (mode_loaded_tx, mut mode_loaded_rx) = mpsc::unbounded_channel::<(Uuid, StatusMode)>();
+    let broadcast_tx = spawn_broadcast_task(pool.clone(), message_live);
+
+    tokio::spawn(async move {
+        let mut users = BTreeMap::<Uuid, Presence>::new();
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        loop {
+            let changed = tokio::select! {
+                Some((user_id, connection)) = connections_rx.recv() => {
+                    match connection {
+                        Connection::Opened => {
+                            let presence = match users.entry(user_id) {
+                                Entry::Occupied(entry) => entry.into_mut(),
+                                Entry::Vacant(entry) => {
+                                    let (pool, mode_loaded_tx) = (pool.clone(), mode_loaded_tx.clone());
+                                    tokio::spawn(async move {
+                                        let _ = mode_loaded_tx.send((user_id, fetch_mode(&pool, user_id).await));
+                                    });
+                                    entry.insert(Presence {
+                                        connections: 0,
+                                        last_active: Instant::now(),
+                                        mode: None,
+                                        shown: Status::Offline,
+                                    })
+                                }
+                            };
+                            presence.connections += 1;
+                            presence.last_active = Instant::now();
+                        }
+                        Connection::Closed => {
+                            if let Some(presence) = users.get_mut(&user_id) {
+                                presence.connections = presence.connections.saturating_sub(1);
+                            }
+                        }
+                    }
+                    vec![user_id]
+                }
+                Some(user_id) = active_rx.recv() => {
+                    if let Some(presence) = users.get_mut(&user_id) {
+                        presence.last_active = Instant::now();
+                    }
+                    vec![user_id]
+                }
+                Some((user_id, mode, text)) = set_status_rx.recv() => {
+                    if let Some(presence) = users.get_mut(&user_id) {
+                        presence.mode = Some(mode);
+                    }
+                    // Sent through the same task as status changes, so they stay in order
+                    if let Some(text) = text {
+                        let _ = broadcast_tx.send((user_id, render_status_text_update(user_id, &text)));
+                    }
+                    vec![user_id]
+                }
+                Some((user_id, mode)) = mode_loaded_rx.recv() => {
+                    // A mode set while it was loading is newer
+                    if let Some(presence) = users.get_mut(&user_id) {
+                        presence.mode.get_or_insert(mode);
+                    }
+                    vec![user_id]
+                }
+                Some((user_ids, sender)) = statuses_rx.recv() => {
+                    let statuses = user_ids
+                        .into_iter()
+                        .map(|id| (id, users.get(&id).map(|presence| presence.shown).unwrap_or_default()))
+                        .collect();
+                    let _ = sender.send(statuses);
+                    Vec::new()
+                }
+                Some(sender) = online_rx.recv() => {
+                    let online = users
+                        .iter()
+                        .filter(|(_, presence)| presence.shown != Status::Offline)
+                        .map(|(id, presence)| (*id, presence.shown))
+                        .collect();
+                    let _ = sender.send(online);
+                    Vec::new()
+                }
+                _ = idle_check.tick() => {
+                    users
+                        .iter()
+                        .filter(|(_, presence)| presence.status() != presence.shown)
+                        .map(|(id, _)| *id)
+                        .collect()
+                }
+            };
+
+            for user_id in changed {
+                let Some(presence) = users.get_mut(&user_id) else {
+                    continue;
+                };
+                let status = presence.status();
+                let was_shown = std::mem::replace(&mut presence.shown, status);
+                if presence.connections == 0 {
+                    users.remove(&user_id);
+                }
+                if status != was_shown {
+                    let _ = broadcast_tx.send((user_id, render_status_dot(user_id, status, true)));
+                }
+            }
+        }
+    });
+
+    PresenceRegistry {
+        connections: connections_tx,
+        active: active_tx,
+        set_status: set_status_tx,
+        statuses: statuses_tx,
+        online: online_tx,
+    }
+}
+
+/// Sends the updates of the users' statuses one after another, so they arrive in the order they
+/// happened
+fn spawn_broadcast_task(
+    pool: PgPool,
+    message_live: MessageRegistry,
+) -> mpsc::UnboundedSender<(Uuid, Markup)> {
+    let (broadcast_tx, mut broadcast_rx) = mpsc::unbounded_channel::<(Uuid, Markup)>();
+    tokio::spawn(async move {
+        while let Some((user_id, update)) = broadcast_rx.recv().await {
+            let span = debug_span!("Presence change", %user_id);
+            if let Err(err) = broadcast(&pool, &message_live, user_id, update)
+                .instrument(span)
+                .await
+            {
+                error!(?err, "An error occured while sending a status change");
+            }
+        }
+    });
+    broadcast_tx
+}
+
+async fn fetch_mode(pool: &PgPool, user_id: Uuid) -> StatusMode {
+    let mode = query_scalar!(
+        r#"SELECT status_mode FROM chat_users WHERE id = $1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await;
+    match mode {
+        Ok(mode) => StatusMode::parse(&mode).unwrap_or(StatusMode::Online),
+        Err(err) => {
+            error!(?err, %user_id, "Failed to fetch status mode");
+            StatusMode::Online
+        }
+    }
+}
+
+/// Sends an update of the user's status to themselves, their friends and those they share a
+/// server with
+async fn broadcast(
+    pool: &PgPool,
+    message_live: &MessageRegistry,
+    user_id: Uuid,
+    update: Markup,
+) -> Result<()> {
+    let audience = query_scalar!(
+        r#"SELECT $1::uuid as "id!"
+    UNION SELECT friend FROM users_friends WHERE "user" = $1
+    UNION SELECT other."user"
+        FROM users_member_of_servers AS me
+        JOIN users_member_of_servers AS other ON other.server = me.server
+        WHERE me."user" = $1"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for id in audience {
+        message_live.send_to_user(id, update.clone()).await;
+    }
+    Ok(())
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", routing::post(update_status))
+        .route("/active", routing::post(mark_active))
+}
+
+/// Sent by pages while the user is using them, throttled on the client
+async fn mark_active(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+) -> impl IntoResponse {
+    state.presence.mark_active(user_id).await;
+    html!()
+}
+
+#[derive(Deserialize)]
+struct StatusForm {
+    mode: String,
+    #[serde(default)]
+    text: String,
+}
+async fn update_status(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Form(StatusForm { mode, text }): Form<StatusForm>,
+) -> Result<impl IntoResponse> {
+    let mode = StatusMode::parse(&mode).ok_or(Error::InvalidFormField { field: "mode" })?;
+    let text = text.trim();
+    if text.chars().count() > STATUS_TEXT_LEN {
+        return Ok(render_status_form(
+            mode,
+            text,
+            Some(Err("Status can be at most 128 characters")),
+        ));
+    }
+
+    let previous_text = query_scalar!(
+        r#"WITH previous AS (
+        SELECT status_text FROM chat_users WHERE id = $1
+    )
+    UPDATE chat_users SET status_mode = $2, status_text = $3 WHERE id = $1
+    RETURNING (SELECT status_text FROM previous) as "status_text!""#,
+        user_id,
+        mode.as_str(),
+        text,
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(Error::DatabaseActionFailed)?;
+    let changed_text = (previous_text != text).then(|| text.to_owned());
+    state.presence.set_status(user_id, mode, changed_text).await;
+
+    Ok(render_status_form(mode, text, Some(Ok("Saved"))))
+}
+
+pub async fn fetch_render_status_form(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
+    let user = query!(
+        r#"SELECT status_mode, status_text FROM chat_users WHERE id = $1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    let mode = StatusMode::parse(&user.status_mode).unwrap_or(StatusMode::Online);
+
+    Ok(render_status_form(mode, &user.status_text, None))
+}
+
+/// The outcome of the last update is shown below the form
+fn render_status_form(
+    mode: StatusMode,
+    text: &str,
+    result: Option<std::result::Result<&str, &str>>,
+) -> Markup {
+    html!(
+        form
+            class="flex flex-wrap items-end gap-2"
+            hx-post="/users/presence"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            label.form-control {
+                .label { .label-text { "Status" } }
+                select.select.select-bordered name="mode" {
+                    @for option in StatusMode::ALL {
+                        option value=(option.as_str()) selected[option == mode] { (option.label()) }
+                    }
+                }
+            }
+            label.form-control.grow {
+                .label { .label-text { "Custom status" } }
+                input.input.input-bordered.w-full.input-error[matches!(result, Some(Err(_)))]
+                    type="text" name="text" value=(text) maxlength=(STATUS_TEXT_LEN);
+            }
+            button type="submit" class="btn btn-primary" { "Set" }
+            @match result {
+                Some(Ok(message)) => .label.basis-full { span.label-text-alt.text-success { (message) } },
+                Some(Err(error)) => .label.basis-full { span.label-text-alt.text-error { (error) } },
+                None => {}
+            }
+        }
+    )
+}
+
+/// Every element showing the custom status of the user has this class, they are filled in again
+/// when it changes. They are hidden while the status is empty.
+pub fn status_text_class(user_id: Uuid) -> String {
+    format!("status-text-{user_id}")
+}
+
+fn render_status_text_update(user_id: Uuid, text: &str) -> Markup {
+    html!(
+        span hx-swap-oob={"innerHTML:." (status_text_class(user_id))} { (text) }
+    )
+}
+
+/// Every dot of the user on a page is replaced when it is swapped out of band
+pub fn render_status_dot(user_id: Uuid, status: Status, swap_oob: bool) -> Markup {
+    let color = match status {
+        Status::Online => "bg-success",
+        Status::Idle => "bg-warning",
+        Status::DoNotDisturb => "bg-error",
+        Status::Offline => "bg-base-300",
+    };
+    html!(
+        span.inline-block.size-2.rounded-full.{(color)}.{"presence-"(user_id)}
+            title=(status.label())
+            hx-swap-oob=[swap_oob.then(|| format!("outerHTML:.presence-{user_id}"))]
+            {}
+    )
+}
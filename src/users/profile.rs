@@ -1,45 +1,182 @@
-use axum::{extract::State, response::IntoResponse, routing, Router};
+use axum::{extract::State, response::IntoResponse, routing, Form, Router};
 use axum_htmx::HxResponseTrigger;
 use maud::{html, Markup};
+use serde::Deserialize;
 use sqlx::{query, PgPool};
 use uuid::Uuid;
 
-use crate::{auth::Auth, base_modal, error::Result, AppState};
+use crate::{
+    auth::{validate_username, Auth},
+    base_modal,
+    error::Result,
+    AppState,
+};
 
-use super::{render_user_nav, UserTab};
+use super::{
+    avatars::fetch_render_avatar_form, presence::fetch_render_status_form, render_user_nav, UserTab,
+};
+
+const DISPLAY_NAME_LEN: usize = 32;
+const BIO_LEN: usize = 190;
 
 pub fn router() -> Router<AppState> {
-    Router::new().route("/", routing::get(open_user_profile))
+    Router::new().route("/", routing::get(open_user_profile).post(update_profile))
 }
 
 async fn open_user_profile(
     State(state): State<AppState>,
-    Auth { id: user_id }: Auth,
+    Auth { id: user_id, .. }: Auth,
 ) -> Result<impl IntoResponse> {
+    let avatar_form = fetch_render_avatar_form(&state, user_id, None).await?;
+    let profile = fetch_profile(&state.db, user_id).await?;
+    let status_form = fetch_render_status_form(&state.db, user_id).await?;
+
     Ok((
         HxResponseTrigger::normal(["open-main-modal"]),
-        fetch_and_render_user_profile(&state.db, user_id).await?,
+        base_modal(html! {
+            (render_user_nav(UserTab::Profile))
+            (avatar_form)
+            (status_form)
+            (render_profile_form(&profile, &FormErrors::default(), false))
+            div class="flex items-center" {
+              (user_id)
+              button class="btn btn-circle btn-ghost btn-sm"
+                onclick={"navigator.clipboard.writeText('"(user_id)"')"}
+                title="Copy user id"
+                { ">" }
+            }
+        }),
     ))
 }
-async fn fetch_and_render_user_profile(pool: &PgPool, user_id: Uuid) -> Result<Markup> {
-    let user = query!("SELECT id, name FROM chat_users WHERE id = $1", user_id)
-        .fetch_one(pool)
-        .await?;
-
-    Ok(base_modal(html! {
-        (render_user_nav(UserTab::Profile))
-        form {
-          label.form-control {
-            .label { .label-text { "Username" } }
-            input type="text" class="input input-bordered" value=(user.name);
-          }
+
+#[derive(Deserialize)]
+struct ProfileForm {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    bio: String,
+}
+
+#[derive(Default)]
+struct FormErrors {
+    name: Option<&'static str>,
+    display_name: Option<&'static str>,
+    bio: Option<&'static str>,
+}
+
+impl FormErrors {
+    fn is_empty(&self) -> bool {
+        self.name.is_none() && self.display_name.is_none() && self.bio.is_none()
+    }
+}
+
+async fn fetch_profile(pool: &PgPool, user_id: Uuid) -> Result<ProfileForm> {
+    let user = query!(
+        "SELECT name, display_name, bio FROM chat_users WHERE id = $1",
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(ProfileForm {
+        name: user.name,
+        display_name: user.display_name.unwrap_or_default(),
+        bio: user.bio,
+    })
+}
+
+/// The username is used to log in, the display name is shown to others instead of it when set
+async fn update_profile(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Form(form): Form<ProfileForm>,
+) -> Result<impl IntoResponse> {
+    let form = ProfileForm {
+        name: form.name.trim().to_owned(),
+        display_name: form.display_name.trim().to_owned(),
+        bio: form.bio.trim().to_owned(),
+    };
+
+    let mut errors = FormErrors {
+        name: validate_username(&form.name),
+        ..Default::default()
+    };
+    if form.display_name.chars().count() > DISPLAY_NAME_LEN {
+        errors.display_name = Some("Display name can be at most 32 characters");
+    } else if form.display_name.chars().any(char::is_control) {
+        errors.display_name = Some("Display name can not contain control characters");
+    }
+    if form.bio.chars().count() > BIO_LEN {
+        errors.bio = Some("Bio can be at most 190 characters");
+    }
+    if !errors.is_empty() {
+        return Ok(render_profile_form(&form, &errors, false));
+    }
+
+    let updated = query!(
+        r#"UPDATE chat_users SET name = $2, display_name = $3, bio = $4 WHERE id = $1"#,
+        user_id,
+        form.name,
+        Some(form.display_name.as_str()).filter(|name| !name.is_empty()),
+        form.bio,
+    )
+    .execute(&state.db)
+    .await;
+    match updated {
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            errors.name = Some("Username is already taken");
+            Ok(render_profile_form(&form, &errors, false))
+        }
+        updated => {
+            updated?;
+            Ok(render_profile_form(&form, &errors, true))
         }
-        div class="flex items-center" {
-          (user.id)
-          button class="btn btn-circle btn-ghost btn-sm"
-            onclick={"navigator.clipboard.writeText('"(user.id)"')"}
-            title="Copy user id"
-            { ">" }
+    }
+}
+
+fn render_profile_form(form: &ProfileForm, errors: &FormErrors, saved: bool) -> Markup {
+    html!(
+        form.flex.flex-col.gap-2
+            hx-post="/users/profile"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            label.form-control {
+                .label { .label-text { "Username" } }
+                input.input.input-bordered.input-error[errors.name.is_some()]
+                    type="text" name="name" value=(form.name) required;
+                @if let Some(error) = errors.name {
+                    .label { span.label-text-alt.text-error { (error) } }
+                }
+            }
+            label.form-control {
+                .label {
+                    .label-text { "Display name" }
+                    .label-text-alt.opacity-70 { "Shown instead of your username" }
+                }
+                input.input.input-bordered.input-error[errors.display_name.is_some()]
+                    type="text" name="display_name" value=(form.display_name)
+                    placeholder=(form.name) maxlength=(DISPLAY_NAME_LEN);
+                @if let Some(error) = errors.display_name {
+                    .label { span.label-text-alt.text-error { (error) } }
+                }
+            }
+            label.form-control {
+                .label { .label-text { "Bio" } }
+                textarea.textarea.textarea-bordered.textarea-error[errors.bio.is_some()]
+                    name="bio" rows="3" maxlength=(BIO_LEN)
+                    { (form.bio) }
+                @if let Some(error) = errors.bio {
+                    .label { span.label-text-alt.text-error { (error) } }
+                }
+            }
+            .flex.items-center.justify-end.gap-2 {
+                @if saved {
+                    span.text-sm.text-success { "Saved" }
+                }
+                button type="submit" class="btn btn-primary" { "Save" }
+            }
         }
-    }))
+    )
 }
@@ -0,0 +1,191 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing, Router,
+};
+use axum_htmx::HxResponseTrigger;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::{query, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    auth::Auth,
+    base_modal,
+    error::{Error, Result},
+    utils::MyUuidExt,
+    AppState,
+};
+
+use super::{render_user_nav, UserTab};
+
+#[derive(Deserialize)]
+struct SessionId {
+    session_id: Uuid,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/",
+            routing::get(open_user_sessions).delete(revoke_other_sessions),
+        )
+        .route("/:session_id", routing::delete(revoke_session))
+        .route("/table", routing::get(get_sessions_table))
+}
+
+async fn open_user_sessions(
+    State(state): State<AppState>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+) -> Result<impl IntoResponse> {
+    Ok((
+        HxResponseTrigger::normal(["open-main-modal"]),
+        fetch_render_user_sessions(&state.db, user_id, session_id).await?,
+    ))
+}
+async fn fetch_render_user_sessions(
+    pool: &PgPool,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<Markup> {
+    let sessions_table = fetch_render_sessions_table(pool, user_id, session_id).await?;
+
+    Ok(base_modal(html! {
+        (render_user_nav(UserTab::Sessions))
+        div class="flex justify-end" {
+            button class="btn btn-error btn-sm"
+                hx-delete="/users/sessions"
+                hx-confirm="Log out all other devices?"
+                hx-swap="none"
+                { "Log out other devices" }
+        }
+        (sessions_table)
+    }))
+}
+
+async fn revoke_session(
+    State(state): State<AppState>,
+    Auth { id: user_id, .. }: Auth,
+    Path(SessionId { session_id }): Path<SessionId>,
+) -> Result<impl IntoResponse> {
+    let rows_affected = query!(
+        r#"DELETE FROM sessions WHERE id = $1 AND "user" = $2"#,
+        session_id,
+        user_id,
+    )
+    .execute(&state.db)
+    .await?;
+    if rows_affected.rows_affected() != 1 {
+        return Err(Error::DatabaseActionFailed);
+    }
+    state.message_live.close_session_streams(session_id).await;
+
+    Ok(html!())
+}
+
+async fn revoke_other_sessions(
+    State(state): State<AppState>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+) -> Result<impl IntoResponse> {
+    let revoked = query!(
+        r#"DELETE FROM sessions WHERE "user" = $1 AND id != $2 RETURNING id"#,
+        user_id,
+        session_id,
+    )
+    .fetch_all(&state.db)
+    .await?;
+    for session in revoked {
+        state.message_live.close_session_streams(session.id).await;
+    }
+
+    Ok((
+        HxResponseTrigger::normal(["update-sessions-table"]),
+        html!(),
+    ))
+}
+
+async fn get_sessions_table(
+    State(state): State<AppState>,
+    Auth {
+        id: user_id,
+        session_id,
+    }: Auth,
+) -> impl IntoResponse {
+    fetch_render_sessions_table(&state.db, user_id, session_id).await
+}
+async fn fetch_render_sessions_table(
+    pool: &PgPool,
+    user_id: Uuid,
+    current_session: Uuid,
+) -> Result<Markup> {
+    let sessions = query!(
+        r#"SELECT id, last_seen, user_agent, ip
+    FROM sessions
+    WHERE "user" = $1 AND expires > $2
+    ORDER BY last_seen DESC
+    "#,
+        user_id,
+        chrono::Utc::now().naive_utc(),
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(html!(
+        table class="table"
+            hx-get={"/users/sessions/table"}
+            hx-trigger="update-sessions-table from:body"
+            hx-swap="outerHTML"
+            hx-target="this"
+        {
+            thead {
+                tr {
+                    th { "device" }
+                    th { "created" }
+                    th { "last active" }
+                    th {}
+                }
+            }
+            tbody {
+                @for session in sessions {
+                    tr {
+                        td {
+                            .max-w-48.truncate title=[&session.user_agent] {
+                                (session.user_agent.as_deref().unwrap_or("Unknown device"))
+                            }
+                            .text-xs.opacity-50 { (session.ip.as_deref().unwrap_or("Unknown ip")) }
+                        }
+                        td {
+                            @let created_at = session.id.get_datetime().ok_or(Error::NoTimestampFromUuid { id: session.id })?;
+                            relative-time datetime=(created_at.to_rfc3339()) {
+                                (created_at.to_rfc2822())
+                            }
+                        }
+                        td {
+                            @let last_seen = session.last_seen.and_utc();
+                            relative-time datetime=(last_seen.to_rfc3339()) {
+                                (last_seen.to_rfc2822())
+                            }
+                        }
+                        td {
+                            @if session.id != current_session {
+                                button class="link link-error"
+                                    hx-delete={"/users/sessions/"(session.id)}
+                                    hx-target="closest tr"
+                                    hx-swap="outerHTML"
+                                    { "Revoke" }
+                            } @else {
+                                .italic.opacity-50 { "This device" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    ))
+}